@@ -0,0 +1,45 @@
+// Glitches an image with Ingot's renderer, without the GUI or `ingot render`.
+// Try it with `cargo run --example glitch -- in.png out.png`.
+
+extern crate ingot;
+extern crate num_cpus;
+
+use ingot::{
+  filters::{Filter, GlitchFilter},
+  image_io,
+  render::{DummyRenderProc, TileSize},
+  NullCallback, Renderer,
+};
+use std::{env, path::Path, process, sync::Arc};
+
+fn run(in_path: &Path, out_path: &Path) -> Result<(), String> {
+  let mut renderer = Renderer::new(
+    TileSize::Auto,
+    num_cpus::get(),
+    Arc::new(DummyRenderProc),
+    NullCallback,
+  );
+
+  let (in_img, _) = image_io::load_store(in_path, true, renderer.pager())?;
+
+  let handle = renderer.render(in_img, vec![GlitchFilter::new().proc()]);
+
+  handle
+    .wait()?
+    .save(out_path)
+    .map_err(|e| format!("couldn't save image: {}", e))
+}
+
+fn main() {
+  let args: Vec<_> = env::args().collect();
+
+  if args.len() != 3 {
+    eprintln!("usage: {} <input> <output>", args[0]);
+    process::exit(1);
+  }
+
+  if let Err(e) = run(Path::new(&args[1]), Path::new(&args[2])) {
+    eprintln!("{}", e);
+    process::exit(1);
+  }
+}
@@ -1,19 +1,24 @@
+use cli::{self, RenderOpts};
+use color::ColorSpace;
 use danger::{Danger, DangerWeak};
-use filters::{self, Filter};
+use filters::{Filter, FilterCtor};
 use gdk_pixbuf::{prelude::*, Colorspace, Pixbuf};
 use glib;
 use gtk::{
-  self, prelude::*, AccelFlags, AccelGroup, Application, ApplicationWindow,
-  Box as GBox, Builder, Button, ButtonsType, ComboBoxText, DialogFlags,
-  FileChooserAction, FileChooserDialog, HeaderBar, Image as GImage, Label,
-  MessageDialog, MessageType, ProgressBar, ResponseType, Window,
+  self, prelude::*, AccelFlags, AccelGroup, Adjustment, Application,
+  ApplicationWindow, Box as GBox, Builder, Button, ButtonsType, CheckButton,
+  ComboBoxText, DialogFlags, EventBox, FileChooserAction, FileChooserDialog,
+  HeaderBar, Image as GImage, Label, MessageDialog, MessageType, Orientation,
+  ProgressBar, ResponseType, ScrolledWindow, SpinButton, Window,
+};
+use image_io::{self, Depth};
+use pipeline::Pipeline;
+use pipeline_builder;
+use render::{
+  quantize, DummyRenderProc, RenderCallback, Renderer, TaggedTile, TileSize,
 };
-use image::{self, DynamicImage, GenericImageView};
-use num_cpus;
-use param_builder;
-use render::{DummyRenderProc, RenderCallback, Renderer, TaggedTile};
 use std::{
-  cell::RefCell,
+  cell::{Cell, RefCell},
   cmp,
   collections::{HashMap, VecDeque},
   path::PathBuf,
@@ -23,32 +28,47 @@ use std::{
     Arc, Mutex, RwLock,
   },
 };
+use storage::Layout;
+use tile_order::{
+  AroundPoint, CenterOut, Hilbert, Scanline, TileOrder, ViewportFirst,
+};
+use timing::{secs, TileTiming, Timings};
 
-type AppRenderer = Renderer<AppRenderCallback>;
-type RcAppRenderer = Rc<RefCell<AppRenderer>>;
+// The preview pass is cheap enough at this size to be worth running even for
+// fast filters
+const PREVIEW_SCALE: u32 = 4;
 
-pub type ArcFilter = Arc<Filter + Send + Sync>;
+// How much memory finished tiles can take up, so flipping a setting back and
+// forth only renders each side once
+const CACHE_SIZE: usize = 256 * 1024 * 1024;
 
-pub fn flt<T>(f: T) -> ArcFilter
-where
-  T: Filter + Send + Sync + 'static,
-{
-  Arc::new(f) as ArcFilter
-}
+type AppRenderer = Renderer<AppRenderCallback>;
+type RcAppRenderer = Rc<RefCell<AppRenderer>>;
 
 pub struct App {
   win: ApplicationWindow,
   header: HeaderBar,
   image_preview: GImage,
   tool_box: GBox,
-  in_img: Rc<RefCell<Option<DynamicImage>>>,
+  // The depth of the input file, which is the default when saving
+  in_depth: Rc<Cell<Depth>>,
+  use_icc: bool,
   buf: Arc<Mutex<Option<Danger<Pixbuf>>>>,
+  timings: Arc<Mutex<Timings>>,
+  show_heat: Arc<AtomicBool>,
+  backdrop: Arc<Mutex<Backdrop>>,
+  selection: Selection,
   renderer: RcAppRenderer,
-  filters: Rc<HashMap<String, ArcFilter>>,
+  filters: Rc<HashMap<String, FilterCtor>>,
+  pipeline: Rc<RefCell<Pipeline>>,
 }
 
 impl App {
-  pub fn new(gtk_app: &Application, filter_list: Vec<ArcFilter>) -> Self {
+  pub fn new(
+    gtk_app: &Application,
+    filter_list: Vec<FilterCtor>,
+    opts: RenderOpts,
+  ) -> Self {
     let main_glade = include_str!("res/main.glade");
 
     let builder = Builder::new_from_string(main_glade);
@@ -64,11 +84,16 @@ impl App {
 
     let open_btn: Button = builder.get_object("open_btn").unwrap();
     let save_btn: Button = builder.get_object("save_btn").unwrap();
+    let render_all_btn: Button = builder.get_object("render_all_btn").unwrap();
 
     let image_preview: GImage = builder.get_object("image_preview").unwrap();
+    let image_events: EventBox = builder.get_object("image_events").unwrap();
+    let image_scroll: ScrolledWindow =
+      builder.get_object("image_scroll").unwrap();
 
     let filter_select: ComboBoxText =
       builder.get_object("filter_select").unwrap();
+    let add_stage_btn: Button = builder.get_object("add_stage_btn").unwrap();
 
     let tool_box: GBox = builder.get_object("tool_box").unwrap();
 
@@ -76,7 +101,24 @@ impl App {
       builder.get_object("status_progress").unwrap();
     let status_text: Label = builder.get_object("status_text").unwrap();
 
+    let tile_auto_check: CheckButton =
+      builder.get_object("tile_auto_check").unwrap();
+    let tile_w_spin: SpinButton = builder.get_object("tile_w_spin").unwrap();
+    let tile_h_spin: SpinButton = builder.get_object("tile_h_spin").unwrap();
+    let njobs_spin: SpinButton = builder.get_object("njobs_spin").unwrap();
+    let tile_order_select: ComboBoxText =
+      builder.get_object("tile_order_select").unwrap();
+    let backdrop_select: ComboBoxText =
+      builder.get_object("backdrop_select").unwrap();
+    let heat_check: CheckButton = builder.get_object("heat_check").unwrap();
+    let export_timings_btn: Button =
+      builder.get_object("export_timings_btn").unwrap();
+
     let buf = Arc::new(Mutex::new(None as Option<Danger<Pixbuf>>));
+    let timings = Arc::new(Mutex::new(Timings::new()));
+    let show_heat = Arc::new(AtomicBool::new(false));
+    let backdrop = Arc::new(Mutex::new(Backdrop::Clear));
+    let selection = Arc::new(Mutex::new(None));
 
     let renderer = Self::gen_renderer(
       &save_btn,
@@ -84,23 +126,25 @@ impl App {
       &status_progress,
       &status_text,
       buf.clone(),
+      timings.clone(),
+      show_heat.clone(),
+      backdrop.clone(),
+      selection.clone(),
+      opts,
     );
 
     let filters = Rc::new({
       let mut filters = HashMap::new();
 
-      for (i, flt) in vec![
-        flt(filters::DummyFilter::new()),
-        // flt(filters::PanicFilter::new()),
-      ]
-      .into_iter()
-      .chain(filter_list.into_iter())
-      .enumerate()
+      for (i, new_filter) in filter_list
+        .into_iter()
+        // .chain(vec![ctor(filters::PanicFilter::new)])
+        .enumerate()
       {
         let id = i.to_string();
 
-        filter_select.append(id.as_str(), flt.name());
-        filters.insert(id, flt);
+        filter_select.append(id.as_str(), new_filter().name());
+        filters.insert(id, new_filter);
       }
 
       filters
@@ -111,13 +155,48 @@ impl App {
       header,
       image_preview,
       tool_box,
-      in_img: Rc::new(RefCell::new(None)),
+      in_depth: Rc::new(Cell::new(Depth::U8)),
+      use_icc: opts.use_icc(),
       buf,
+      timings,
+      show_heat,
+      backdrop,
+      selection,
       renderer,
       filters,
+      pipeline: Rc::new(RefCell::new({
+        let mut pipeline = Pipeline::new();
+
+        if opts.isolate() {
+          match cli::own_workers() {
+            Ok(w) => pipeline.set_workers(Some(Arc::new(w))),
+            Err(e) => println!("can't isolate filters: {}", e),
+          }
+        }
+
+        pipeline
+      })),
     };
 
-    ret.init(win_accel_group, open_btn, save_btn, filter_select, "0");
+    ret.init(
+      win_accel_group,
+      open_btn,
+      save_btn,
+      render_all_btn,
+      filter_select,
+      add_stage_btn,
+      "0",
+      tile_auto_check,
+      tile_w_spin,
+      tile_h_spin,
+      njobs_spin,
+      tile_order_select,
+      image_events,
+      image_scroll,
+      backdrop_select,
+      heat_check,
+      export_timings_btn,
+    );
 
     ret
   }
@@ -128,20 +207,38 @@ impl App {
     status_progress: &ProgressBar,
     status_text: &Label,
     buf: Arc<Mutex<Option<Danger<Pixbuf>>>>,
+    timings: Arc<Mutex<Timings>>,
+    show_heat: Arc<AtomicBool>,
+    backdrop: Arc<Mutex<Backdrop>>,
+    selection: Selection,
+    opts: RenderOpts,
   ) -> RcAppRenderer {
-    let nthreads = num_cpus::get();
-    let tile_x: u32 = 64;
-    let tile_y: u32 = 64;
+    let nthreads = opts.njobs();
+    let tile_size = opts.tile_size();
+    let memory_budget = opts.memory_budget();
+    let format = opts.pixel_format();
 
     println!(
-      "starting renderer\n  {} threads\n  {}x{} tiles",
-      nthreads, tile_x, tile_y
+      "starting renderer\n  {} threads\n  {} tiles\n  {} memory\n  {}{} \
+       storage",
+      nthreads,
+      match tile_size {
+        TileSize::Auto => "auto".to_string(),
+        TileSize::Fixed(w, h) => format!("{}x{}", w, h),
+      },
+      match memory_budget {
+        Some(b) => format!("{} MiB", b / 1024 / 1024),
+        None => "unlimited".to_string(),
+      },
+      format.sample.id(),
+      match format.layout {
+        Layout::Interleaved => "",
+        Layout::Planar => " planar",
+      }
     );
 
-    // TODO: make these configurable
-    Rc::new(RefCell::new(Renderer::new(
-      tile_x,
-      tile_y,
+    let mut renderer = Renderer::new(
+      tile_size,
       nthreads,
       Arc::new(DummyRenderProc),
       AppRenderCallback::new(
@@ -150,8 +247,19 @@ impl App {
         status_progress.into(),
         status_text.into(),
         buf,
+        timings,
+        show_heat,
+        backdrop,
+        selection,
       ),
-    )))
+    );
+
+    renderer.set_memory_budget(memory_budget);
+    renderer.set_pixel_format(format);
+    renderer.set_cache_limit(CACHE_SIZE);
+    renderer.set_preview_scale(Some(PREVIEW_SCALE));
+
+    Rc::new(RefCell::new(renderer))
   }
 
   fn prompt_open_img<W>(parent: Option<&W>) -> Vec<PathBuf>
@@ -186,7 +294,10 @@ impl App {
     files
   }
 
-  fn prompt_save_img<W>(parent: Option<&W>) -> Vec<PathBuf>
+  fn prompt_save_img<W>(
+    parent: Option<&W>,
+    depth: Depth,
+  ) -> (Vec<PathBuf>, Depth)
   where
     W: IsA<Window>,
   {
@@ -201,6 +312,63 @@ impl App {
       ("_Save", ResponseType::Accept.into()),
     ]);
 
+    let depth_box = GBox::new(Orientation::Horizontal, 8);
+    let depth_select = ComboBoxText::new();
+
+    for d in Depth::all() {
+      depth_select.append(d.id(), d.name());
+    }
+
+    depth_select.set_active_id(depth.id());
+    depth_select.set_tooltip_text(
+      "16-bit images can be saved as PNG or TIFF, and float images as TIFF or \
+       Radiance HDR",
+    );
+
+    depth_box.pack_start(&Label::new("Bit depth:"), false, false, 0);
+    depth_box.pack_start(&depth_select, false, false, 0);
+    depth_box.show_all();
+
+    dlg.set_extra_widget(&depth_box);
+
+    dlg.set_do_overwrite_confirmation(true);
+    dlg.set_modal(true);
+
+    match ResponseType::from(dlg.run()) {
+      ResponseType::Accept => {},
+      _ => {
+        dlg.destroy();
+        return (Vec::new(), depth);
+      },
+    }
+
+    let files = dlg.get_filenames();
+    let depth = depth_select
+      .get_active_id()
+      .and_then(|i| Depth::from_id(&i))
+      .unwrap_or(depth);
+
+    dlg.destroy();
+
+    (files, depth)
+  }
+
+  fn prompt_save_timings<W>(parent: Option<&W>) -> Vec<PathBuf>
+  where
+    W: IsA<Window>,
+  {
+    let dlg = FileChooserDialog::new(
+      Some("Export Timings"),
+      parent,
+      FileChooserAction::Save,
+    );
+
+    dlg.add_buttons(&[
+      ("_Cancel", ResponseType::Cancel.into()),
+      ("_Save", ResponseType::Accept.into()),
+    ]);
+
+    dlg.set_current_name("timings.csv");
     dlg.set_do_overwrite_confirmation(true);
     dlg.set_modal(true);
 
@@ -219,7 +387,7 @@ impl App {
     files
   }
 
-  fn modal_message<W>(parent: Option<&W>, msg: &str, msg_type: MessageType)
+  pub fn modal_message<W>(parent: Option<&W>, msg: &str, msg_type: MessageType)
   where
     W: IsA<Window>,
   {
@@ -241,8 +409,20 @@ impl App {
     win_accel_group: AccelGroup,
     open_btn: Button,
     save_btn: Button,
+    render_all_btn: Button,
     filter_select: ComboBoxText,
+    add_stage_btn: Button,
     default_filter_id: &str,
+    tile_auto_check: CheckButton,
+    tile_w_spin: SpinButton,
+    tile_h_spin: SpinButton,
+    njobs_spin: SpinButton,
+    tile_order_select: ComboBoxText,
+    image_events: EventBox,
+    image_scroll: ScrolledWindow,
+    backdrop_select: ComboBoxText,
+    heat_check: CheckButton,
+    export_timings_btn: Button,
   ) {
     {
       let (key, mods) = gtk::accelerator_parse("<Control>q");
@@ -259,21 +439,548 @@ impl App {
 
     self.install_open_handler(&open_btn);
     self.install_save_handler(&save_btn);
-    self.install_filter_change_handler(&filter_select);
+    self.install_region_handlers(&image_events, &render_all_btn);
+    self.install_add_stage_handler(&filter_select, &add_stage_btn);
+    self.install_prefs_handlers(
+      &tile_auto_check,
+      &tile_w_spin,
+      &tile_h_spin,
+      &njobs_spin,
+    );
+    self.install_tile_order_handlers(
+      &tile_order_select,
+      &image_events,
+      &image_scroll,
+    );
+    self.install_backdrop_handler(&backdrop_select);
+    self.install_timing_handlers(&heat_check, &export_timings_btn);
 
     filter_select.set_active_id(default_filter_id);
 
+    pipeline_builder::build(
+      &self.tool_box,
+      &self.pipeline,
+      &self.renderer,
+      self.use_icc,
+    );
+
     self.win.show_all();
   }
 
+  fn read_tile_size(
+    tile_auto_check: &CheckButton,
+    tile_w_spin: &SpinButton,
+    tile_h_spin: &SpinButton,
+  ) -> TileSize {
+    if tile_auto_check.get_active() {
+      TileSize::Auto
+    } else {
+      TileSize::Fixed(
+        tile_w_spin.get_value_as_int() as u32,
+        tile_h_spin.get_value_as_int() as u32,
+      )
+    }
+  }
+
+  fn install_prefs_handlers(
+    &self,
+    tile_auto_check: &CheckButton,
+    tile_w_spin: &SpinButton,
+    tile_h_spin: &SpinButton,
+    njobs_spin: &SpinButton,
+  ) {
+    {
+      let renderer = self.renderer.borrow();
+
+      // The size spinners keep whatever they held while automatic tiling is on,
+      // so switching back to manual restores the last manual size
+      match renderer.tile_size() {
+        TileSize::Auto => tile_auto_check.set_active(true),
+        TileSize::Fixed(w, h) => {
+          tile_auto_check.set_active(false);
+          tile_w_spin.set_value(w as f64);
+          tile_h_spin.set_value(h as f64);
+        },
+      }
+
+      njobs_spin.set_value(renderer.njobs() as f64);
+    }
+
+    let auto = tile_auto_check.get_active();
+
+    tile_w_spin.set_sensitive(!auto);
+    tile_h_spin.set_sensitive(!auto);
+
+    let update_tile_size = Rc::new({
+      let renderer = self.renderer.clone();
+      let tile_auto_check = tile_auto_check.downgrade();
+      let tile_w_spin = tile_w_spin.downgrade();
+      let tile_h_spin = tile_h_spin.downgrade();
+
+      move || {
+        let tile_auto_check = tile_auto_check.upgrade().unwrap();
+        let tile_w_spin = tile_w_spin.upgrade().unwrap();
+        let tile_h_spin = tile_h_spin.upgrade().unwrap();
+
+        let auto = tile_auto_check.get_active();
+
+        tile_w_spin.set_sensitive(!auto);
+        tile_h_spin.set_sensitive(!auto);
+
+        renderer.borrow_mut().set_tile_size(Self::read_tile_size(
+          &tile_auto_check,
+          &tile_w_spin,
+          &tile_h_spin,
+        ));
+      }
+    });
+
+    tile_auto_check.connect_toggled(
+      autoclone!(update_tile_size => move |_| update_tile_size()),
+    );
+
+    tile_w_spin.connect_value_changed(
+      autoclone!(update_tile_size => move |_| update_tile_size()),
+    );
+
+    tile_h_spin.connect_value_changed(
+      autoclone!(update_tile_size => move |_| update_tile_size()),
+    );
+
+    njobs_spin.connect_value_changed({
+      let renderer = self.renderer.clone();
+
+      move |njobs_spin| {
+        renderer
+          .borrow_mut()
+          .set_njobs(njobs_spin.get_value_as_int() as usize);
+      }
+    });
+  }
+
+  // Finds where the image is drawn within image_preview, as (x, y, w, h)
+  fn image_bounds(image_preview: &GImage) -> Option<(i32, i32, i32, i32)> {
+    let pixbuf = image_preview.get_pixbuf()?;
+    let alloc = image_preview.get_allocation();
+
+    let w = pixbuf.get_width();
+    let h = pixbuf.get_height();
+
+    // GtkImage centers its contents
+    Some((
+      cmp::max(0, (alloc.width - w) / 2),
+      cmp::max(0, (alloc.height - h) / 2),
+      w,
+      h,
+    ))
+  }
+
+  // Converts a position over image_events to the pixel of the image under it,
+  // clamped to the image's edges
+  fn image_point(
+    image_preview: &GImage,
+    (x, y): (f64, f64),
+  ) -> Option<(u32, u32)> {
+    let (ix, iy, iw, ih) = Self::image_bounds(image_preview)?;
+
+    let x = cmp::min(cmp::max(0, x as i32 - ix), iw - 1);
+    let y = cmp::min(cmp::max(0, y as i32 - iy), ih - 1);
+
+    Some((x as u32, y as u32))
+  }
+
+  // Finds the part of the image that's scrolled into view, as (x, y, w, h)
+  fn visible_rect(
+    image_preview: &GImage,
+    hadj: &Adjustment,
+    vadj: &Adjustment,
+  ) -> Option<(u32, u32, u32, u32)> {
+    let (ix, iy, iw, ih) = Self::image_bounds(image_preview)?;
+
+    // The scroll offsets are relative to image_events, which shares its origin
+    // with image_preview
+    let x0 = cmp::max(0, hadj.get_value() as i32 - ix);
+    let y0 = cmp::max(0, vadj.get_value() as i32 - iy);
+    let x1 =
+      cmp::min(iw, (hadj.get_value() + hadj.get_page_size()) as i32 - ix);
+    let y1 =
+      cmp::min(ih, (vadj.get_value() + vadj.get_page_size()) as i32 - iy);
+
+    if x1 > x0 && y1 > y0 {
+      Some((x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32))
+    } else {
+      None
+    }
+  }
+
+  fn install_tile_order_handlers(
+    &self,
+    tile_order_select: &ComboBoxText,
+    image_events: &EventBox,
+    image_scroll: &ScrolledWindow,
+  ) {
+    // The last position of the pointer over the image, in image coordinates
+    let cursor = Rc::new(Cell::new(None));
+
+    let hadj = image_scroll.get_hadjustment().unwrap();
+    let vadj = image_scroll.get_vadjustment().unwrap();
+
+    let update_order = Rc::new({
+      let renderer = self.renderer.clone();
+      let image_preview = self.image_preview.downgrade();
+      let tile_order_select = tile_order_select.downgrade();
+      let cursor = cursor.clone();
+      let hadj = hadj.downgrade();
+      let vadj = vadj.downgrade();
+
+      move || {
+        let image_preview = image_preview.upgrade().unwrap();
+        let tile_order_select = tile_order_select.upgrade().unwrap();
+
+        let id = tile_order_select.get_active_id();
+
+        let order: Arc<TileOrder + Send + Sync> =
+          match id.as_ref().map(|s| s.as_str()) {
+            Some("scanline") => Arc::new(Scanline),
+            Some("hilbert") => Arc::new(Hilbert),
+            Some("cursor") => match cursor.get() {
+              Some((x, y)) => Arc::new(AroundPoint::new(x, y)),
+              None => Arc::new(CenterOut),
+            },
+            Some("viewport") => {
+              let hadj = hadj.upgrade().unwrap();
+              let vadj = vadj.upgrade().unwrap();
+
+              match Self::visible_rect(&image_preview, &hadj, &vadj) {
+                Some((x, y, w, h)) => Arc::new(ViewportFirst::new(x, y, w, h)),
+                None => Arc::new(CenterOut),
+              }
+            },
+            _ => Arc::new(CenterOut),
+          };
+
+        renderer.borrow_mut().set_tile_order(order);
+      }
+    });
+
+    // Only bother reordering if the pointer or the scroll position is actually
+    // being used
+    let is_active = Rc::new({
+      let tile_order_select = tile_order_select.downgrade();
+
+      move |mode: &str| {
+        let tile_order_select = tile_order_select.upgrade().unwrap();
+
+        tile_order_select
+          .get_active_id()
+          .as_ref()
+          .map(|s| s.as_str())
+          == Some(mode)
+      }
+    });
+
+    tile_order_select
+      .connect_changed(autoclone!(update_order => move |_| update_order()));
+
+    image_events.connect_motion_notify_event({
+      let image_preview = self.image_preview.downgrade();
+
+      autoclone!(update_order, is_active => move |_, evt| {
+        let image_preview = image_preview.upgrade().unwrap();
+
+        if let Some(point) = Self::image_point(&image_preview, evt.get_position()) {
+          cursor.set(Some(point));
+
+          if is_active("cursor") {
+            update_order();
+          }
+        }
+
+        Inhibit(false)
+      })
+    });
+
+    for adj in &[hadj, vadj] {
+      adj.connect_value_changed(
+        autoclone!(update_order, is_active => move |_| {
+          if is_active("viewport") {
+            update_order();
+          }
+        }),
+      );
+
+      adj.connect_changed(autoclone!(update_order, is_active => move |_| {
+        if is_active("viewport") {
+          update_order();
+        }
+      }));
+    }
+  }
+
+  // Makes a function that shows the image again with the current overlays,
+  // since nothing else redraws the preview once a render is finished
+  fn refresher(&self) -> impl Fn() {
+    let buf = self.buf.clone();
+    let image_preview = self.image_preview.downgrade();
+    let timings = self.timings.clone();
+    let show_heat = self.show_heat.clone();
+    let selection = self.selection.clone();
+
+    move || {
+      if let Some(b) = &*buf.lock().unwrap() {
+        let timings = timings.lock().unwrap();
+
+        Self::present(
+          &image_preview.upgrade().unwrap(),
+          b,
+          if show_heat.load(Ordering::SeqCst) {
+            Some(&timings)
+          } else {
+            None
+          },
+          *selection.lock().unwrap(),
+        );
+      }
+    }
+  }
+
+  // Dragging over the image selects a region to render on its own, and
+  // clicking without dragging goes back to rendering all of it
+  fn install_region_handlers(
+    &self,
+    image_events: &EventBox,
+    render_all_btn: &Button,
+  ) {
+    // Where the drag started, in image coordinates
+    let anchor = Rc::new(Cell::new(None));
+    let refresh = Rc::new(self.refresher());
+
+    // The corners are both inside the selection
+    let span = |(x0, y0): (u32, u32), (x1, y1): (u32, u32)| {
+      (
+        cmp::min(x0, x1),
+        cmp::min(y0, y1),
+        cmp::max(x0, x1) - cmp::min(x0, x1) + 1,
+        cmp::max(y0, y1) - cmp::min(y0, y1) + 1,
+      )
+    };
+
+    image_events.connect_button_press_event({
+      let image_preview = self.image_preview.downgrade();
+      let anchor = anchor.clone();
+
+      move |_, evt| {
+        if evt.get_button() == 1 {
+          let image_preview = image_preview.upgrade().unwrap();
+
+          anchor.set(Self::image_point(&image_preview, evt.get_position()));
+        }
+
+        Inhibit(false)
+      }
+    });
+
+    image_events.connect_motion_notify_event({
+      let image_preview = self.image_preview.downgrade();
+      let selection = self.selection.clone();
+      let anchor = anchor.clone();
+      let refresh = refresh.clone();
+
+      move |_, evt| {
+        let image_preview = image_preview.upgrade().unwrap();
+
+        if let (Some(start), Some(point)) = (
+          anchor.get(),
+          Self::image_point(&image_preview, evt.get_position()),
+        ) {
+          *selection.lock().unwrap() = Some(span(start, point));
+          refresh();
+        }
+
+        Inhibit(false)
+      }
+    });
+
+    image_events.connect_button_release_event({
+      let image_preview = self.image_preview.downgrade();
+      let renderer = self.renderer.clone();
+      let selection = self.selection.clone();
+
+      move |_, evt| {
+        let start = match anchor.take() {
+          Some(s) if evt.get_button() == 1 => s,
+          _ => return Inhibit(false),
+        };
+
+        let image_preview = image_preview.upgrade().unwrap();
+
+        let region = Self::image_point(&image_preview, evt.get_position())
+          .and_then(|point| {
+            let (x, y, w, h) = span(start, point);
+
+            // Allow for the pointer wobbling a little during a click
+            if w <= 2 && h <= 2 {
+              None
+            } else {
+              Some((x, y, w, h))
+            }
+          });
+
+        *selection.lock().unwrap() = region;
+        refresh();
+
+        renderer.borrow_mut().set_region(region);
+
+        Inhibit(false)
+      }
+    });
+
+    render_all_btn.connect_clicked({
+      let renderer = self.renderer.clone();
+
+      move |_| renderer.borrow_mut().render_full()
+    });
+  }
+
+  fn install_backdrop_handler(&self, backdrop_select: &ComboBoxText) {
+    backdrop_select.connect_changed({
+      let backdrop = self.backdrop.clone();
+
+      move |backdrop_select| {
+        let id = backdrop_select.get_active_id();
+
+        // Takes effect when the next render begins
+        *backdrop.lock().unwrap() = match id.as_ref().map(|s| s.as_str()) {
+          Some("keep") => Backdrop::Keep,
+          Some("dim") => Backdrop::Dim,
+          _ => Backdrop::Clear,
+        };
+      }
+    });
+  }
+
+  fn install_timing_handlers(
+    &self,
+    heat_check: &CheckButton,
+    export_timings_btn: &Button,
+  ) {
+    heat_check.connect_toggled({
+      let refresh = self.refresher();
+      let show_heat = self.show_heat.clone();
+
+      move |heat_check| {
+        show_heat.store(heat_check.get_active(), Ordering::SeqCst);
+
+        refresh();
+      }
+    });
+
+    export_timings_btn.connect_clicked({
+      let win = self.win.downgrade();
+      let timings = self.timings.clone();
+
+      move |_| {
+        let win = win.upgrade().unwrap();
+        let files = Self::prompt_save_timings(Some(&win));
+
+        if files.is_empty() {
+          return;
+        } else if files.len() > 1 {
+          println!("too many files");
+          return;
+        }
+
+        println!("saving timings to {:?}", files[0]);
+
+        match timings.lock().unwrap().save(&files[0]) {
+          Ok(()) => println!("  done"),
+          Err(e) => {
+            println!("  failed to write timings: {:?}", e);
+
+            App::modal_message(
+              Some(&win),
+              &format!("Couldn't export timings: {}", e),
+              MessageType::Error,
+            );
+          },
+        }
+      }
+    });
+  }
+
+  // Shows buf in image_preview, with a heatmap of the given timings over it.
+  // The heatmap is dithered over a copy so the image shows through, and so
+  // turning it off again doesn't need the whole image to be redrawn.  The
+  // selected region is outlined the same way.
+  fn present(
+    image_preview: &GImage,
+    buf: &Pixbuf,
+    heat: Option<&Timings>,
+    selection: Option<(u32, u32, u32, u32)>,
+  ) {
+    let heat = match heat {
+      Some(t) => t.heat(),
+      None => Vec::new(),
+    };
+
+    let max = heat.iter().map(|h| secs(h.4)).fold(0.0, f64::max);
+    let heat = if max > 0.0 { heat } else { Vec::new() };
+
+    if heat.is_empty() && selection.is_none() {
+      image_preview.set_from_pixbuf(Some(buf));
+      return;
+    }
+
+    let shown = buf.copy().unwrap();
+
+    let buf_w = shown.get_width();
+    let buf_h = shown.get_height();
+
+    // Slow tiles are red and fast ones blue
+    for (x, y, w, h, time) in heat {
+      let t = secs(time) / max;
+
+      let red = (t * 255.0).round() as u8;
+      let blue = 255 - red;
+
+      for r in y as i32..cmp::min(buf_h, (y + h) as i32) {
+        for c in x as i32..cmp::min(buf_w, (x + w) as i32) {
+          if (r + c) % 2 == 0 {
+            shown.put_pixel(c, r, red, 0, blue, 255);
+          }
+        }
+      }
+    }
+
+    // Dashed in black and white, so it shows up on any image
+    if let Some((x, y, w, h)) = selection {
+      let x1 = cmp::min(buf_w, (x + w) as i32);
+      let y1 = cmp::min(buf_h, (y + h) as i32);
+
+      for r in y as i32..y1 {
+        for c in x as i32..x1 {
+          if r == y as i32 || r == y1 - 1 || c == x as i32 || c == x1 - 1 {
+            let v = if (r + c) / 4 % 2 == 0 { 255 } else { 0 };
+
+            shown.put_pixel(c, r, v, v, v, 255);
+          }
+        }
+      }
+    }
+
+    image_preview.set_from_pixbuf(Some(&shown));
+  }
+
   fn install_open_handler(&self, open_btn: &Button) {
     open_btn.connect_clicked({
       let win = self.win.downgrade();
-      let in_img = self.in_img.clone();
+      let in_depth = self.in_depth.clone();
+      let use_icc = self.use_icc;
       let buf = self.buf.clone();
       let image_preview = self.image_preview.downgrade();
       let renderer = self.renderer.clone();
       let header = self.header.downgrade();
+      let selection = self.selection.clone();
 
       move |_| {
         let win = win.upgrade().unwrap();
@@ -287,19 +994,25 @@ impl App {
         }
 
         gtk::idle_add({
-          let in_img = in_img.clone();
+          let in_depth = in_depth.clone();
           let buf = buf.clone();
           let image_preview = image_preview.clone();
           let renderer = renderer.clone();
+          let selection = selection.clone();
           let header = header.clone();
 
           move || {
-            let mut img = in_img.borrow_mut();
-
             println!("loading {:?}", files[0]);
 
-            *img = Some(match image::open(files[0].as_path()) {
-              Ok(i) => i,
+            let pager = renderer.borrow().pager().clone();
+
+            let img = match image_io::load_store(&files[0], use_icc, &pager) {
+              Ok((i, depth)) => {
+                println!("  {}", depth.name());
+
+                in_depth.set(depth);
+                i
+              },
               Err(e) => {
                 println!("  failed to read image: {:?}", e);
 
@@ -311,21 +1024,19 @@ impl App {
 
                 return Continue(false);
               },
-            });
+            };
 
             println!("  done");
 
             let mut buf = buf.lock().unwrap();
 
-            let img = img.as_ref().unwrap();
-
             *buf = Some(
               Pixbuf::new(
                 Colorspace::Rgb,
                 true,
                 8,
-                img.width() as i32,
-                img.height() as i32,
+                img.w() as i32,
+                img.h() as i32,
               )
               .into(),
             );
@@ -337,8 +1048,8 @@ impl App {
 
             println!("clearing pixbuf...");
 
-            for r in 0..img.height() {
-              for c in 0..img.width() {
+            for r in 0..img.h() {
+              for c in 0..img.w() {
                 buf.put_pixel(c as i32, r as i32, 0, 127, 0, 255);
               }
             }
@@ -348,6 +1059,7 @@ impl App {
             println!("initializing renderer...");
 
             renderer.borrow_mut().read_input(img);
+            *selection.lock().unwrap() = None;
 
             println!("  done");
 
@@ -365,14 +1077,33 @@ impl App {
   fn install_save_handler(&self, save_btn: &Button) {
     save_btn.connect_clicked({
       let renderer = self.renderer.clone();
+      let in_depth = self.in_depth.clone();
       let win = self.win.downgrade();
 
       move |_| {
+        // Rendering the rest here would hang the window until it's done, so
+        // it's started in the background instead
+        if renderer.borrow().region().is_some()
+          && renderer.borrow().missing_tiles() > 0
+        {
+          renderer.borrow_mut().render_full();
+
+          App::modal_message(
+            Some(&win.upgrade().unwrap()),
+            "Only the selected region has been rendered. The rest of the \
+             image is rendering now, so save again once it's done.",
+            MessageType::Info,
+          );
+
+          return;
+        }
+
         let img = renderer.borrow_mut().get_output();
 
         if img.is_some() {
           let win = win.upgrade().unwrap();
-          let files = Self::prompt_save_img(Some(&win));
+          let (files, depth) =
+            Self::prompt_save_img(Some(&win), in_depth.get());
 
           if files.is_empty() {
             return;
@@ -384,9 +1115,9 @@ impl App {
           gtk::idle_add({
             //
             move || {
-              println!("saving {:?}", files[0]);
+              println!("saving {:?} ({})", files[0], depth.name());
 
-              match img.as_ref().unwrap().save(files[0].clone()) {
+              match image_io::save(&files[0], img.as_ref().unwrap(), depth) {
                 Ok(_) => (),
                 Err(e) => {
                   println!("  failed to write image: {:?}", e);
@@ -412,25 +1143,34 @@ impl App {
     });
   }
 
-  fn install_filter_change_handler(&self, filter_select: &ComboBoxText) {
-    filter_select.connect_changed({
+  fn install_add_stage_handler(
+    &self,
+    filter_select: &ComboBoxText,
+    add_stage_btn: &Button,
+  ) {
+    add_stage_btn.connect_clicked({
       let renderer = self.renderer.clone();
       let filters = self.filters.clone();
+      let pipeline = self.pipeline.clone();
       let tool_box = self.tool_box.downgrade();
+      let filter_select = filter_select.downgrade();
+      let use_icc = self.use_icc;
+
+      move |_| {
+        let filter_select = filter_select.upgrade().unwrap();
 
-      move |el| {
-        let id = match el.get_active_id() {
+        let id = match filter_select.get_active_id() {
           Some(i) => i,
           None => return,
         };
 
-        let flt = &filters[&id];
+        let new_filter = &filters[&id];
 
-        renderer.borrow_mut().set_proc(flt.proc());
+        pipeline.borrow_mut().push(new_filter());
 
         let tool_box = tool_box.upgrade().unwrap();
 
-        param_builder::build(&tool_box, flt.params(), &renderer);
+        pipeline_builder::update(&tool_box, &pipeline, &renderer, use_icc);
       }
     });
   }
@@ -444,6 +1184,20 @@ impl Default for AppRenderCallbackTag {
 
 type AppTaggedTile = TaggedTile<AppRenderCallbackTag>;
 
+// The region of the image picked out to render, as (x, y, w, h)
+type Selection = Arc<Mutex<Option<(u32, u32, u32, u32)>>>;
+
+// What's left on screen under a new render until its tiles arrive
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Backdrop {
+  Clear,
+  // The last render is kept, and only the finished image's tiles are drawn
+  // over it
+  Keep,
+  // As with Keep, but the last render is shaded over to show it's out of date
+  Dim,
+}
+
 #[derive(Clone)]
 struct AppRenderCallback {
   done: Arc<AtomicUsize>,
@@ -455,8 +1209,22 @@ struct AppRenderCallback {
   status_text: DangerWeak<Label>,
   buf: Arc<Mutex<Option<Danger<Pixbuf>>>>,
   clear_buf: Arc<AtomicBool>,
-  working: Arc<RwLock<HashMap<usize, Arc<AppTaggedTile>>>>,
+  // The size of the output being rendered, which buf is resized to match
+  size: Arc<Mutex<(u32, u32)>>,
+  // Which render is current.  Tiles from any other were aborted, and are
+  // dropped rather than shown.
+  generation: Arc<AtomicUsize>,
+  // The tile each worker is on, and how far through it the proc says it is
+  working: Arc<RwLock<HashMap<usize, (Arc<AppTaggedTile>, f32)>>>,
   q: Arc<Mutex<VecDeque<Arc<AppTaggedTile>>>>,
+  // Tiles whose proc panicked, along with the panic message
+  failed: Arc<Mutex<Vec<(Arc<AppTaggedTile>, String)>>>,
+  // Why the current render stopped partway, if it couldn't carry on
+  error: Arc<Mutex<Option<String>>>,
+  timings: Arc<Mutex<Timings>>,
+  show_heat: Arc<AtomicBool>,
+  backdrop: Arc<Mutex<Backdrop>>,
+  selection: Selection,
 }
 
 impl AppRenderCallback {
@@ -466,6 +1234,10 @@ impl AppRenderCallback {
     status_progress: DangerWeak<ProgressBar>,
     status_text: DangerWeak<Label>,
     buf: Arc<Mutex<Option<Danger<Pixbuf>>>>,
+    timings: Arc<Mutex<Timings>>,
+    show_heat: Arc<AtomicBool>,
+    backdrop: Arc<Mutex<Backdrop>>,
+    selection: Selection,
   ) -> Self {
     Self {
       done: Arc::new(AtomicUsize::new(0)),
@@ -477,11 +1249,23 @@ impl AppRenderCallback {
       status_text,
       buf,
       clear_buf: Arc::new(AtomicBool::new(false)),
+      size: Arc::new(Mutex::new((0, 0))),
+      generation: Arc::new(AtomicUsize::new(0)),
       working: Arc::new(RwLock::new(HashMap::new())), // TODO: this might need weak references
       q: Arc::new(Mutex::new(VecDeque::new())),
+      failed: Arc::new(Mutex::new(Vec::new())),
+      error: Arc::new(Mutex::new(None)),
+      timings,
+      show_heat,
+      backdrop,
+      selection,
     }
   }
 
+  fn is_current(&self, tile: &AppTaggedTile) -> bool {
+    tile.generation() == self.generation.load(Ordering::SeqCst)
+  }
+
   fn dispatch_worker(&self) {
     if self.running.swap(true, Ordering::SeqCst) {
       return;
@@ -494,8 +1278,16 @@ impl AppRenderCallback {
       let status_text = self.status_text.clone();
       let save_btn = self.save_btn.clone();
       let clear_buf = self.clear_buf.clone();
+      let size = self.size.clone();
+      let generation = self.generation.clone();
       let working = self.working.clone();
       let q = self.q.clone();
+      let failed = self.failed.clone();
+      let error = self.error.clone();
+      let timings = self.timings.clone();
+      let show_heat = self.show_heat.clone();
+      let backdrop = self.backdrop.clone();
+      let selection = self.selection.clone();
       let done = self.done.clone();
       let total = self.total.clone();
       let running = self.running.clone();
@@ -503,7 +1295,7 @@ impl AppRenderCallback {
       move || {
         let mut did_work = false;
 
-        let out_buf = buf.lock().unwrap();
+        let mut out_buf = buf.lock().unwrap();
         let mut qlen: usize = 0;
 
         let image_preview = image_preview.upgrade().unwrap();
@@ -518,13 +1310,50 @@ impl AppRenderCallback {
 
         const CHUNK_SIZE: usize = 500;
 
+        let generation = generation.load(Ordering::SeqCst);
+        let backdrop = *backdrop.lock().unwrap();
+        let keep = backdrop != Backdrop::Clear;
+        // Set if the buffer was just made, so there's nothing in it to keep
+        let mut fresh = false;
+
+        // Procs can change the size of the image, so the buffer is replaced if
+        // it doesn't match the new output
+        if clear_buf.load(Ordering::SeqCst) {
+          let (w, h) = *size.lock().unwrap();
+
+          let resize = match &*out_buf {
+            Some(b) => b.get_width() != w as i32 || b.get_height() != h as i32,
+            None => false,
+          };
+
+          if resize {
+            *out_buf = Some(
+              Pixbuf::new(Colorspace::Rgb, true, 8, w as i32, h as i32).into(),
+            );
+            fresh = true;
+          }
+        }
+
         if let Some(b) = &*out_buf {
           let out_buf = &**b;
 
           if clear_buf.swap(false, Ordering::SeqCst) {
-            for r in 0..out_buf.get_height() {
-              for c in 0..out_buf.get_width() {
-                out_buf.put_pixel(c, r, 31, 31, 31, 255);
+            // Dimming is dithered like the heatmap, so doing it again over a
+            // render that was never replaced doesn't darken it any further
+            let dim = match backdrop {
+              _ if fresh => Some(1),
+              Backdrop::Clear => Some(1),
+              Backdrop::Keep => None,
+              Backdrop::Dim => Some(2),
+            };
+
+            if let Some(every) = dim {
+              for r in 0..out_buf.get_height() {
+                for c in 0..out_buf.get_width() {
+                  if (r + c) % every == 0 {
+                    out_buf.put_pixel(c, r, 31, 31, 31, 255);
+                  }
+                }
               }
             }
           }
@@ -534,26 +1363,55 @@ impl AppRenderCallback {
 
             // TODO: the logic behind this could be improved
 
-            for tile in working.values() {
+            let buf_w = out_buf.get_width() as u32;
+            let buf_h = out_buf.get_height() as u32;
+
+            // Only the outline is drawn so the preview shows through, with a
+            // bar along the top that fills up as the proc reports progress
+            for (tile, progress) in working.values() {
+              if tile.generation() != generation {
+                continue;
+              }
+
+              // Kept renders only have the output's tiles drawn over them, so
+              // nothing else is outlined.  Preview tiles aren't either, since
+              // their outlines land at preview coordinates.  Outlines left by
+              // an aborted render are painted over when their tile comes in.
+              if keep && (!tile.writes_output() || tile.tile().scale() > 1) {
+                continue;
+              }
+
               let tile = tile.tile();
 
               let last_r = tile.h() - 1;
               let last_c = tile.w() - 1;
-
-              for r in 0..tile.h() {
-                for c in 0..tile.w() {
-                  out_buf.put_pixel(
-                    (tile.x() + c) as i32,
-                    (tile.y() + r) as i32,
-                    if r == 0 || r == last_r || c == 0 || c == last_c {
-                      255
-                    } else {
-                      31
-                    },
-                    31,
-                    31,
-                    255,
-                  );
+              let filled = (progress * tile.w() as f32) as u32;
+
+              // Tiles from earlier stages can be outside the final image
+              let rows = cmp::min(tile.h(), buf_h.saturating_sub(tile.y()));
+              let cols = cmp::min(tile.w(), buf_w.saturating_sub(tile.x()));
+
+              for r in 0..rows {
+                for c in 0..cols {
+                  if r < 3 && c < filled {
+                    out_buf.put_pixel(
+                      (tile.x() + c) as i32,
+                      (tile.y() + r) as i32,
+                      31,
+                      255,
+                      31,
+                      255,
+                    );
+                  } else if r == 0 || r == last_r || c == 0 || c == last_c {
+                    out_buf.put_pixel(
+                      (tile.x() + c) as i32,
+                      (tile.y() + r) as i32,
+                      255,
+                      31,
+                      31,
+                      255,
+                    );
+                  }
                 }
               }
             }
@@ -567,30 +1425,87 @@ impl AppRenderCallback {
             for tile in q.drain(0..cmp::min(CHUNK_SIZE, qlen)) {
               did_work = true;
 
+              // Tiles from aborted renders can still slip in here
+              if tile.generation() != generation {
+                continue;
+              }
+
+              // Earlier stages would cover the kept render with something that
+              // isn't the new one either
+              if keep && !tile.writes_output() {
+                continue;
+              }
+
               let tile = tile.tile();
 
               let tile_buf = tile.out_buf();
 
+              // Preview tiles are blown back up to full size in blocks
+              let scale = tile.scale() as i32;
+              let buf_w = out_buf.get_width();
+              let buf_h = out_buf.get_height();
+
               for r in 0..tile.h() {
                 let r_stride = r * tile.w();
+                let y = (tile.y() + r) as i32 * scale;
 
                 for c in 0..tile.w() {
                   let px = tile_buf[(r_stride + c) as usize];
+                  let x = (tile.x() + c) as i32 * scale;
+
+                  let px = ColorSpace::Perceptual.encode(px);
+
+                  let data = [
+                    quantize(px[0], 255.0) as u8,
+                    quantize(px[1], 255.0) as u8,
+                    quantize(px[2], 255.0) as u8,
+                    quantize(px[3], 255.0) as u8,
+                  ];
+
+                  for y in y..cmp::min(buf_h, y + scale) {
+                    for x in x..cmp::min(buf_w, x + scale) {
+                      out_buf
+                        .put_pixel(x, y, data[0], data[1], data[2], data[3]);
+                    }
+                  }
+                }
+              }
+            }
+          }
 
-                  out_buf.put_pixel(
-                    (tile.x() + c) as i32,
-                    (tile.y() + r) as i32,
-                    (px[0] * 255.0).round() as u8,
-                    (px[1] * 255.0).round() as u8,
-                    (px[2] * 255.0).round() as u8,
-                    (px[3] * 255.0).round() as u8,
-                  );
+          // Failed tiles are hatched over, and redrawn every time in case a
+          // later stage blitted over them
+          for (tile, _) in failed.lock().unwrap().iter() {
+            if tile.generation() != generation {
+              continue;
+            }
+
+            let (x, y, w, h) = tile.tile().bounds();
+
+            let x1 = cmp::min(out_buf.get_width(), (x + w) as i32);
+            let y1 = cmp::min(out_buf.get_height(), (y + h) as i32);
+
+            for r in y as i32..y1 {
+              for c in x as i32..x1 {
+                if (r + c) / 4 % 2 == 0 {
+                  out_buf.put_pixel(c, r, 255, 0, 255, 255);
                 }
               }
             }
           }
 
-          image_preview.set_from_pixbuf(Some(out_buf));
+          let timings = timings.lock().unwrap();
+
+          App::present(
+            &image_preview,
+            out_buf,
+            if show_heat.load(Ordering::SeqCst) {
+              Some(&timings)
+            } else {
+              None
+            },
+            *selection.lock().unwrap(),
+          );
         }
 
         let done = done.load(Ordering::SeqCst);
@@ -598,14 +1513,47 @@ impl AppRenderCallback {
 
         let safe_total = cmp::max(1, total);
 
-        status_progress.set_fraction(done as f64 / safe_total as f64);
+        // Tiles that are partway done count for what they've reported
+        let partial: f32 = working
+          .read()
+          .unwrap()
+          .values()
+          .filter(|(t, _)| t.generation() == generation)
+          .map(|(_, p)| p)
+          .sum();
 
-        let text = if qlen < CHUNK_SIZE {
+        status_progress
+          .set_fraction((done as f64 + partial as f64) / safe_total as f64);
+
+        let mut text = if qlen < CHUNK_SIZE {
           format!("{} / {}", done, total)
         } else {
           format!("{} / {} (blitting {})", done, total, qlen)
         };
 
+        {
+          let failed = failed.lock().unwrap();
+
+          if let Some((_, msg)) = failed.first() {
+            text.push_str(&format!(
+              " \u{2014} {} failed: {}",
+              failed.len(),
+              msg
+            ));
+          }
+        }
+
+        if let Some(ref msg) = *error.lock().unwrap() {
+          text.push_str(&format!(" \u{2014} render stopped: {}", msg));
+        }
+
+        if total > 0 && done >= total {
+          text.push_str(&format!(
+            " \u{2014} {}",
+            timings.lock().unwrap().summary()
+          ));
+        }
+
         status_text.set_text(&text);
 
         save_btn.set_sensitive(done >= safe_total);
@@ -620,15 +1568,25 @@ impl AppRenderCallback {
   }
 }
 
-// TODO: use a lighter-weight preview for faster filters
 impl RenderCallback for AppRenderCallback {
   type Tag = AppRenderCallbackTag;
 
-  fn before_begin(&self, ntiles: usize) {
+  fn before_begin(&self, generation: usize, ntiles: usize, w: u32, h: u32) {
+    {
+      // Held so handle_tile can't count a stale tile towards this render
+      let _q = self.q.lock().unwrap();
+
+      self.generation.store(generation, Ordering::SeqCst);
+      self.done.store(0, Ordering::SeqCst);
+    }
+
+    *self.size.lock().unwrap() = (w, h);
     self.total.store(ntiles, Ordering::SeqCst);
-    self.done.store(0, Ordering::SeqCst);
     self.clear_buf.store(true, Ordering::SeqCst);
     self.working.write().unwrap().clear();
+    self.failed.lock().unwrap().clear();
+    *self.error.lock().unwrap() = None;
+    self.timings.lock().unwrap().clear();
 
     self.dispatch_worker();
   }
@@ -643,8 +1601,34 @@ impl RenderCallback for AppRenderCallback {
     }
   }
 
+  fn handle_error(&self, generation: usize, msg: &str) {
+    if generation != self.generation.load(Ordering::SeqCst) {
+      return;
+    }
+
+    println!("render stopped: {}", msg);
+
+    *self.error.lock().unwrap() = Some(msg.to_string());
+
+    self.dispatch_worker();
+  }
+
   fn before_tile(&self, tile: Arc<AppTaggedTile>, wid: usize) {
-    self.working.write().unwrap().insert(wid, tile);
+    if !self.is_current(&tile) {
+      return;
+    }
+
+    self.working.write().unwrap().insert(wid, (tile, 0.0));
+
+    self.dispatch_worker();
+  }
+
+  fn handle_preview(&self, tile: Arc<AppTaggedTile>, _: usize) {
+    if !self.is_current(&tile) {
+      return;
+    }
+
+    self.q.lock().unwrap().push_back(tile);
 
     self.dispatch_worker();
   }
@@ -652,16 +1636,86 @@ impl RenderCallback for AppRenderCallback {
   fn handle_tile(&self, tile: Arc<AppTaggedTile>, wid: usize) {
     // TODO: determine if Danger<Pixbuf> is safe enough to blit to from another thread
 
-    self.done.fetch_add(1, Ordering::SeqCst);
-
     {
       let mut q = self.q.lock().unwrap();
       let mut working = self.working.write().unwrap();
 
+      if !self.is_current(&tile) {
+        return;
+      }
+
+      self.done.fetch_add(1, Ordering::SeqCst);
+
       q.push_back(tile);
       working.remove(&wid);
     }
 
     self.dispatch_worker();
   }
+
+  fn handle_progress(&self, tile: Arc<AppTaggedTile>, wid: usize, frac: f32) {
+    if !self.is_current(&tile) {
+      return;
+    }
+
+    {
+      let mut working = self.working.write().unwrap();
+
+      let entry = match working.get_mut(&wid) {
+        Some(e) => e,
+        None => return,
+      };
+
+      if !Arc::ptr_eq(&entry.0, &tile) {
+        return;
+      }
+
+      // Redrawing is only worth it once there's a visible change
+      if frac - entry.1 < 0.01 && frac < 1.0 {
+        return;
+      }
+
+      entry.1 = frac;
+    }
+
+    self.dispatch_worker();
+  }
+
+  fn handle_timing(&self, timing: TileTiming) {
+    if timing.generation != self.generation.load(Ordering::SeqCst) {
+      return;
+    }
+
+    self.timings.lock().unwrap().push(timing);
+  }
+
+  fn handle_panic(&self, tile: Arc<AppTaggedTile>, wid: usize, msg: &str) {
+    if !self.is_current(&tile) {
+      return;
+    }
+
+    let (x, y, _, _) = tile.tile().bounds();
+
+    println!("tile at ({}, {}) panicked: {}", x, y, msg);
+
+    // Preview tiles never count toward progress
+    let preview = tile.tile().scale() > 1;
+
+    if !preview {
+      self.done.fetch_add(1, Ordering::SeqCst);
+    }
+
+    {
+      let mut failed = self.failed.lock().unwrap();
+      let mut working = self.working.write().unwrap();
+
+      failed.push((tile, msg.to_string()));
+
+      if !preview {
+        working.remove(&wid);
+      }
+    }
+
+    self.dispatch_worker();
+  }
 }
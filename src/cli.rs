@@ -0,0 +1,488 @@
+use filters::{self, ctor, params::*, ArcFilter, FilterCtor};
+use image_io::{self, Depth};
+use num_cpus;
+use pipeline::Pipeline;
+use render::{DummyRenderProc, RenderCallback, Renderer, TaggedTile, TileSize};
+use std::{
+  env,
+  io::{self, Write},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+use storage::{Layout, PixelFormat, SampleFormat};
+use timing::{TileTiming, Timings};
+use worker::WorkerPool;
+
+const USAGE: &str = "\
+usage: ingot render [options] <input> <output>
+
+options:
+  -f, --filter <name>         add a filter to the pipeline (can be repeated)
+  -p, --param <name>=<value>  set a parameter of the last filter added (image
+                              parameters take a file path)
+  -l, --list-filters          list all filters and their parameters
+  -d, --depth <8|16|float>    bit depth of the output (default: same as the
+                              input, if the output format supports it)
+  -t, --timings <file>        save how long each tile took to render, as .csv
+                              or .json
+  -W, --tile-width <px>       width of each tile (default: automatic)
+  -H, --tile-height <px>      height of each tile (default: automatic)
+  -j, --threads <n>           number of threads to render with
+                              (default: one per core)
+  -M, --memory <MiB>          keep at most this much image data in memory,
+                              paging the rest to a scratch file (default: no
+                              limit)
+  -S, --storage <f32|f16|u16>
+                              how to store images between filters, trading
+                              precision for memory (default: f32)
+      --planar                store each color channel separately
+      --no-icc                ignore embedded color profiles, and treat every
+                              image as sRGB
+      --isolate               render tiles in separate worker processes, so a
+                              filter that crashes only fails its own tiles";
+
+// Options that apply to both the GUI and `ingot render`
+#[derive(Clone, Copy)]
+pub struct RenderOpts {
+  tile_w: Option<u32>,
+  tile_h: Option<u32>,
+  njobs: Option<usize>,
+  memory: Option<usize>,
+  format: PixelFormat,
+  no_icc: bool,
+  isolate: bool,
+}
+
+impl RenderOpts {
+  fn new() -> Self {
+    Self {
+      tile_w: None,
+      tile_h: None,
+      njobs: None,
+      memory: None,
+      format: PixelFormat::default(),
+      no_icc: false,
+      isolate: false,
+    }
+  }
+
+  // If only one dimension was given, the tiles are square
+  pub fn tile_size(&self) -> TileSize {
+    match (self.tile_w, self.tile_h) {
+      (Some(w), Some(h)) => TileSize::Fixed(w, h),
+      (Some(s), None) | (None, Some(s)) => TileSize::Fixed(s, s),
+      (None, None) => TileSize::Auto,
+    }
+  }
+
+  pub fn njobs(&self) -> usize { self.njobs.unwrap_or_else(num_cpus::get) }
+
+  // The memory budget in bytes, if one was given
+  pub fn memory_budget(&self) -> Option<usize> {
+    self.memory.map(|m| m * 1024 * 1024)
+  }
+
+  pub fn pixel_format(&self) -> PixelFormat { self.format }
+
+  pub fn use_icc(&self) -> bool { !self.no_icc }
+
+  pub fn isolate(&self) -> bool { self.isolate }
+}
+
+struct StageArgs {
+  filter: String,
+  params: Vec<(String, String)>,
+}
+
+struct RenderArgs {
+  opts: RenderOpts,
+  stages: Vec<StageArgs>,
+  list_filters: bool,
+  depth: Option<Depth>,
+  timings_path: Option<PathBuf>,
+  in_path: Option<PathBuf>,
+  out_path: Option<PathBuf>,
+}
+
+// Turns a human-readable name like "Median Blur (naive)" into something that
+// can be typed on the command line, like "median_blur_naive"
+fn slug(name: &str) -> String {
+  let mut ret = String::new();
+
+  for c in name.chars() {
+    if c.is_alphanumeric() {
+      ret.extend(c.to_lowercase());
+    } else if !ret.is_empty() && !ret.ends_with('_') {
+      ret.push('_');
+    }
+  }
+
+  while ret.ends_with('_') {
+    ret.pop();
+  }
+
+  ret
+}
+
+fn name_matches(name: &str, query: &str) -> bool {
+  name.eq_ignore_ascii_case(query) || slug(name) == slug(query)
+}
+
+fn parse_positive<T>(val: Option<&String>, opt: &str) -> Result<T, String>
+where
+  T: ::std::str::FromStr + Default + PartialOrd,
+{
+  let val = val.ok_or_else(|| format!("missing value for {}", opt))?;
+
+  match val.parse() {
+    Ok(n) if n > T::default() => Ok(n),
+    _ => Err(format!(
+      "expected a positive number for {}, got '{}'",
+      opt, val
+    )),
+  }
+}
+
+// Parses arg if it's one of the options in RenderOpts, taking its value from
+// rest.  Returns false if it isn't.
+fn parse_render_opt<'a, I>(
+  arg: &str,
+  rest: &mut I,
+  opts: &mut RenderOpts,
+) -> Result<bool, String>
+where
+  I: Iterator<Item = &'a String>,
+{
+  match arg {
+    "-W" | "--tile-width" => {
+      opts.tile_w = Some(parse_positive(rest.next(), "--tile-width")?)
+    },
+    "-H" | "--tile-height" => {
+      opts.tile_h = Some(parse_positive(rest.next(), "--tile-height")?)
+    },
+    "-j" | "--threads" => {
+      opts.njobs = Some(parse_positive(rest.next(), "--threads")?)
+    },
+    "-M" | "--memory" => {
+      opts.memory = Some(parse_positive(rest.next(), "--memory")?)
+    },
+    "-S" | "--storage" => {
+      let format = rest.next().ok_or("missing value for --storage")?;
+
+      opts.format.sample = SampleFormat::from_id(format).ok_or_else(|| {
+        format!("expected f32, f16 or u16 for --storage, got '{}'", format)
+      })?;
+    },
+    "--planar" => opts.format.layout = Layout::Planar,
+    "--no-icc" => opts.no_icc = true,
+    "--isolate" => opts.isolate = true,
+    _ => return Ok(false),
+  }
+
+  Ok(true)
+}
+
+// Pulls the RenderOpts out of the GUI's arguments, leaving the rest for GTK
+pub fn parse_gui_args(
+  args: &[String],
+) -> Result<(Vec<String>, RenderOpts), String> {
+  let mut opts = RenderOpts::new();
+  let mut rest = Vec::new();
+
+  let mut args = args.iter();
+
+  while let Some(arg) = args.next() {
+    if !parse_render_opt(arg, &mut args, &mut opts)? {
+      rest.push(arg.clone());
+    }
+  }
+
+  Ok((rest, opts))
+}
+
+fn parse_args(args: &[String]) -> Result<RenderArgs, String> {
+  let mut ret = RenderArgs {
+    opts: RenderOpts::new(),
+    stages: Vec::new(),
+    list_filters: false,
+    depth: None,
+    timings_path: None,
+    in_path: None,
+    out_path: None,
+  };
+
+  let mut args = args.iter();
+
+  while let Some(arg) = args.next() {
+    if parse_render_opt(arg, &mut args, &mut ret.opts)? {
+      continue;
+    }
+
+    match arg.as_str() {
+      "-f" | "--filter" => {
+        let name = args.next().ok_or("missing value for --filter")?;
+
+        ret.stages.push(StageArgs {
+          filter: name.clone(),
+          params: Vec::new(),
+        });
+      },
+      "-p" | "--param" => {
+        let param = args.next().ok_or("missing value for --param")?;
+
+        let eq = param
+          .find('=')
+          .ok_or_else(|| format!("expected <name>=<value>, got '{}'", param))?;
+
+        let stage = ret
+          .stages
+          .last_mut()
+          .ok_or("--param must come after the --filter it applies to")?;
+
+        stage
+          .params
+          .push((param[..eq].to_string(), param[eq + 1..].to_string()));
+      },
+      "-l" | "--list-filters" => ret.list_filters = true,
+      "-d" | "--depth" => {
+        let depth = args.next().ok_or("missing value for --depth")?;
+
+        ret.depth = Some(Depth::from_id(depth).ok_or_else(|| {
+          format!("expected 8, 16 or float for --depth, got '{}'", depth)
+        })?);
+      },
+      "-t" | "--timings" => {
+        let path =
+          PathBuf::from(args.next().ok_or("missing value for --timings")?);
+
+        Timings::check_path(&path)?;
+
+        ret.timings_path = Some(path);
+      },
+      s if s.starts_with('-') && s.len() > 1 => {
+        return Err(format!("unknown option '{}'", s));
+      },
+      s => {
+        if ret.in_path.is_none() {
+          ret.in_path = Some(s.into());
+        } else if ret.out_path.is_none() {
+          ret.out_path = Some(s.into());
+        } else {
+          return Err(format!("unexpected argument '{}'", s));
+        }
+      },
+    }
+  }
+
+  Ok(ret)
+}
+
+// Workers for --isolate, started from this same executable as `ingot worker`
+pub fn own_workers() -> Result<WorkerPool, String> {
+  let exe = env::current_exe()
+    .map_err(|e| format!("couldn't find Ingot's executable: {}", e))?;
+
+  Ok(WorkerPool::new(exe, vec!["worker".to_string()]))
+}
+
+pub fn set_param(
+  param: &Param,
+  val: &str,
+  use_icc: bool,
+) -> Result<(), String> {
+  use self::ParamVal as P;
+
+  let Param(name, pval) = param;
+
+  let bad_val = || format!("invalid value '{}' for parameter {}", val, name);
+
+  match pval {
+    P::Switch(b) => b.set(match val.to_lowercase().as_str() {
+      "1" | "true" | "yes" | "on" => true,
+      "0" | "false" | "no" | "off" => false,
+      _ => return Err(bad_val()),
+    }),
+    P::SpinInt(i) => i.set(val.parse().map_err(|_| bad_val())?),
+    P::RangedInt(r) => r.set(val.parse().map_err(|_| bad_val())?),
+    P::RangedFloat(r) => r.set(val.parse().map_err(|_| bad_val())?),
+    P::Image(i) => {
+      let path = Path::new(val);
+
+      let (img, _) = image_io::load(path, use_icc).map_err(|e| {
+        format!("couldn't open image for parameter {}: {}", name, e)
+      })?;
+
+      i.set(path, img);
+    },
+  }
+
+  Ok(())
+}
+
+fn print_filters(filters: &Vec<ArcFilter>) {
+  for flt in filters {
+    println!("{} ({})", slug(flt.name()), flt.name());
+
+    for Param(name, _) in flt.params() {
+      println!("  {}", slug(name));
+    }
+  }
+}
+
+// Progress is followed through the render's handle, so this only collects
+// what the handle doesn't say
+#[derive(Clone)]
+struct HeadlessRenderCallback {
+  timings: Arc<Mutex<Timings>>,
+}
+
+impl HeadlessRenderCallback {
+  fn new() -> Self {
+    Self {
+      timings: Arc::new(Mutex::new(Timings::new())),
+    }
+  }
+}
+
+impl RenderCallback for HeadlessRenderCallback {
+  type Tag = ();
+
+  fn before_begin(&self, _: usize, _: usize, _: u32, _: u32) {
+    self.timings.lock().unwrap().clear();
+  }
+
+  fn handle_tile(&self, _: Arc<TaggedTile<()>>, _: usize) {}
+
+  fn handle_timing(&self, timing: TileTiming) {
+    self.timings.lock().unwrap().push(timing);
+  }
+
+  fn handle_panic(&self, tile: Arc<TaggedTile<()>>, _: usize, msg: &str) {
+    let (x, y, _, _) = tile.tile().bounds();
+
+    eprintln!("\r  tile at ({}, {}) panicked: {}", x, y, msg);
+  }
+}
+
+pub fn render_main(
+  args: &[String],
+  filter_list: Vec<FilterCtor>,
+) -> Result<(), String> {
+  let args = parse_args(args).map_err(|e| format!("{}\n\n{}", e, USAGE))?;
+
+  let ctors: Vec<_> = vec![ctor(filters::DummyFilter::new)]
+    .into_iter()
+    .chain(filter_list)
+    .collect();
+
+  // These are only used for looking up names, since each stage needs its own
+  // instance of its filter
+  let filters: Vec<_> = ctors.iter().map(|c| c()).collect();
+
+  if args.list_filters {
+    print_filters(&filters);
+    return Ok(());
+  }
+
+  let (in_path, out_path) = match (args.in_path, args.out_path) {
+    (Some(i), Some(o)) => (i, o),
+    _ => return Err(format!("missing input or output file\n\n{}", USAGE)),
+  };
+
+  let mut pipeline = Pipeline::new();
+
+  if args.opts.isolate() {
+    pipeline.set_workers(Some(Arc::new(own_workers()?)));
+  }
+
+  for stage in &args.stages {
+    let idx = filters
+      .iter()
+      .position(|f| name_matches(f.name(), &stage.filter))
+      .ok_or_else(|| format!("unknown filter '{}'", stage.filter))?;
+
+    let filter = ctors[idx]();
+
+    for (name, val) in &stage.params {
+      let param = filter
+        .params()
+        .iter()
+        .find(|Param(n, _)| name_matches(n, name))
+        .ok_or_else(|| {
+          format!("filter {} has no parameter '{}'", filter.name(), name)
+        })?;
+
+      set_param(param, val, args.opts.use_icc())?;
+    }
+
+    pipeline.push(filter);
+  }
+
+  let callback = HeadlessRenderCallback::new();
+
+  let mut renderer = Renderer::new(
+    args.opts.tile_size(),
+    args.opts.njobs(),
+    Arc::new(DummyRenderProc),
+    callback.clone(),
+  );
+
+  renderer.set_memory_budget(args.opts.memory_budget());
+  renderer.set_pixel_format(args.opts.pixel_format());
+
+  eprintln!("loading {:?}", in_path);
+
+  // The image goes straight into the renderer's store, so the memory budget
+  // covers it from the start
+  let (in_img, in_depth) =
+    image_io::load_store(&in_path, args.opts.use_icc(), renderer.pager())
+      .map_err(|e| format!("couldn't open image: {}", e))?;
+
+  eprintln!(
+    "rendering with {}...",
+    pipeline
+      .stages()
+      .iter()
+      .map(|s| s.filter().name())
+      .collect::<Vec<_>>()
+      .join(", ")
+  );
+
+  let handle = renderer.render(in_img, pipeline.procs());
+
+  for progress in handle.progress() {
+    eprint!("\r  {} / {}", progress.done, progress.total);
+    io::stderr().flush().ok();
+  }
+
+  eprintln!();
+
+  let out_img = handle.wait_store();
+
+  {
+    let timings = callback.timings.lock().unwrap();
+
+    eprintln!("took {}", timings.summary());
+
+    if let Some(ref path) = args.timings_path {
+      eprintln!("saving timings to {:?}", path);
+
+      timings
+        .save(path)
+        .map_err(|e| format!("couldn't save timings: {}", e))?;
+    }
+  }
+
+  let out_img = out_img?;
+
+  eprintln!("saving {:?}", out_path);
+
+  let depth = args
+    .depth
+    .unwrap_or_else(|| image_io::depth_for(&out_path, in_depth));
+
+  image_io::save(&out_path, &out_img, depth)
+    .map_err(|e| format!("couldn't save image: {}", e))?;
+
+  Ok(())
+}
@@ -0,0 +1,290 @@
+use nalgebra::{Matrix3, Vector3};
+use render::{Pixel, Quantum};
+
+// The values a proc works on.  Everything between procs is kept in linear
+// light, where averaging and blending behave the way light does; perceptual
+// values are sRGB-encoded, and spaced closer to how bright things look.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpace {
+  Linear,
+  Perceptual,
+}
+
+impl ColorSpace {
+  // Converts a pixel from linear light into this space
+  pub fn encode(&self, px: Pixel) -> Pixel {
+    match self {
+      ColorSpace::Linear => px,
+      ColorSpace::Perceptual => Pixel::new(
+        linear_to_srgb(px[0]),
+        linear_to_srgb(px[1]),
+        linear_to_srgb(px[2]),
+        px[3],
+      ),
+    }
+  }
+
+  // Converts a pixel in this space back to linear light
+  pub fn decode(&self, px: Pixel) -> Pixel {
+    match self {
+      ColorSpace::Linear => px,
+      ColorSpace::Perceptual => Pixel::new(
+        srgb_to_linear(px[0]),
+        srgb_to_linear(px[1]),
+        srgb_to_linear(px[2]),
+        px[3],
+      ),
+    }
+  }
+}
+
+// The sRGB transfer functions.  Values outside [0, 1] (which float images can
+// have) are mirrored around zero and continue along the curve past one.
+
+pub fn srgb_to_linear(v: Quantum) -> Quantum {
+  let a = v.abs();
+
+  let l = if a <= 0.04045 {
+    a / 12.92
+  } else {
+    ((a + 0.055) / 1.055).powf(2.4)
+  };
+
+  if v < 0.0 {
+    -l
+  } else {
+    l
+  }
+}
+
+pub fn linear_to_srgb(v: Quantum) -> Quantum {
+  let a = v.abs();
+
+  let s = if a <= 0.0031308 {
+    a * 12.92
+  } else {
+    1.055 * a.powf(1.0 / 2.4) - 0.055
+  };
+
+  if v < 0.0 {
+    -s
+  } else {
+    s
+  }
+}
+
+// Maps an ICC profile's encoded values to linear ones
+enum Curve {
+  Gamma(Quantum),
+  // Evenly spaced samples, interpolated between
+  Table(Vec<Quantum>),
+  // The general form of ICC parametric curves, as [g, a, b, c, d, e, f]:
+  //   y = (ax + b)^g + e  if x >= d
+  //   y = cx + f          otherwise
+  Param([Quantum; 7]),
+}
+
+impl Curve {
+  fn eval(&self, x: Quantum) -> Quantum {
+    match self {
+      Curve::Gamma(g) => x.max(0.0).powf(*g),
+      Curve::Table(t) => {
+        let pos = x.max(0.0).min(1.0) * (t.len() - 1) as Quantum;
+        let i = pos.floor() as usize;
+
+        if i + 1 >= t.len() {
+          t[t.len() - 1]
+        } else {
+          t[i] + (t[i + 1] - t[i]) * (pos - i as Quantum)
+        }
+      },
+      Curve::Param([g, a, b, c, d, e, f]) => {
+        if x >= *d {
+          (a * x + b).max(0.0).powf(*g) + e
+        } else {
+          c * x + f
+        }
+      },
+    }
+  }
+}
+
+// Converts from the D50 XYZ space ICC profiles are relative to into linear
+// sRGB, with Bradford chromatic adaptation to sRGB's D65 white point
+fn xyz_d50_to_srgb() -> Matrix3<Quantum> {
+  Matrix3::new(
+    3.1338561, -1.6168667, -0.4906146, //
+    -0.9787684, 1.9161415, 0.0334540, //
+    0.0719453, -0.2289914, 1.4052427,
+  )
+}
+
+// An embedded ICC profile.  Only matrix/TRC profiles (which covers most RGB
+// and grayscale profiles cameras and editors embed) are supported, not ones
+// built from lookup tables.
+pub struct Profile {
+  // One curve per channel, or just one for grayscale
+  curves: Vec<Curve>,
+  // Converts the profile's linear values to linear sRGB
+  matrix: Matrix3<Quantum>,
+}
+
+impl Profile {
+  pub fn parse(data: &[u8]) -> Result<Self, String> {
+    let u32_at = |at: usize| data.get(at..at + 4).map(be_u32);
+
+    let trunc = || "truncated color profile".to_string();
+
+    // The tag table follows the 128-byte header
+    let ntags = u32_at(128).ok_or_else(trunc)? as usize;
+
+    // The count comes from the file, so it has to be checked against the
+    // space the table can take before anything is allocated for it
+    if ntags > data.len().saturating_sub(132) / 12 {
+      return Err(trunc());
+    }
+
+    let mut tags = Vec::with_capacity(ntags);
+
+    for i in 0..ntags {
+      let at = 132 + i * 12;
+
+      let sig = data.get(at..at + 4).ok_or_else(trunc)?;
+      let offset = u32_at(at + 4).ok_or_else(trunc)? as usize;
+      let size = u32_at(at + 8).ok_or_else(trunc)? as usize;
+
+      let end = offset.checked_add(size).ok_or_else(trunc)?;
+
+      tags.push((sig, data.get(offset..end).ok_or_else(trunc)?));
+    }
+
+    let tag = |sig: &[u8]| tags.iter().find(|t| t.0 == sig).map(|t| t.1);
+
+    let curve = |sig: &[u8]| {
+      tag(sig)
+        .ok_or_else(|| {
+          format!("color profile is missing {}", String::from_utf8_lossy(sig))
+        })
+        .and_then(parse_curve)
+    };
+
+    match data.get(16..20) {
+      Some(b"RGB ") => {
+        let mut cols = Vec::new();
+
+        for sig in &[b"rXYZ", b"gXYZ", b"bXYZ"] {
+          let xyz = tag(*sig).and_then(parse_xyz).ok_or_else(|| {
+            "only matrix-based color profiles are supported".to_string()
+          })?;
+
+          cols.push(xyz);
+        }
+
+        Ok(Self {
+          curves: vec![curve(b"rTRC")?, curve(b"gTRC")?, curve(b"bTRC")?],
+          matrix: xyz_d50_to_srgb() * Matrix3::from_columns(&cols),
+        })
+      },
+      // Gray maps straight to luminance, and D50 and D65 white are both
+      // neutral after adaptation
+      Some(b"GRAY") => Ok(Self {
+        curves: vec![curve(b"kTRC")?],
+        matrix: Matrix3::identity(),
+      }),
+      _ => Err("only RGB and grayscale color profiles are supported".into()),
+    }
+  }
+
+  // Converts a pixel from the profile's encoding to linear sRGB
+  pub fn to_linear(&self, px: Pixel) -> Pixel {
+    let rgb = match self.curves.len() {
+      1 => Vector3::from_element(self.curves[0].eval(px[0])),
+      _ => {
+        self.matrix
+          * Vector3::new(
+            self.curves[0].eval(px[0]),
+            self.curves[1].eval(px[1]),
+            self.curves[2].eval(px[2]),
+          )
+      },
+    };
+
+    Pixel::new(rgb[0], rgb[1], rgb[2], px[3])
+  }
+}
+
+fn be_u16(b: &[u8]) -> u16 { (b[0] as u16) << 8 | b[1] as u16 }
+
+fn be_u32(b: &[u8]) -> u32 {
+  (be_u16(&b[0..2]) as u32) << 16 | be_u16(&b[2..4]) as u32
+}
+
+// ICC's s15Fixed16Number
+fn fixed(b: &[u8]) -> Quantum { be_u32(b) as i32 as Quantum / 65536.0 }
+
+fn parse_xyz(data: &[u8]) -> Option<Vector3<Quantum>> {
+  if data.len() < 20 || &data[0..4] != b"XYZ " {
+    return None;
+  }
+
+  Some(Vector3::new(
+    fixed(&data[8..12]),
+    fixed(&data[12..16]),
+    fixed(&data[16..20]),
+  ))
+}
+
+fn parse_curve(data: &[u8]) -> Result<Curve, String> {
+  let bad = || "malformed curve in color profile".to_string();
+
+  match data.get(0..4) {
+    Some(b"curv") => {
+      let count = be_u32(data.get(8..12).ok_or_else(bad)?) as usize;
+
+      let entries = data.get(12..12 + count * 2).ok_or_else(bad)?;
+
+      Ok(match count {
+        0 => Curve::Gamma(1.0),
+        // A single entry is a gamma value, in u8Fixed8Number format
+        1 => Curve::Gamma(be_u16(entries) as Quantum / 256.0),
+        _ => Curve::Table(
+          entries
+            .chunks(2)
+            .map(|e| be_u16(e) as Quantum / 65535.0)
+            .collect(),
+        ),
+      })
+    },
+    Some(b"para") => {
+      let kind = be_u16(data.get(8..10).ok_or_else(bad)?);
+
+      let nparams = match kind {
+        0 => 1,
+        1 => 3,
+        2 => 4,
+        3 => 5,
+        4 => 7,
+        _ => {
+          return Err(format!("unknown curve type {} in color profile", kind))
+        },
+      };
+
+      let p: Vec<_> = data
+        .get(12..12 + nparams * 4)
+        .ok_or_else(bad)?
+        .chunks(4)
+        .map(fixed)
+        .collect();
+
+      // Fill in the general form from each of the special cases
+      Ok(Curve::Param(match kind {
+        0 => [p[0], 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        1 => [p[0], p[1], p[2], 0.0, -p[2] / p[1], 0.0, 0.0],
+        2 => [p[0], p[1], p[2], 0.0, -p[2] / p[1], p[3], p[3]],
+        3 => [p[0], p[1], p[2], p[3], p[4], 0.0, 0.0],
+        _ => [p[0], p[1], p[2], p[3], p[4], p[5], p[6]],
+      }))
+    },
+    _ => Err(bad()),
+  }
+}
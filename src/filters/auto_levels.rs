@@ -0,0 +1,149 @@
+use super::prelude::*;
+use std::{
+  any::Any,
+  f32::{INFINITY, NEG_INFINITY},
+};
+
+// The darkest and lightest value of each color channel
+#[derive(Clone, Copy)]
+struct Levels {
+  min: [Quantum; 3],
+  max: [Quantum; 3],
+}
+
+impl Levels {
+  fn empty() -> Self {
+    Self {
+      min: [INFINITY; 3],
+      max: [NEG_INFINITY; 3],
+    }
+  }
+}
+
+struct Proc {
+  levels: RwLock<Levels>,
+  param_per_channel: Arc<BoolParam>,
+  param_amt: Arc<RangedParam<f64>>,
+}
+
+pub struct AutoLevelsFilter {
+  params: Vec<Param>,
+  proc: Arc<Proc>,
+}
+
+impl AutoLevelsFilter {
+  pub fn new() -> Self {
+    let param_per_channel = Arc::new(BoolParam::new(false));
+    let param_amt = Arc::new(RangedParam::new(1.0, 0.0, 1.0, 0.0, 1.0));
+
+    Self {
+      params: vec![
+        Param("Per Channel".to_string(), param_per_channel.clone().into()),
+        Param("Amount".to_string(), param_amt.clone().into()),
+      ],
+      proc: Arc::new(Proc {
+        levels: RwLock::new(Levels::empty()),
+        param_per_channel,
+        param_amt,
+      }),
+    }
+  }
+}
+
+impl Filter for AutoLevelsFilter {
+  fn name(&self) -> &str { "Auto Levels" }
+
+  fn params(&self) -> &Vec<Param> { &self.params }
+
+  fn proc(&self) -> ArcProc { self.proc.clone() as ArcProc }
+}
+
+impl RenderProc for Proc {
+  fn needs_analysis(&self) -> bool { true }
+
+  fn analyze_tile(&self, tile: &Tile) -> Analysis {
+    let mut levels = Levels::empty();
+
+    for r in 0..tile.h() {
+      for c in 0..tile.w() {
+        let px = tile.get_input(c, r);
+
+        // Fully transparent pixels don't show, whatever their color
+        if px[3] <= 0.0 {
+          continue;
+        }
+
+        for i in 0..3 {
+          levels.min[i] = levels.min[i].min(px[i]);
+          levels.max[i] = levels.max[i].max(px[i]);
+        }
+      }
+    }
+
+    Box::new(levels)
+  }
+
+  fn combine(&self, a: Analysis, b: Analysis) -> Analysis {
+    let mut a = *a.downcast_ref::<Levels>().unwrap();
+    let b = b.downcast_ref::<Levels>().unwrap();
+
+    for i in 0..3 {
+      a.min[i] = a.min[i].min(b.min[i]);
+      a.max[i] = a.max[i].max(b.max[i]);
+    }
+
+    Box::new(a)
+  }
+
+  fn analyzed(&self, result: &(Any + Send + Sync)) {
+    *self.levels.write().unwrap() = *result.downcast_ref::<Levels>().unwrap();
+  }
+
+  // Levels are adjusted on the encoded values, like other editors do
+  fn color_space(&self) -> ColorSpace { ColorSpace::Perceptual }
+
+  fn cache_key(&self) -> Option<u64> {
+    Some(hash(&[self.param_per_channel.key(), self.param_amt.key()]))
+  }
+
+  fn process_tile(&self, tile: &Tile, cancel_tok: &CancelTok) {
+    let mut out_buf = tile.out_buf();
+
+    let mut levels = *self.levels.read().unwrap();
+
+    // Stretching every channel by the same amount keeps the colors in balance
+    if !self.param_per_channel.get() {
+      let min = levels.min.iter().fold(INFINITY, |a, &b| a.min(b));
+      let max = levels.max.iter().fold(NEG_INFINITY, |a, &b| a.max(b));
+
+      levels.min = [min; 3];
+      levels.max = [max; 3];
+    }
+
+    let amt = self.param_amt.get() as f32;
+
+    for r in 0..tile.h() {
+      let r_stride = r * tile.w();
+
+      if cancel_tok.cancelled() {
+        break;
+      }
+
+      for c in 0..tile.w() {
+        let px = tile.get_input(c, r);
+        let mut out = px;
+
+        for i in 0..3 {
+          let range = levels.max[i] - levels.min[i];
+
+          // Flat (or empty) channels are left alone
+          if range > 0.0 {
+            out[i] = (px[i] - levels.min[i]) / range;
+          }
+        }
+
+        out_buf[(r_stride + c) as usize] = out * amt + px * (1.0 - amt);
+      }
+    }
+  }
+}
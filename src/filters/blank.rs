@@ -25,6 +25,11 @@ impl Filter for BlankFilter {
 }
 
 impl RenderProc for Proc {
+  // The fill color is given in sRGB, like colors usually are
+  fn color_space(&self) -> ColorSpace { ColorSpace::Perceptual }
+
+  fn cache_key(&self) -> Option<u64> { Some(0) }
+
   fn process_tile(&self, tile: &Tile, _: &CancelTok) {
     let mut out_buf = tile.out_buf();
 
@@ -0,0 +1,114 @@
+use super::prelude::*;
+
+struct Proc {
+  param_layer: Arc<ImageParam>,
+  param_mask: Arc<ImageParam>,
+  param_mode: Arc<RangedParam<i32>>,
+  param_opacity: Arc<RangedParam<f64>>,
+}
+
+pub struct BlendFilter {
+  params: Vec<Param>,
+  proc: Arc<Proc>,
+}
+
+impl BlendFilter {
+  pub fn new() -> Self {
+    let param_layer = Arc::new(ImageParam::new());
+    let param_mask = Arc::new(ImageParam::new());
+    let param_mode = Arc::new(RangedParam::new(0, 0, 3, 0, 3));
+    let param_opacity = Arc::new(RangedParam::new(1.0, 0.0, 1.0, 0.0, 1.0));
+
+    Self {
+      params: vec![
+        Param("Layer".to_string(), param_layer.clone().into()),
+        Param("Mask".to_string(), param_mask.clone().into()),
+        Param("Mode".to_string(), param_mode.clone().into()),
+        Param("Opacity".to_string(), param_opacity.clone().into()),
+      ],
+      proc: Arc::new(Proc {
+        param_layer,
+        param_mask,
+        param_mode,
+        param_opacity,
+      }),
+    }
+  }
+}
+
+impl Filter for BlendFilter {
+  fn name(&self) -> &str { "Blend" }
+
+  fn params(&self) -> &Vec<Param> { &self.params }
+
+  fn proc(&self) -> ArcProc { self.proc.clone() as ArcProc }
+}
+
+impl RenderProc for Proc {
+  fn inputs(&self) -> Vec<Option<(u64, Arc<PixelBuf>)>> {
+    vec![self.param_layer.input(), self.param_mask.input()]
+  }
+
+  fn cache_key(&self) -> Option<u64> {
+    Some(hash(&[
+      self.param_layer.key(),
+      self.param_mask.key(),
+      self.param_mode.key(),
+      self.param_opacity.key(),
+    ]))
+  }
+
+  // This is fast enough that we can ignore the cancellation token
+  fn process_tile(&self, tile: &Tile, _: &CancelTok) {
+    let mut out_buf = tile.out_buf();
+
+    let mode = self.param_mode.get();
+    let opacity = self.param_opacity.get() as Quantum;
+
+    let has_layer = tile.has_extra(0);
+    let has_mask = tile.has_extra(1);
+
+    for r in 0..tile.h() {
+      let r_stride = r * tile.w();
+
+      for c in 0..tile.w() {
+        let base = tile.get_input(c, r);
+
+        if !has_layer {
+          out_buf[(r_stride + c) as usize] = base;
+          continue;
+        }
+
+        let layer = tile.get_extra(0, c, r);
+
+        // Modes are (in order) normal, multiply, screen and difference
+        let mixed = match mode {
+          1 => base.component_mul(&layer),
+          2 => {
+            let one = Pixel::from_element(1.0);
+
+            one - (one - base).component_mul(&(one - layer))
+          },
+          3 => (base - layer).abs(),
+          _ => layer,
+        };
+
+        let mut alpha = layer[3] * opacity;
+
+        // The mask's luminance decides how much of the layer shows through
+        if has_mask {
+          let mask = tile.get_extra(1, c, r);
+
+          alpha *=
+            (0.2126 * mask[0] + 0.7152 * mask[1] + 0.0722 * mask[2]) * mask[3];
+        }
+
+        let mut px = base + (mixed - base) * alpha;
+
+        px[3] = alpha + base[3] * (1.0 - alpha);
+
+        out_buf[(r_stride + c) as usize] = px;
+      }
+    }
+  }
+}
@@ -0,0 +1,88 @@
+use super::prelude::*;
+
+struct Proc {
+  param_radius: Arc<RangedParam<i32>>,
+}
+
+pub struct BoxBlurFilter {
+  params: Vec<Param>,
+  proc: Arc<Proc>,
+}
+
+impl BoxBlurFilter {
+  pub fn new() -> Self {
+    let param_radius = Arc::new(RangedParam::new(5, 0, 100, 0, None));
+
+    Self {
+      params: vec![Param("Radius".to_string(), param_radius.clone().into())],
+      proc: Arc::new(Proc { param_radius }),
+    }
+  }
+}
+
+impl Filter for BoxBlurFilter {
+  fn name(&self) -> &str { "Box Blur" }
+
+  fn params(&self) -> &Vec<Param> { &self.params }
+
+  fn proc(&self) -> ArcProc { self.proc.clone() as ArcProc }
+}
+
+impl RenderProc for Proc {
+  // The blur is separable, so the first pass blurs each row and the second
+  // blurs each column of the result
+  fn passes(&self) -> u32 { 2 }
+
+  fn halo(&self) -> Option<u32> { Some(self.param_radius.get() as u32) }
+
+  fn cache_key(&self) -> Option<u64> { Some(hash(&[self.param_radius.key()])) }
+
+  fn process_tile(&self, tile: &Tile, cancel_tok: &CancelTok) {
+    let mut out_buf = tile.out_buf();
+
+    // Shrink the window to match the preview's resolution
+    let radius = ((self.param_radius.get() as u32 + tile.scale() / 2)
+      / tile.scale()) as i32;
+
+    let n = (radius * 2 + 1) as f32;
+
+    // Both passes slide a window along a line of pixels, so only the axis
+    // changes between them
+    let (lines, len) = match tile.pass() {
+      0 => (tile.h(), tile.w()),
+      _ => (tile.w(), tile.h()),
+    };
+
+    let sample = |line: u32, i: i32| match tile.pass() {
+      0 => tile.sample(i, line as i32, Edge::Clamp),
+      _ => tile.sample(line as i32, i, Edge::Clamp),
+    };
+
+    for line in 0..lines {
+      if cancel_tok.cancelled() {
+        break;
+      }
+
+      let mut sum = Pixel::zeros();
+
+      for i in -radius..radius + 1 {
+        sum += sample(line, i);
+      }
+
+      for i in 0..len {
+        let idx = match tile.pass() {
+          0 => line * tile.w() + i,
+          _ => i * tile.w() + line,
+        };
+
+        out_buf[idx as usize] = sum / n;
+
+        let i = i as i32;
+
+        sum += sample(line, i + radius + 1) - sample(line, i - radius);
+      }
+
+      cancel_tok.set_progress((line + 1) as f32 / lines as f32);
+    }
+  }
+}
@@ -0,0 +1,93 @@
+use super::prelude::*;
+use std::cmp;
+
+struct Proc {
+  param_left: Arc<RangedParam<i32>>,
+  param_top: Arc<RangedParam<i32>>,
+  param_right: Arc<RangedParam<i32>>,
+  param_bottom: Arc<RangedParam<i32>>,
+}
+
+pub struct CropFilter {
+  params: Vec<Param>,
+  proc: Arc<Proc>,
+}
+
+impl CropFilter {
+  pub fn new() -> Self {
+    // Negative values pad the image with transparency instead
+    let side = || Arc::new(RangedParam::new(0, -500, 500, None, None));
+
+    let param_left = side();
+    let param_top = side();
+    let param_right = side();
+    let param_bottom = side();
+
+    Self {
+      params: vec![
+        Param("Left".to_string(), param_left.clone().into()),
+        Param("Top".to_string(), param_top.clone().into()),
+        Param("Right".to_string(), param_right.clone().into()),
+        Param("Bottom".to_string(), param_bottom.clone().into()),
+      ],
+      proc: Arc::new(Proc {
+        param_left,
+        param_top,
+        param_right,
+        param_bottom,
+      }),
+    }
+  }
+}
+
+impl Filter for CropFilter {
+  fn name(&self) -> &str { "Crop" }
+
+  fn params(&self) -> &Vec<Param> { &self.params }
+
+  fn proc(&self) -> ArcProc { self.proc.clone() as ArcProc }
+}
+
+impl RenderProc for Proc {
+  fn output_size(&self, w: u32, h: u32) -> (u32, u32) {
+    let w =
+      w as i64 - self.param_left.get() as i64 - self.param_right.get() as i64;
+    let h =
+      h as i64 - self.param_top.get() as i64 - self.param_bottom.get() as i64;
+
+    (cmp::max(w, 1) as u32, cmp::max(h, 1) as u32)
+  }
+
+  fn halo(&self) -> Option<u32> { None }
+
+  fn cache_key(&self) -> Option<u64> {
+    Some(hash(&[
+      self.param_left.key(),
+      self.param_top.key(),
+      self.param_right.key(),
+      self.param_bottom.key(),
+    ]))
+  }
+
+  // This is fast enough that we can ignore the cancellation token
+  fn process_tile(&self, tile: &Tile, _: &CancelTok) {
+    let mut out_buf = tile.out_buf();
+
+    // Preview tiles are scaled down, so the offsets have to be too
+    let scale = tile.scale() as i32;
+    let left = self.param_left.get() / scale;
+    let top = self.param_top.get() / scale;
+
+    for r in 0..tile.h() {
+      let r_stride = r * tile.w();
+
+      for c in 0..tile.w() {
+        out_buf[(r_stride + c) as usize] = tile.global_sample(
+          (tile.x() + c) as i32 + left,
+          (tile.y() + r) as i32 + top,
+          Edge::Transparent,
+        );
+      }
+    }
+  }
+}
@@ -0,0 +1,83 @@
+use super::prelude::*;
+
+struct Proc {
+  param_map: Arc<ImageParam>,
+  param_amount: Arc<RangedParam<f64>>,
+}
+
+pub struct DisplaceFilter {
+  params: Vec<Param>,
+  proc: Arc<Proc>,
+}
+
+impl DisplaceFilter {
+  pub fn new() -> Self {
+    let param_map = Arc::new(ImageParam::new());
+    let param_amount = Arc::new(RangedParam::new(10.0, 0.0, 100.0, 0.0, None));
+
+    Self {
+      params: vec![
+        Param("Map".to_string(), param_map.clone().into()),
+        Param("Amount".to_string(), param_amount.clone().into()),
+      ],
+      proc: Arc::new(Proc {
+        param_map,
+        param_amount,
+      }),
+    }
+  }
+}
+
+impl Filter for DisplaceFilter {
+  fn name(&self) -> &str { "Displace" }
+
+  fn params(&self) -> &Vec<Param> { &self.params }
+
+  fn proc(&self) -> ArcProc { self.proc.clone() as ArcProc }
+}
+
+impl RenderProc for Proc {
+  fn halo(&self) -> Option<u32> { None }
+
+  // Maps are read the way they were painted, so mid-gray means no shift
+  fn color_space(&self) -> ColorSpace { ColorSpace::Perceptual }
+
+  fn inputs(&self) -> Vec<Option<(u64, Arc<PixelBuf>)>> {
+    vec![self.param_map.input()]
+  }
+
+  fn cache_key(&self) -> Option<u64> {
+    Some(hash(&[self.param_map.key(), self.param_amount.key()]))
+  }
+
+  // This is fast enough that we can ignore the cancellation token
+  fn process_tile(&self, tile: &Tile, _: &CancelTok) {
+    let mut out_buf = tile.out_buf();
+
+    // The red channel moves pixels sideways and green moves them up and down,
+    // by up to this many pixels either way
+    let amount = self.param_amount.get() as Quantum / tile.scale() as Quantum;
+
+    let has_map = tile.has_extra(0);
+
+    for r in 0..tile.h() {
+      let r_stride = r * tile.w();
+
+      for c in 0..tile.w() {
+        let px = if has_map {
+          let map = tile.get_extra(0, c, r);
+
+          tile.sample(
+            c as i32 + ((map[0] - 0.5) * 2.0 * amount).round() as i32,
+            r as i32 + ((map[1] - 0.5) * 2.0 * amount).round() as i32,
+            Edge::Clamp,
+          )
+        } else {
+          tile.get_input(c, r)
+        };
+
+        out_buf[(r_stride + c) as usize] = px;
+      }
+    }
+  }
+}
@@ -51,6 +51,12 @@ impl RenderProc for Proc {
     data.h = h;
   }
 
+  fn halo(&self) -> Option<u32> { None }
+
+  fn cache_key(&self) -> Option<u64> {
+    Some(hash(&[self.param_flipx.key(), self.param_flipy.key()]))
+  }
+
   // This is fast enough that we can ignore the cancellation token
   fn process_tile(&self, tile: &Tile, _: &CancelTok) {
     let data = self.data.read().unwrap();
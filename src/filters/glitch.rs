@@ -1,10 +1,15 @@
 use super::prelude::*;
-use rand::prelude::*;
-use std::{
-  cmp,
-  collections::{BTreeMap, Bound},
-  mem,
-};
+use rand::Rng;
+use rng;
+use std::cmp;
+
+// The furthest any row's sampling window can reach past a pixel
+const MAX_REACH: u32 = 40;
+
+// Rows are glitched in bands, each starting wherever a row's noise falls under
+// this, and no longer than BAND_MAX rows
+const BAND_ODDS: f32 = 1.0 / 45.0;
+const BAND_MAX: u32 = 100;
 
 struct RowData {
   radius: u32,
@@ -12,14 +17,46 @@ struct RowData {
   offy: i32,
 }
 
-struct Data {
-  w: u32,
-  h: u32,
-  row_data: BTreeMap<u32, RowData>,
+// A band of full-size rows, up to (but not including) end
+struct Band {
+  end: u32,
+  data: RowData,
+}
+
+impl Band {
+  fn starts_at(seed: u64, row: u32) -> bool {
+    row % BAND_MAX == 0 || rng::noise(seed, 0, row) < BAND_ODDS
+  }
+
+  // The band holding a full-size row.  Every band is picked from the noise of
+  // the rows it covers, so it comes out the same however the image is tiled.
+  fn around(seed: u64, row: u32) -> Self {
+    let mut start = row;
+
+    while !Self::starts_at(seed, start) {
+      start = start - 1;
+    }
+
+    let mut end = row + 1;
+
+    while !Self::starts_at(seed, end) {
+      end = end + 1;
+    }
+
+    let mut gen = StableRng::new(hash(&[seed, start as u64]));
+
+    Self {
+      end,
+      data: RowData {
+        radius: gen.gen_range(0, 30),
+        offx: gen.gen_range(-10, 10),
+        offy: gen.gen_range(-10, 10),
+      },
+    }
+  }
 }
 
 struct Proc {
-  data: RwLock<Data>,
   param_seed: Arc<IntParam>,
   param_perc: Arc<RangedParam<f64>>,
   param_flipat: Arc<RangedParam<f64>>,
@@ -46,11 +83,6 @@ impl GlitchFilter {
         Param("Gran. Offs.".to_string(), param_flipoff.clone().into()),
       ],
       proc: Arc::new(Proc {
-        data: RwLock::new(Data {
-          w: 0,
-          h: 0,
-          row_data: BTreeMap::new(),
-        }),
         param_seed,
         param_perc,
         param_flipat,
@@ -69,10 +101,9 @@ impl Filter for GlitchFilter {
 }
 
 impl Proc {
-  fn process_px<'a>(
+  fn process_px(
     &self,
     tile: &Tile,
-    data: &RwLockReadGuard<'a, Data>,
     r: u32,
     c: u32,
     tile_data: &TileData,
@@ -131,15 +162,8 @@ impl Proc {
     let ry = cmp::min(3, radius);
 
     for r2 in (r - ry)..(r + ry) {
-      let r2 =
-        cmp::max(0, cmp::min((data.h - 1) as i32, r2 + tile.y() as i32)) as u32;
-
       for c2 in (c - rx)..(c + rx) {
-        let c2 =
-          cmp::max(0, cmp::min((data.w - 1) as i32, c2 + tile.x() as i32))
-            as u32;
-
-        let px = tile.global_input(c2, r2);
+        let px = tile.sample(c2, r2, Edge::Clamp);
 
         for i in 0..4 {
           samples[i].push(px[i]);
@@ -172,67 +196,19 @@ struct TileData {
 }
 
 impl RenderProc for Proc {
-  fn begin(&self, w: u32, h: u32) {
-    fn gen_seed(seed: u64) -> <SmallRng as SeedableRng>::Seed {
-      let mut ret: <SmallRng as SeedableRng>::Seed = Default::default();
-
-      for i in 0..ret.len() {
-        let shift = (ret.len() - i - 1) * 8;
-
-        ret[i] = if shift < 64 {
-          ((seed >> shift) & 0xff) as u8
-        } else {
-          0
-        };
-      }
-
-      ret
-    }
-
-    fn gen_rng(seeder: &mut SmallRng) -> SmallRng {
-      let mut seed: <SmallRng as SeedableRng>::Seed = Default::default();
-
-      seeder.fill(&mut seed);
-
-      SmallRng::from_seed(seed)
-    }
-
-    let mut data = self.data.write().unwrap();
-
-    let seed = self.param_seed.get() as u64 * w as u64 * h as u64;
-
-    let mut seeder = SmallRng::from_seed(gen_seed(seed));
-
-    let mut count_rng = gen_rng(&mut seeder);
-    let mut radius_rng = gen_rng(&mut seeder);
-    let mut offx_rng = gen_rng(&mut seeder);
-    let mut offy_rng = gen_rng(&mut seeder);
-
-    let mut row_data: Vec<(u32, RowData)> = Vec::new();
-    let mut row: u32 = 0;
-
-    while row < h {
-      let count: u32 = count_rng.gen_range(10, 100);
-
-      row = row + count;
-
-      row_data.push((
-        row,
-        RowData {
-          radius: radius_rng.gen_range(0, 30),
-          offx: offx_rng.gen_range(-10, 10),
-          offy: offy_rng.gen_range(-10, 10),
-        },
-      ));
-    }
-
-    data.w = w;
-    data.h = h;
-    data.row_data = row_data.into_iter().collect();
+  fn halo(&self) -> Option<u32> { Some(MAX_REACH) }
+
+  fn cache_key(&self) -> Option<u64> {
+    Some(hash(&[
+      self.param_seed.key(),
+      self.param_perc.key(),
+      self.param_flipat.key(),
+      self.param_flipoff.key(),
+    ]))
   }
 
   fn process_tile(&self, tile: &Tile, cancel_tok: &CancelTok) {
-    let data = self.data.read().unwrap();
+    let seed = self.param_seed.get() as u64;
     let mut out_buf = tile.out_buf();
 
     let tile_data = TileData {
@@ -241,24 +217,19 @@ impl RenderProc for Proc {
       flipoff: self.param_flipoff.get(),
     };
 
-    let mut row_data_src = data
-      .row_data
-      .range((Bound::Included(tile.y()), Bound::Unbounded));
-
-    let mut curr_row_data: Option<&RowData> = None;
-    let mut next_data_row: u32 = 0;
+    // Bands are laid out over full-size rows, so previews follow the rows of
+    // the image they stand in for
+    let mut band = Band::around(seed, tile.y() * tile.scale());
 
     'row_loop: for r in 0..tile.h() {
       let r_stride = r * tile.w();
+      let row = (tile.y() + r) * tile.scale();
 
-      if r >= next_data_row {
-        let (row, data) = row_data_src.next().unwrap();
-
-        curr_row_data = Some(data);
-        next_data_row = row - tile.y();
+      if row >= band.end {
+        band = Band::around(seed, row);
       }
 
-      let curr_row_data = curr_row_data.unwrap();
+      let curr_row_data = &band.data;
 
       if curr_row_data.radius < 30 {
         if cancel_tok.cancelled() {
@@ -267,7 +238,7 @@ impl RenderProc for Proc {
 
         for c in 0..tile.w() {
           out_buf[(r_stride + c) as usize] =
-            self.process_px(tile, &data, r, c, &tile_data, curr_row_data);
+            self.process_px(tile, r, c, &tile_data, curr_row_data);
         }
       } else {
         for c in 0..tile.w() {
@@ -276,9 +247,11 @@ impl RenderProc for Proc {
           }
 
           out_buf[(r_stride + c) as usize] =
-            self.process_px(tile, &data, r, c, &tile_data, curr_row_data);
+            self.process_px(tile, r, c, &tile_data, curr_row_data);
         }
       }
+
+      cancel_tok.set_progress((r + 1) as f32 / tile.h() as f32);
     }
   }
 }
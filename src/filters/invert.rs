@@ -29,6 +29,12 @@ impl Filter for InvertFilter {
 }
 
 impl RenderProc for Proc {
+  // Inverting light intensities would push everything towards white, so this
+  // flips the encoded values like other editors do
+  fn color_space(&self) -> ColorSpace { ColorSpace::Perceptual }
+
+  fn cache_key(&self) -> Option<u64> { Some(hash(&[self.param_amt.key()])) }
+
   // This is fast enough that we can ignore the cancellation token
   fn process_tile(&self, tile: &Tile, _: &CancelTok) {
     let mut out_buf = tile.out_buf();
@@ -1,4 +1,9 @@
+mod auto_levels;
 mod blank;
+mod blend;
+mod box_blur;
+mod crop;
+mod displace;
 mod dummy;
 mod flip;
 mod glitch;
@@ -6,16 +11,23 @@ mod invert;
 mod naive_median;
 mod panic;
 pub mod params;
+mod rotate;
 
 pub use self::{
-  blank::*, dummy::*, flip::*, glitch::*, invert::*, naive_median::*, panic::*,
+  auto_levels::*, blank::*, blend::*, box_blur::*, crop::*, displace::*,
+  dummy::*, flip::*, glitch::*, invert::*, naive_median::*, panic::*,
+  rotate::*,
 };
 
 // TODO: look into creating a macro to define filters
 
 mod prelude {
   pub use super::{params::*, ArcProc, Filter};
-  pub use render::{CancelTok, Pixel, Quantum, RenderProc, Tile};
+  pub use color::ColorSpace;
+  pub use render::{
+    Analysis, CancelTok, Edge, Pixel, PixelBuf, Quantum, RenderProc, Tile,
+  };
+  pub use rng::{hash, StableRng};
   pub use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 }
 
@@ -23,6 +35,25 @@ use self::prelude::*;
 
 pub type ArcProc = Arc<RenderProc + Send + Sync>;
 
+pub type ArcFilter = Arc<Filter + Send + Sync>;
+
+pub type FilterCtor = Box<Fn() -> ArcFilter>;
+
+pub fn flt<T>(f: T) -> ArcFilter
+where
+  T: Filter + Send + Sync + 'static,
+{
+  Arc::new(f) as ArcFilter
+}
+
+pub fn ctor<T, F>(f: F) -> FilterCtor
+where
+  T: Filter + Send + Sync + 'static,
+  F: Fn() -> T + 'static,
+{
+  Box::new(move || flt(f()))
+}
+
 pub trait Filter {
   fn name(&self) -> &str;
 
@@ -1,13 +1,6 @@
 use super::prelude::*;
-use std::cmp;
-
-struct Data {
-  w: u32,
-  h: u32,
-}
 
 struct Proc {
-  data: RwLock<Data>,
   param_radius: Arc<RangedParam<i32>>,
 }
 
@@ -22,10 +15,7 @@ impl NaiveMedianFilter {
 
     Self {
       params: vec![Param("Radius".to_string(), param_radius.clone().into())],
-      proc: Arc::new(Proc {
-        data: RwLock::new(Data { w: 0, h: 0 }),
-        param_radius,
-      }),
+      proc: Arc::new(Proc { param_radius }),
     }
   }
 }
@@ -39,14 +29,7 @@ impl Filter for NaiveMedianFilter {
 }
 
 impl Proc {
-  fn process_px<'a>(
-    &self,
-    tile: &Tile,
-    data: &RwLockReadGuard<'a, Data>,
-    r: u32,
-    c: u32,
-    radius: u32,
-  ) -> Pixel {
+  fn process_px(&self, tile: &Tile, r: u32, c: u32, radius: u32) -> Pixel {
     if radius < 1 {
       return tile.get_input(c, r);
     }
@@ -58,15 +41,8 @@ impl Proc {
     let radius = radius as i32;
 
     for r2 in (r - radius)..(r + radius) {
-      let r2 =
-        cmp::max(0, cmp::min((data.h - 1) as i32, r2 + tile.y() as i32)) as u32;
-
       for c2 in (c - radius)..(c + radius) {
-        let c2 =
-          cmp::max(0, cmp::min((data.w - 1) as i32, c2 + tile.x() as i32))
-            as u32;
-
-        let px = tile.global_input(c2, r2);
+        let px = tile.sample(c2, r2, Edge::Clamp);
 
         for i in 0..4 {
           samples[i].push(px[i]);
@@ -88,18 +64,16 @@ impl Proc {
 }
 
 impl RenderProc for Proc {
-  fn begin(&self, w: u32, h: u32) {
-    let mut data = self.data.write().unwrap();
+  fn halo(&self) -> Option<u32> { Some(self.param_radius.get() as u32) }
 
-    data.w = w;
-    data.h = h;
-  }
+  fn cache_key(&self) -> Option<u64> { Some(hash(&[self.param_radius.key()])) }
 
   fn process_tile(&self, tile: &Tile, cancel_tok: &CancelTok) {
-    let data = self.data.read().unwrap();
     let mut out_buf = tile.out_buf();
 
-    let radius = self.param_radius.get() as u32;
+    // Shrink the window to match the preview's resolution
+    let radius =
+      (self.param_radius.get() as u32 + tile.scale() / 2) / tile.scale();
 
     if radius < 30 {
       'row_loop_a: for r in 0..tile.h() {
@@ -111,8 +85,10 @@ impl RenderProc for Proc {
 
         for c in 0..tile.w() {
           out_buf[(r_stride + c) as usize] =
-            self.process_px(tile, &data, r, c, radius);
+            self.process_px(tile, r, c, radius);
         }
+
+        cancel_tok.set_progress((r + 1) as f32 / tile.h() as f32);
       }
     } else {
       'row_loop_b: for r in 0..tile.h() {
@@ -124,8 +100,10 @@ impl RenderProc for Proc {
           }
 
           out_buf[(r_stride + c) as usize] =
-            self.process_px(tile, &data, r, c, radius);
+            self.process_px(tile, r, c, radius);
         }
+
+        cancel_tok.set_progress((r + 1) as f32 / tile.h() as f32);
       }
     }
   }
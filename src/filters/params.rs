@@ -1,6 +1,10 @@
-use std::sync::{
-  atomic::{AtomicBool, AtomicI32, Ordering},
-  Arc, RwLock, RwLockWriteGuard,
+use render::PixelBuf;
+use std::{
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering},
+    Arc, RwLock, RwLockWriteGuard,
+  },
 };
 
 pub struct Param(pub String, pub ParamVal);
@@ -10,6 +14,7 @@ pub enum ParamVal {
   SpinInt(Arc<IntParam>),
   RangedInt(Arc<RangedParam<i32>>),
   RangedFloat(Arc<RangedParam<f64>>),
+  Image(Arc<ImageParam>),
 }
 
 use self::ParamVal::*;
@@ -30,6 +35,10 @@ impl From<Arc<RangedParam<f64>>> for ParamVal {
   fn from(val: Arc<RangedParam<f64>>) -> Self { RangedFloat(val) }
 }
 
+impl From<Arc<ImageParam>> for ParamVal {
+  fn from(val: Arc<ImageParam>) -> Self { Image(val) }
+}
+
 pub struct BoolParam {
   value: AtomicBool,
 }
@@ -44,6 +53,9 @@ impl BoolParam {
   pub fn get(&self) -> bool { self.value.load(Ordering::SeqCst) }
 
   pub fn set(&self, val: bool) { self.value.store(val, Ordering::SeqCst); }
+
+  // For RenderProc::cache_key
+  pub fn key(&self) -> u64 { self.get() as u64 }
 }
 
 pub struct IntParam {
@@ -62,6 +74,8 @@ impl IntParam {
   pub fn set(&self, val: i32) { self.value.store(val, Ordering::SeqCst); }
 
   pub fn swap(&self, val: i32) -> i32 { self.value.swap(val, Ordering::SeqCst) }
+
+  pub fn key(&self) -> u64 { self.get() as u64 }
 }
 
 struct RangedParamValue<T> {
@@ -163,3 +177,66 @@ where
     prev
   }
 }
+
+impl RangedParam<i32> {
+  pub fn key(&self) -> u64 { self.get() as u64 }
+}
+
+impl RangedParam<f64> {
+  pub fn key(&self) -> u64 { self.get().to_bits() }
+}
+
+// Tells apart every image ever given to an ImageParam, for their keys
+static NEXT_IMAGE: AtomicUsize = AtomicUsize::new(1);
+
+// An image a filter reads from alongside its input, such as a mask or a layer
+// to blend.  It's empty until a file is picked.
+pub struct ImageParam {
+  value: RwLock<Option<(PathBuf, Arc<PixelBuf>, usize)>>,
+}
+
+impl ImageParam {
+  pub fn new() -> Self {
+    Self {
+      value: RwLock::new(None),
+    }
+  }
+
+  pub fn get(&self) -> Option<Arc<PixelBuf>> {
+    self.value.read().unwrap().as_ref().map(|v| v.1.clone())
+  }
+
+  // The image along with its key, for RenderProc::inputs
+  pub fn input(&self) -> Option<(u64, Arc<PixelBuf>)> {
+    self
+      .value
+      .read()
+      .unwrap()
+      .as_ref()
+      .map(|v| (v.2 as u64, v.1.clone()))
+  }
+
+  pub fn path(&self) -> Option<PathBuf> {
+    self.value.read().unwrap().as_ref().map(|v| v.0.clone())
+  }
+
+  // The image should already be decoded to linear light
+  pub fn set(&self, path: &Path, img: PixelBuf) {
+    let id = NEXT_IMAGE.fetch_add(1, Ordering::SeqCst);
+
+    *self.value.write().unwrap() =
+      Some((path.to_path_buf(), Arc::new(img), id));
+  }
+
+  pub fn clear(&self) { *self.value.write().unwrap() = None; }
+
+  // Picking the same file again gives a new key, since it may have changed
+  pub fn key(&self) -> u64 {
+    self
+      .value
+      .read()
+      .unwrap()
+      .as_ref()
+      .map_or(0, |v| v.2 as u64)
+  }
+}
@@ -0,0 +1,71 @@
+use super::prelude::*;
+
+struct Proc {
+  param_turns: Arc<RangedParam<i32>>,
+}
+
+pub struct RotateFilter {
+  params: Vec<Param>,
+  proc: Arc<Proc>,
+}
+
+impl RotateFilter {
+  pub fn new() -> Self {
+    let param_turns = Arc::new(RangedParam::new(1, 0, 3, 0, 3));
+
+    Self {
+      params: vec![Param("Turns".to_string(), param_turns.clone().into())],
+      proc: Arc::new(Proc { param_turns }),
+    }
+  }
+}
+
+impl Filter for RotateFilter {
+  fn name(&self) -> &str { "Rotate" }
+
+  fn params(&self) -> &Vec<Param> { &self.params }
+
+  fn proc(&self) -> ArcProc { self.proc.clone() as ArcProc }
+}
+
+impl RenderProc for Proc {
+  fn output_size(&self, w: u32, h: u32) -> (u32, u32) {
+    match self.param_turns.get() {
+      1 | 3 => (h, w),
+      _ => (w, h),
+    }
+  }
+
+  fn halo(&self) -> Option<u32> { None }
+
+  fn cache_key(&self) -> Option<u64> { Some(hash(&[self.param_turns.key()])) }
+
+  // This is fast enough that we can ignore the cancellation token
+  fn process_tile(&self, tile: &Tile, _: &CancelTok) {
+    let mut out_buf = tile.out_buf();
+
+    let turns = self.param_turns.get();
+
+    let in_w = tile.in_w();
+    let in_h = tile.in_h();
+
+    for r in 0..tile.h() {
+      let r_stride = r * tile.w();
+      let y = tile.y() + r;
+
+      for c in 0..tile.w() {
+        let x = tile.x() + c;
+
+        // Each quarter turn is clockwise
+        let px = match turns {
+          1 => tile.global_input(y, in_h - 1 - x),
+          2 => tile.global_input(in_w - 1 - x, in_h - 1 - y),
+          3 => tile.global_input(in_w - 1 - y, x),
+          _ => tile.global_input(x, y),
+        };
+
+        out_buf[(r_stride + c) as usize] = px;
+      }
+    }
+  }
+}
@@ -0,0 +1,1126 @@
+use color::{ColorSpace, Profile};
+use deflate::{write::ZlibEncoder, Compression};
+use image::{
+  self,
+  hdr::{HDRDecoder, HDREncoder, HDRImageDecoderIterator},
+  tiff::TIFFDecoder,
+  ColorType, DecodingResult, ImageDecoder, Rgb, RgbaImage,
+};
+use inflate;
+use png::{self, HasParameters};
+use render::{quantize, Pixel, PixelBuf, Quantum};
+use std::{
+  cmp,
+  collections::HashMap,
+  fs::File,
+  io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+  path::Path,
+  sync::Arc,
+  vec,
+};
+use storage::{ImageStore, Pager, BLOCK_SIZE};
+
+// How many bits each channel of an image file gets
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Depth {
+  U8,
+  U16,
+  F32,
+}
+
+impl Depth {
+  pub fn all() -> &'static [Depth] { &[Depth::U8, Depth::U16, Depth::F32] }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Depth::U8 => "8-bit",
+      Depth::U16 => "16-bit",
+      Depth::F32 => "32-bit float",
+    }
+  }
+
+  // The name used on the command line
+  pub fn id(&self) -> &'static str {
+    match self {
+      Depth::U8 => "8",
+      Depth::U16 => "16",
+      Depth::F32 => "float",
+    }
+  }
+
+  pub fn from_id(id: &str) -> Option<Depth> {
+    Self::all().iter().cloned().find(|d| d.id() == id)
+  }
+
+  // The extensions of the formats that can be saved at this depth
+  pub fn extensions(&self) -> &'static [&'static str] {
+    match self {
+      Depth::U8 => &["png", "tif", "tiff", "jpg", "jpeg", "bmp"],
+      Depth::U16 => &["png", "tif", "tiff"],
+      Depth::F32 => &["tif", "tiff", "hdr"],
+    }
+  }
+}
+
+fn extension(path: &Path) -> String {
+  path
+    .extension()
+    .and_then(|e| e.to_str())
+    .unwrap_or("")
+    .to_lowercase()
+}
+
+// Picks preferred if path's format supports it, or else the deepest depth it
+// does support
+pub fn depth_for(path: &Path, preferred: Depth) -> Depth {
+  let ext = extension(path);
+
+  if preferred.extensions().contains(&ext.as_str()) {
+    return preferred;
+  }
+
+  Depth::all()
+    .iter()
+    .rev()
+    .cloned()
+    .find(|d| d.extensions().contains(&ext.as_str()))
+    .unwrap_or(Depth::U8)
+}
+
+fn open(path: &Path) -> Result<File, String> {
+  File::open(path).map_err(|e| e.to_string())
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>, String> {
+  File::create(path)
+    .map(BufWriter::new)
+    .map_err(|e| e.to_string())
+}
+
+// A decoder that hands over an image a row at a time, so it never has to be
+// held in memory all at once
+trait Rows {
+  fn size(&self) -> (u32, u32);
+
+  // Reads the next row into row, which is as wide as the image
+  fn read_row(&mut self, row: &mut [Pixel]) -> Result<(), String>;
+}
+
+// Builds a pixel out of gray, gray-alpha, RGB or RGBA samples
+fn to_pixel<F: Fn(usize) -> Quantum>(channels: usize, s: F) -> Pixel {
+  match channels {
+    1 => Pixel::new(s(0), s(0), s(0), 1.0),
+    2 => Pixel::new(s(0), s(0), s(0), s(1)),
+    3 => Pixel::new(s(0), s(1), s(2), 1.0),
+    _ => Pixel::new(s(0), s(1), s(2), s(3)),
+  }
+}
+
+// Reads an image into a store belonging to pager, in linear light, along with
+// the depth it was stored at.  If use_icc is set and the image has an embedded
+// color profile, it's decoded with that; otherwise it's taken to be sRGB.
+//
+// The image goes into the store a band of rows at a time, so pager's memory
+// budget covers it as it's read.
+pub fn load_store(
+  path: &Path,
+  use_icc: bool,
+  pager: &Arc<Pager>,
+) -> Result<(ImageStore, Depth), String> {
+  let (mut rows, depth) = open_rows(path)?;
+
+  // Float images hold linear values already
+  let profile = if depth == Depth::F32 {
+    None
+  } else {
+    read_profile(path, use_icc)
+  };
+
+  let (w, h) = rows.size();
+  let store = ImageStore::new(pager.clone(), w, h);
+
+  let mut row = vec![Pixel::zeros(); w as usize];
+  let mut band = Vec::with_capacity((w * BLOCK_SIZE) as usize);
+
+  for y in 0..h {
+    rows.read_row(&mut row)?;
+
+    band.extend(row.iter().map(|&px| match depth {
+      Depth::F32 => px,
+      _ => match profile {
+        Some(ref profile) => profile.to_linear(px),
+        None => ColorSpace::Perceptual.decode(px),
+      },
+    }));
+
+    // Bands line up with the store's blocks, so each block is only written
+    // once
+    if (y + 1) % BLOCK_SIZE == 0 || y + 1 == h {
+      let top = y - y % BLOCK_SIZE;
+
+      store.write_rect(0, top, w, y + 1 - top, &band);
+      band.clear();
+    }
+  }
+
+  Ok((store, depth))
+}
+
+// Reads a whole image into memory, for small ones like filter parameters
+pub fn load(path: &Path, use_icc: bool) -> Result<(PixelBuf, Depth), String> {
+  let (store, depth) = load_store(path, use_icc, &Arc::new(Pager::new(None)))?;
+
+  Ok((store.to_pixel_buf(), depth))
+}
+
+fn read_profile(path: &Path, use_icc: bool) -> Option<Profile> {
+  if !use_icc {
+    return None;
+  }
+
+  let profile = read_icc(path).and_then(|data| match data {
+    Some(data) => Profile::parse(&data).map(Some),
+    None => Ok(None),
+  });
+
+  // A profile that can't be read is no reason not to open the image
+  profile.unwrap_or_else(|e| {
+    eprintln!("ignoring color profile in {:?}: {}", path, e);
+    None
+  })
+}
+
+// Opens an image for reading its values as they're stored
+fn open_rows(path: &Path) -> Result<(Box<Rows>, Depth), String> {
+  match extension(path).as_str() {
+    "hdr" => return Ok((Box::new(HdrRows::new(path)?), Depth::F32)),
+    "png" => return open_png(path),
+    "tif" | "tiff" => {
+      if let Some(rows) = load_float_tiff(path)? {
+        return Ok((Box::new(rows), Depth::F32));
+      }
+
+      if let Some(rows) = load_tiff16(path)? {
+        return Ok((Box::new(rows), Depth::U16));
+      }
+    },
+    _ => {},
+  }
+
+  let img = image::open(path).map_err(|e| e.to_string())?.to_rgba();
+  let (w, h) = img.dimensions();
+
+  Ok((
+    Box::new(Samples::new(w, h, 4, SampleData::U8(img.into_raw()))),
+    Depth::U8,
+  ))
+}
+
+enum SampleData {
+  U8(Vec<u8>),
+  U16(Vec<u16>),
+}
+
+// An image that could only be decoded all at once.  Its samples are kept as
+// they were decoded, which takes a fraction of the space of whole pixels.
+struct Samples {
+  w: u32,
+  h: u32,
+  channels: usize,
+  data: SampleData,
+  // Where the next row starts
+  at: usize,
+}
+
+impl Samples {
+  fn new(w: u32, h: u32, channels: usize, data: SampleData) -> Self {
+    Self {
+      w,
+      h,
+      channels,
+      data,
+      at: 0,
+    }
+  }
+}
+
+impl Rows for Samples {
+  fn size(&self) -> (u32, u32) { (self.w, self.h) }
+
+  fn read_row(&mut self, row: &mut [Pixel]) -> Result<(), String> {
+    let channels = self.channels;
+    let at = self.at;
+    let end = at + self.w as usize * channels;
+
+    let len = match self.data {
+      SampleData::U8(ref d) => d.len(),
+      SampleData::U16(ref d) => d.len(),
+    };
+
+    if end > len {
+      return Err("truncated image data".to_string());
+    }
+
+    for (i, px) in row.iter_mut().enumerate() {
+      let s = at + i * channels;
+
+      *px = match self.data {
+        SampleData::U8(ref d) => {
+          to_pixel(channels, |c| d[s + c] as Quantum / 255.0)
+        },
+        SampleData::U16(ref d) => {
+          to_pixel(channels, |c| d[s + c] as Quantum / 65535.0)
+        },
+      };
+    }
+
+    self.at = end;
+
+    Ok(())
+  }
+}
+
+fn open_png(path: &Path) -> Result<(Box<Rows>, Depth), String> {
+  let mut dec = png::Decoder::new(BufReader::new(open(path)?));
+
+  // The default transformations squash 16-bit images down to 8 bits, and
+  // 16-bit images never need expanding anyway
+  dec.set(png::Transformations::IDENTITY);
+
+  let (info, reader) = dec.read_info().map_err(|e| e.to_string())?;
+
+  let sixteen = match info.bit_depth {
+    png::BitDepth::Sixteen => true,
+    _ => false,
+  };
+
+  // Anything shallower is expanded to 8 bits a sample, with palettes and
+  // transparent colors turned into RGB and alpha
+  let (info, mut reader) = if sixteen {
+    (info, reader)
+  } else {
+    let mut dec = png::Decoder::new(BufReader::new(open(path)?));
+
+    dec.set(png::Transformations::EXPAND);
+    dec.read_info().map_err(|e| e.to_string())?
+  };
+
+  let channels = match info.color_type {
+    png::ColorType::Grayscale => 1,
+    png::ColorType::GrayscaleAlpha => 2,
+    png::ColorType::RGB => 3,
+    png::ColorType::RGBA => 4,
+    png::ColorType::Indexed => {
+      return Err("malformed or unsupported PNG file".to_string())
+    },
+  };
+
+  let depth = if sixteen { Depth::U16 } else { Depth::U8 };
+
+  // Interlaced images come a pass at a time, so they have to be decoded in
+  // full before any row is done
+  if reader.info().interlaced {
+    let mut buf = vec![0; info.buffer_size()];
+
+    reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+
+    let data = if sixteen {
+      SampleData::U16(buf.chunks(2).map(be_u16).collect())
+    } else {
+      SampleData::U8(buf)
+    };
+
+    let rows = Samples::new(info.width, info.height, channels, data);
+
+    return Ok((Box::new(rows), depth));
+  }
+
+  let rows = PngRows {
+    reader,
+    w: info.width,
+    h: info.height,
+    channels,
+    sixteen,
+  };
+
+  Ok((Box::new(rows), depth))
+}
+
+struct PngRows {
+  reader: png::Reader<BufReader<File>>,
+  w: u32,
+  h: u32,
+  channels: usize,
+  sixteen: bool,
+}
+
+impl Rows for PngRows {
+  fn size(&self) -> (u32, u32) { (self.w, self.h) }
+
+  fn read_row(&mut self, row: &mut [Pixel]) -> Result<(), String> {
+    let channels = self.channels;
+    let sixteen = self.sixteen;
+
+    let line = self
+      .reader
+      .next_row()
+      .map_err(|e| e.to_string())?
+      .ok_or("truncated PNG file")?;
+
+    for (i, px) in row.iter_mut().enumerate() {
+      let s = i * channels;
+
+      // PNG samples are big-endian
+      *px = if sixteen {
+        to_pixel(channels, |c| {
+          be_u16(&line[(s + c) * 2..]) as Quantum / 65535.0
+        })
+      } else {
+        to_pixel(channels, |c| line[s + c] as Quantum / 255.0)
+      };
+    }
+
+    Ok(())
+  }
+}
+
+// The following return None if the image isn't in the format they read, in
+// which case image::open can handle it
+
+fn load_tiff16(path: &Path) -> Result<Option<Samples>, String> {
+  let mut dec = TIFFDecoder::new(open(path)?).map_err(|e| e.to_string())?;
+
+  let channels = match dec.colortype().map_err(|e| e.to_string())? {
+    ColorType::Gray(16) => 1,
+    ColorType::GrayA(16) => 2,
+    ColorType::RGB(16) => 3,
+    ColorType::RGBA(16) => 4,
+    _ => return Ok(None),
+  };
+
+  let (w, h) = dec.dimensions().map_err(|e| e.to_string())?;
+
+  let samples = match dec.read_image().map_err(|e| e.to_string())? {
+    DecodingResult::U16(s) => s,
+    DecodingResult::U8(_) => return Ok(None),
+  };
+
+  Ok(Some(Samples::new(w, h, channels, SampleData::U16(samples))))
+}
+
+fn be_u16(b: &[u8]) -> u16 { (b[0] as u16) << 8 | b[1] as u16 }
+
+fn be_u32(b: &[u8]) -> u32 {
+  (be_u16(&b[0..2]) as u32) << 16 | be_u16(&b[2..4]) as u32
+}
+
+// Just enough of a TIFF reader to get at the things image's decoder doesn't
+// handle.  Only the tags are read up front.
+struct Tiff {
+  file: BufReader<File>,
+  // Nothing read from the file can be bigger than this
+  len: u64,
+  le: bool,
+  // Each tag's type, how many values it has, and where they start
+  tags: HashMap<u16, (u16, u64, u64)>,
+}
+
+impl Tiff {
+  fn parse(file: File) -> Option<Self> {
+    let len = file.metadata().ok()?.len();
+
+    let mut ret = Self {
+      file: BufReader::new(file),
+      len,
+      le: true,
+      tags: HashMap::new(),
+    };
+
+    let head = ret.read_at(0, 8)?;
+
+    ret.le = match &head[0..2] {
+      b"II" => true,
+      b"MM" => false,
+      _ => return None,
+    };
+
+    let ifd = ret.u32_from(&head[4..]) as u64;
+    let ntags = ret.read_at(ifd, 2)?;
+    let ntags = ret.u16_from(&ntags) as u64;
+    let entries = ret.read_at(ifd + 2, ntags * 12)?;
+
+    for (i, entry) in entries.chunks(12).enumerate() {
+      let tag = ret.u16_from(&entry[0..]);
+      let kind = ret.u16_from(&entry[2..]);
+      let count = ret.u32_from(&entry[4..]) as u64;
+
+      let size = match kind {
+        3 | 8 => 2,       // SHORT, SSHORT
+        4 | 9 | 11 => 4,  // LONG, SLONG, FLOAT
+        5 | 10 | 12 => 8, // RATIONAL, SRATIONAL, DOUBLE
+        _ => 1,
+      };
+
+      // Values that fit in the entry are stored inline
+      let base = if size * count <= 4 {
+        ifd + 2 + i as u64 * 12 + 8
+      } else {
+        ret.u32_from(&entry[8..]) as u64
+      };
+
+      ret.tags.insert(tag, (kind, count, base));
+    }
+
+    Some(ret)
+  }
+
+  // Lengths come from the file itself, so they're checked against its size
+  // before anything is allocated
+  fn read_at(&mut self, at: u64, len: u64) -> Option<Vec<u8>> {
+    if at.checked_add(len)? > self.len {
+      return None;
+    }
+
+    let mut buf = vec![0; len as usize];
+
+    self.file.seek(SeekFrom::Start(at)).ok()?;
+    self.file.read_exact(&mut buf).ok()?;
+
+    Some(buf)
+  }
+
+  fn u16_from(&self, b: &[u8]) -> u16 {
+    if self.le {
+      b[0] as u16 | (b[1] as u16) << 8
+    } else {
+      be_u16(b)
+    }
+  }
+
+  fn u32_from(&self, b: &[u8]) -> u32 {
+    let a = self.u16_from(&b[0..2]) as u32;
+    let b = self.u16_from(&b[2..4]) as u32;
+
+    if self.le {
+      a | b << 16
+    } else {
+      a << 16 | b
+    }
+  }
+
+  // The values of a SHORT or LONG tag
+  fn values(&mut self, tag: u16) -> Option<Vec<u32>> {
+    let &(kind, count, base) = self.tags.get(&tag)?;
+
+    let size = match kind {
+      3 => 2,
+      4 => 4,
+      _ => return None,
+    };
+
+    let data = self.read_at(base, count.checked_mul(size as u64)?)?;
+
+    Some(
+      data
+        .chunks(size)
+        .map(|b| match size {
+          2 => self.u16_from(b) as u32,
+          _ => self.u32_from(b),
+        })
+        .collect(),
+    )
+  }
+
+  fn first(&mut self, tag: u16) -> Option<u32> {
+    self.values(tag)?.first().cloned()
+  }
+
+  // The raw contents of a BYTE or UNDEFINED tag
+  fn bytes(&mut self, tag: u16) -> Option<Vec<u8>> {
+    let &(_, count, base) = self.tags.get(&tag)?;
+
+    self.read_at(base, count)
+  }
+}
+
+// image can't read floating-point TIFFs, so this handles the simple ones:
+// uncompressed and interleaved, like the ones save writes.  Anything else is
+// left to image.
+fn load_float_tiff(path: &Path) -> Result<Option<FloatTiffRows>, String> {
+  Ok(Tiff::parse(open(path)?).and_then(parse_float_tiff))
+}
+
+fn parse_float_tiff(mut tiff: Tiff) -> Option<FloatTiffRows> {
+  // SampleFormat 3 is IEEE floating point
+  if tiff.first(339) != Some(3) {
+    return None;
+  }
+
+  if tiff.first(259).unwrap_or(1) != 1
+    || tiff.first(284).unwrap_or(1) != 1
+    || tiff.values(258)?.iter().any(|&b| b != 32)
+  {
+    return None;
+  }
+
+  let w = tiff.first(256)?;
+  let h = tiff.first(257)?;
+  let channels = tiff.first(277).unwrap_or(1) as usize;
+
+  if channels < 1 || channels > 4 {
+    return None;
+  }
+
+  let offsets = tiff.values(273)?;
+  let lens = tiff.values(279)?;
+
+  let total: u64 = lens.iter().map(|&l| l as u64).sum();
+
+  let row_len = w as u64 * channels as u64 * 4;
+
+  if total < row_len * h as u64
+    || row_len > tiff.len
+    || offsets
+      .iter()
+      .zip(&lens)
+      .any(|(&o, &l)| o as u64 + l as u64 > tiff.len)
+  {
+    return None;
+  }
+
+  Some(FloatTiffRows {
+    tiff,
+    w,
+    h,
+    channels,
+    strips: offsets
+      .into_iter()
+      .zip(lens)
+      .collect::<Vec<_>>()
+      .into_iter(),
+    left: 0,
+    bytes: vec![0; row_len as usize],
+  })
+}
+
+struct FloatTiffRows {
+  tiff: Tiff,
+  w: u32,
+  h: u32,
+  channels: usize,
+  // The offset and length of each strip not yet started
+  strips: vec::IntoIter<(u32, u32)>,
+  // How much of the current strip is left to read
+  left: usize,
+  bytes: Vec<u8>,
+}
+
+impl Rows for FloatTiffRows {
+  fn size(&self) -> (u32, u32) { (self.w, self.h) }
+
+  fn read_row(&mut self, row: &mut [Pixel]) -> Result<(), String> {
+    let mut filled = 0;
+
+    // Rows can be split across strips
+    while filled < self.bytes.len() {
+      if self.left == 0 {
+        let (offset, len) = self.strips.next().ok_or("truncated TIFF file")?;
+
+        self
+          .tiff
+          .file
+          .seek(SeekFrom::Start(offset as u64))
+          .map_err(|e| e.to_string())?;
+
+        self.left = len as usize;
+        continue;
+      }
+
+      let n = cmp::min(self.left, self.bytes.len() - filled);
+
+      self
+        .tiff
+        .file
+        .read_exact(&mut self.bytes[filled..filled + n])
+        .map_err(|e| e.to_string())?;
+
+      filled += n;
+      self.left -= n;
+    }
+
+    let channels = self.channels;
+    let tiff = &self.tiff;
+    let bytes = &self.bytes;
+
+    for (i, px) in row.iter_mut().enumerate() {
+      let s = i * channels;
+
+      *px = to_pixel(channels, |c| {
+        f32::from_bits(tiff.u32_from(&bytes[(s + c) * 4..]))
+      });
+    }
+
+    Ok(())
+  }
+}
+
+// Returns the ICC profile embedded in an image, if it has one
+fn read_icc(path: &Path) -> Result<Option<Vec<u8>>, String> {
+  let mut file = BufReader::new(open(path)?);
+
+  Ok(match extension(path).as_str() {
+    "png" => png_icc(&mut file)?,
+    "jpg" | "jpeg" => jpeg_icc(&mut file),
+    // Tag 34675 is InterColorProfile
+    "tif" | "tiff" => {
+      Tiff::parse(file.into_inner()).and_then(|mut t| t.bytes(34675))
+    },
+    _ => None,
+  })
+}
+
+fn png_icc<R: Read + Seek>(r: &mut R) -> Result<Option<Vec<u8>>, String> {
+  let mut head = [0; 8];
+
+  // Skip the signature
+  r.seek(SeekFrom::Start(8)).map_err(|e| e.to_string())?;
+
+  while r.read_exact(&mut head).is_ok() {
+    let len = be_u32(&head) as usize;
+
+    match &head[4..8] {
+      b"iCCP" => {
+        let mut body = vec![0; len];
+
+        r.read_exact(&mut body).map_err(|_| "truncated PNG file")?;
+
+        // The profile's name and compression method come first
+        let start = body.iter().position(|&b| b == 0).map(|i| i + 2);
+
+        let zipped = start
+          .and_then(|s| body.get(s..))
+          .ok_or("malformed color profile")?;
+
+        return inflate::inflate_bytes_zlib(zipped).map(Some);
+      },
+      // The profile has to come before the image data
+      b"IDAT" | b"IEND" => break,
+      _ => {},
+    }
+
+    // Skip the chunk's body and CRC
+    r.seek(SeekFrom::Current(len as i64 + 4))
+      .map_err(|e| e.to_string())?;
+  }
+
+  Ok(None)
+}
+
+// A large profile is split across several ICC_PROFILE segments, which are
+// numbered so they can be put back together
+fn jpeg_icc<R: Read + Seek>(r: &mut R) -> Option<Vec<u8>> {
+  let mut chunks = Vec::new();
+  let mut seg = [0; 4];
+
+  r.seek(SeekFrom::Start(2)).ok()?;
+
+  while r.read_exact(&mut seg).is_ok() {
+    // Nothing of interest comes after the start of the scan data
+    if seg[0] != 0xff || seg[1] == 0xda || seg[1] == 0xd9 {
+      break;
+    }
+
+    // The length counts its own two bytes
+    let len = (be_u16(&seg[2..4]) as usize).checked_sub(2)?;
+
+    if seg[1] != 0xe2 {
+      r.seek(SeekFrom::Current(len as i64)).ok()?;
+      continue;
+    }
+
+    let mut body = vec![0; len];
+
+    r.read_exact(&mut body).ok()?;
+
+    if body.len() > 14 && body.starts_with(b"ICC_PROFILE\0") {
+      chunks.push((body[12], body[14..].to_vec()));
+    }
+  }
+
+  if chunks.is_empty() {
+    return None;
+  }
+
+  chunks.sort_by_key(|c| c.0);
+
+  Some(chunks.into_iter().flat_map(|c| c.1).collect())
+}
+
+struct HdrRows {
+  w: u32,
+  h: u32,
+  pixels: HDRImageDecoderIterator<BufReader<File>>,
+}
+
+impl HdrRows {
+  fn new(path: &Path) -> Result<Self, String> {
+    let dec = HDRDecoder::new(BufReader::new(open(path)?))
+      .map_err(|e| e.to_string())?;
+
+    let meta = dec.metadata();
+
+    Ok(Self {
+      w: meta.width,
+      h: meta.height,
+      pixels: dec.into_iter(),
+    })
+  }
+}
+
+impl Rows for HdrRows {
+  fn size(&self) -> (u32, u32) { (self.w, self.h) }
+
+  fn read_row(&mut self, row: &mut [Pixel]) -> Result<(), String> {
+    for px in row.iter_mut() {
+      let Rgb { data } = self
+        .pixels
+        .next()
+        .ok_or("truncated HDR file")?
+        .map_err(|e| e.to_string())?
+        .to_hdr();
+
+      *px = Pixel::new(data[0], data[1], data[2], 1.0);
+    }
+
+    Ok(())
+  }
+}
+
+// Hands f each row of img and its y, encoded for storing at depth.  Rows are
+// read a block's height at a time, so only that much of img has to be in
+// memory at once.
+fn each_row<F>(img: &ImageStore, depth: Depth, mut f: F) -> Result<(), String>
+where
+  F: FnMut(u32, &[Pixel]) -> Result<(), String>,
+{
+  let w = img.w();
+  let mut y = 0;
+
+  while y < img.h() {
+    let band_h = cmp::min(BLOCK_SIZE, img.h() - y);
+    let mut band = img.read_rect(0, y, w, band_h);
+
+    // Integer formats are stored sRGB-encoded, and float formats as linear
+    // light
+    if depth != Depth::F32 {
+      for px in band.iter_mut() {
+        *px = ColorSpace::Perceptual.encode(*px);
+      }
+    }
+
+    for r in 0..band_h {
+      f(y + r, &band[(r * w) as usize..((r + 1) * w) as usize])?;
+    }
+
+    y += band_h;
+  }
+
+  Ok(())
+}
+
+// Writes img out a band at a time, so saving doesn't need more memory than
+// the store it comes from
+pub fn save(path: &Path, img: &ImageStore, depth: Depth) -> Result<(), String> {
+  let ext = extension(path);
+
+  if !depth.extensions().contains(&ext.as_str()) {
+    return Err(format!(
+      "{} images can't be saved as .{} (try {})",
+      depth.name(),
+      ext,
+      depth
+        .extensions()
+        .iter()
+        .map(|e| format!(".{}", e))
+        .collect::<Vec<_>>()
+        .join(", ")
+    ));
+  }
+
+  match (depth, ext.as_str()) {
+    (_, "tif") | (_, "tiff") => save_tiff(path, img, depth),
+    (_, "png") => save_png(path, img, depth),
+    (Depth::F32, _) => save_hdr(path, img),
+    // NB: image can only write these formats from a whole image, so they get
+    //     an 8-bit copy of it
+    (_, _) => {
+      let mut out = RgbaImage::new(img.w(), img.h());
+
+      each_row(img, depth, |y, row| {
+        for (x, px) in row.iter().enumerate() {
+          out.get_pixel_mut(x as u32, y).data = [
+            quantize(px[0], 255.0) as u8,
+            quantize(px[1], 255.0) as u8,
+            quantize(px[2], 255.0) as u8,
+            quantize(px[3], 255.0) as u8,
+          ];
+        }
+
+        Ok(())
+      })?;
+
+      out.save(path).map_err(|e| e.to_string())
+    },
+  }
+}
+
+fn save_tiff(
+  path: &Path,
+  img: &ImageStore,
+  depth: Depth,
+) -> Result<(), String> {
+  // Checked before the file is created, so a failed save doesn't leave one
+  let header = tiff_header(img.w(), img.h(), depth)?;
+  let mut out = create(path)?;
+  let mut bytes = Vec::new();
+
+  out.write_all(&header).map_err(|e| e.to_string())?;
+
+  each_row(img, depth, |_, row| {
+    bytes.clear();
+
+    for px in row {
+      for i in 0..4 {
+        match depth {
+          Depth::U8 => bytes.push(quantize(px[i], 255.0) as u8),
+          Depth::U16 => put_u16(&mut bytes, quantize(px[i], 65535.0) as u16),
+          Depth::F32 => put_u32(&mut bytes, px[i].to_bits()),
+        }
+      }
+    }
+
+    out.write_all(&bytes).map_err(|e| e.to_string())
+  })?;
+
+  out.flush().map_err(|e| e.to_string())
+}
+
+// How much compressed data goes in each IDAT chunk
+const IDAT_SIZE: usize = 1 << 16;
+
+// Passes what's written to it on to a PNG file as image data
+struct Idat<'a, W: Write + 'a>(&'a mut png::Writer<W>);
+
+impl<'a, W: Write> Write for Idat<'a, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+
+    self
+      .0
+      .write_chunk(png::chunk::IDAT, buf)
+      .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+// image's encoder needs the whole image up front, so this filters and
+// compresses the rows itself, the same way it would
+fn save_png(path: &Path, img: &ImageStore, depth: Depth) -> Result<(), String> {
+  let (bits, bpp) = match depth {
+    Depth::U8 => (png::BitDepth::Eight, 4),
+    _ => (png::BitDepth::Sixteen, 8),
+  };
+
+  let mut out = create(path)?;
+
+  {
+    let mut enc = png::Encoder::new(&mut out, img.w(), img.h());
+
+    enc.set(png::ColorType::RGBA).set(bits);
+
+    let mut writer = enc.write_header().map_err(|e| e.to_string())?;
+
+    let mut zlib = ZlibEncoder::new(
+      BufWriter::with_capacity(IDAT_SIZE, Idat(&mut writer)),
+      Compression::Fast,
+    );
+
+    let mut line = Vec::with_capacity(1 + img.w() as usize * bpp);
+
+    each_row(img, depth, |_, row| {
+      line.clear();
+
+      // Every row gets the Sub filter
+      line.push(1);
+
+      // PNG samples are big-endian
+      for px in row {
+        for i in 0..4 {
+          match depth {
+            Depth::U8 => line.push(quantize(px[i], 255.0) as u8),
+            _ => {
+              let v = quantize(px[i], 65535.0) as u16;
+
+              line.push((v >> 8) as u8);
+              line.push(v as u8);
+            },
+          }
+        }
+      }
+
+      for i in (1 + bpp..line.len()).rev() {
+        line[i] = line[i].wrapping_sub(line[i - bpp]);
+      }
+
+      zlib.write_all(&line).map_err(|e| e.to_string())
+    })?;
+
+    zlib
+      .finish()
+      .and_then(|mut idat| idat.flush())
+      .map_err(|e| e.to_string())?;
+
+    // NB: dropping writer ends the file, which is flushed below
+  }
+
+  out.flush().map_err(|e| e.to_string())
+}
+
+// NB: Radiance HDR has no alpha channel, so alpha is dropped
+fn save_hdr(path: &Path, img: &ImageStore) -> Result<(), String> {
+  let mut out = create(path)?;
+
+  write!(
+    out,
+    "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+    img.h(),
+    img.w()
+  )
+  .map_err(|e| e.to_string())?;
+
+  each_row(img, Depth::F32, |_, row| {
+    let data: Vec<_> = row
+      .iter()
+      .map(|px| Rgb {
+        data: [px[0], px[1], px[2]],
+      })
+      .collect();
+
+    let mut encoded = Vec::new();
+
+    HDREncoder::new(&mut encoded)
+      .encode(&data, row.len(), 1)
+      .map_err(|e| e.to_string())?;
+
+    // The encoder writes a header every time, which ends with the line giving
+    // the image's size
+    let start = encoded
+      .windows(4)
+      .position(|w| w == b"\n-Y ")
+      .and_then(|at| {
+        encoded[at + 1..]
+          .iter()
+          .position(|&b| b == b'\n')
+          .map(|n| at + n + 2)
+      })
+      .ok_or("couldn't encode HDR image")?;
+
+    out.write_all(&encoded[start..]).map_err(|e| e.to_string())
+  })?;
+
+  out.flush().map_err(|e| e.to_string())
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+  out.push(v as u8);
+  out.push((v >> 8) as u8);
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+  put_u16(out, v as u16);
+  put_u16(out, (v >> 16) as u16);
+}
+
+// image can't write TIFFs, so save produces bare-bones little-endian RGBA TIFFs
+// with a single uncompressed strip.  This is everything that comes before the
+// pixels.
+fn tiff_header(w: u32, h: u32, depth: Depth) -> Result<Vec<u8>, String> {
+  const SHORT: u16 = 3;
+  const LONG: u16 = 4;
+  const NTAGS: u32 = 12;
+
+  // SampleFormat is 1 for unsigned ints and 3 for floats
+  let (bits, format) = match depth {
+    Depth::U8 => (8, 1),
+    Depth::U16 => (16, 1),
+    Depth::F32 => (32, 3),
+  };
+
+  let data_len = w as u64 * h as u64 * 4 * bits / 8;
+
+  // The header is followed by the IFD, then the per-channel BitsPerSample and
+  // SampleFormat arrays, then the pixels
+  let ifd_at = 8;
+  let bits_at = ifd_at + 2 + NTAGS * 12 + 4;
+  let format_at = bits_at + 8;
+  let data_at = format_at + 8;
+
+  // Offsets in a TIFF file are 32 bits, which covers up to 4 GiB
+  if data_at as u64 + data_len > u32::MAX as u64 {
+    return Err(format!(
+      "a {}x{} image is too big for a TIFF file at this depth",
+      w, h
+    ));
+  }
+
+  let data_len = data_len as u32;
+
+  let mut out = Vec::with_capacity(data_at as usize);
+
+  out.extend_from_slice(b"II");
+  put_u16(&mut out, 42);
+  put_u32(&mut out, ifd_at);
+
+  let tags: [(u16, u16, u32, u32); NTAGS as usize] = [
+    (256, LONG, 1, w),          // ImageWidth
+    (257, LONG, 1, h),          // ImageLength
+    (258, SHORT, 4, bits_at),   // BitsPerSample
+    (259, SHORT, 1, 1),         // Compression: none
+    (262, SHORT, 1, 2),         // PhotometricInterpretation: RGB
+    (273, LONG, 1, data_at),    // StripOffsets
+    (277, SHORT, 1, 4),         // SamplesPerPixel
+    (278, LONG, 1, h),          // RowsPerStrip
+    (279, LONG, 1, data_len),   // StripByteCounts
+    (284, SHORT, 1, 1),         // PlanarConfiguration: interleaved
+    (338, SHORT, 1, 2),         // ExtraSamples: unassociated alpha
+    (339, SHORT, 4, format_at), // SampleFormat
+  ];
+
+  put_u16(&mut out, NTAGS as u16);
+
+  // NB: a single SHORT sits in the low half of the value field, which is where
+  //     it ends up when written as a little-endian LONG
+  for &(tag, kind, count, val) in tags.iter() {
+    put_u16(&mut out, tag);
+    put_u16(&mut out, kind);
+    put_u32(&mut out, count);
+    put_u32(&mut out, val);
+  }
+
+  put_u32(&mut out, 0);
+
+  for _ in 0..4 {
+    put_u16(&mut out, bits as u16);
+  }
+
+  for _ in 0..4 {
+    put_u16(&mut out, format);
+  }
+
+  Ok(out)
+}
@@ -0,0 +1,25 @@
+#![feature(integer_atomics)]
+
+extern crate deflate;
+extern crate image;
+extern crate inflate;
+extern crate nalgebra;
+extern crate num_cpus;
+extern crate png;
+extern crate rand;
+
+pub mod cli;
+pub mod color;
+pub mod filters;
+pub mod image_io;
+pub mod pipeline;
+pub mod render;
+pub mod rng;
+pub mod storage;
+mod thread_pool;
+mod tile_cache;
+pub mod tile_order;
+pub mod timing;
+pub mod worker;
+
+pub use render::{NullCallback, RenderHandle, Renderer};
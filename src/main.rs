@@ -1,31 +1,78 @@
-#![feature(integer_atomics)]
-
 extern crate gdk_pixbuf;
 extern crate gio;
 extern crate glib;
 extern crate gtk;
-extern crate image;
-extern crate nalgebra;
-extern crate num_cpus;
-extern crate rand;
+extern crate ingot;
 
 #[macro_use]
 mod autoclone;
 
 mod app;
 mod danger;
-mod filters;
-mod oneshot_pool;
 mod param_builder;
-mod render;
-mod thread_pool;
+mod pipeline_builder;
+
+// The GUI modules find the library's modules through these, as if they were
+// declared here
+use ingot::{
+  cli, color, filters, image_io, pipeline, render, storage, tile_order, timing,
+  worker,
+};
 
-use app::{flt, App};
+use app::App;
+use filters::{ctor, FilterCtor};
 use gio::{prelude::*, ApplicationFlags};
 use gtk::Application;
-use std::{cell::RefCell, env, rc::Rc};
+use std::{cell::RefCell, env, process, rc::Rc};
+
+fn filter_list() -> Vec<FilterCtor> {
+  vec![
+    ctor(filters::AutoLevelsFilter::new),
+    ctor(filters::BlankFilter::new),
+    ctor(filters::BlendFilter::new),
+    ctor(filters::BoxBlurFilter::new),
+    ctor(filters::CropFilter::new),
+    ctor(filters::DisplaceFilter::new),
+    ctor(filters::FlipFilter::new),
+    ctor(filters::RotateFilter::new),
+    ctor(filters::InvertFilter::new),
+    ctor(filters::NaiveMedianFilter::new),
+    ctor(filters::GlitchFilter::new),
+  ]
+}
 
 fn main() {
+  let args: Vec<_> = env::args().collect();
+
+  if args.len() > 1 && args[1] == "render" {
+    process::exit(match cli::render_main(&args[2..], filter_list()) {
+      Ok(()) => 0,
+      Err(e) => {
+        eprintln!("{}", e);
+        1
+      },
+    });
+  }
+
+  // Started by the renderer itself, when tiles are rendered out of process
+  if args.len() > 1 && args[1] == "worker" {
+    process::exit(match worker::worker_main(filter_list()) {
+      Ok(()) => 0,
+      Err(e) => {
+        eprintln!("{}", e);
+        1
+      },
+    });
+  }
+
+  let (args, opts) = match cli::parse_gui_args(&args) {
+    Ok(a) => a,
+    Err(e) => {
+      eprintln!("{}", e);
+      process::exit(1);
+    },
+  };
+
   let gtk_app =
     Application::new("net.rk1024.ingot", ApplicationFlags::FLAGS_NONE).unwrap();
 
@@ -34,16 +81,10 @@ fn main() {
   gtk_app.connect_startup(autoclone!(app => move |gtk_app| {
     let mut app = app.borrow_mut();
 
-    *app = Some(App::new(gtk_app, vec![
-      flt(filters::BlankFilter::new()),
-      flt(filters::FlipFilter::new()),
-      flt(filters::InvertFilter::new()),
-      flt(filters::NaiveMedianFilter::new()),
-      flt(filters::GlitchFilter::new()),
-    ]));
+    *app = Some(App::new(gtk_app, filter_list(), opts));
   }));
 
   gtk_app.connect_activate(|_| {});
 
-  gtk_app.run(&env::args().collect::<Vec<_>>());
+  gtk_app.run(&args);
 }
@@ -1,8 +1,11 @@
+use app::App;
 use filters::params::*;
 use gtk::{
-  prelude::*, Adjustment, Box as GBox, Entry as GEntry, Grid, Label,
-  Orientation, Scale, SpinButton, Switch,
+  prelude::*, Adjustment, Box as GBox, Button, Entry as GEntry,
+  FileChooserAction, FileChooserButton, Grid, Label, MessageType, Orientation,
+  Scale, SpinButton, Switch, Window,
 };
+use image_io;
 use render::{RenderCallback, Renderer};
 use std::{cell::RefCell, rc::Rc};
 
@@ -10,8 +13,9 @@ pub fn build<C>(
   tool_box: &GBox,
   params: &Vec<Param>,
   renderer: &Rc<RefCell<Renderer<C>>>,
+  use_icc: bool,
 ) where
-  C: RenderCallback + Clone + Send + 'static,
+  C: RenderCallback + Clone + Send + Sync + 'static,
   C::Tag: Default + Send + Sync,
 {
   for child in tool_box.get_children() {
@@ -19,7 +23,7 @@ pub fn build<C>(
   }
 
   for param in params.iter() {
-    build_param(tool_box, &param, renderer);
+    build_param(tool_box, &param, renderer, use_icc);
   }
 
   tool_box.show_all();
@@ -29,8 +33,9 @@ fn build_param<C>(
   tool_box: &GBox,
   param: &Param,
   renderer: &Rc<RefCell<Renderer<C>>>,
+  use_icc: bool,
 ) where
-  C: RenderCallback + Clone + Send + 'static,
+  C: RenderCallback + Clone + Send + Sync + 'static,
   C::Tag: Default + Send + Sync,
 {
   use self::ParamVal as P;
@@ -224,6 +229,73 @@ fn build_param<C>(
 
       entry.set_text(&r.get().to_string());
     },
+    P::Image(i) => {
+      let image_box = GBox::new(Orientation::Horizontal, 2);
+
+      let label = Label::new(name.as_str());
+
+      image_box.pack_start(&label, false, false, 0);
+
+      let chooser =
+        FileChooserButton::new(name.as_str(), FileChooserAction::Open);
+
+      let clear_btn = Button::new_with_label("\u{2715}");
+
+      clear_btn.set_tooltip_text("Clear this image");
+
+      image_box.pack_end(&clear_btn, false, false, 0);
+      image_box.pack_end(&chooser, true, true, 0);
+
+      tool_box.pack_start(&image_box, false, false, 0);
+
+      if let Some(path) = i.path() {
+        chooser.set_filename(&path);
+      }
+
+      chooser.connect_file_set(autoclone!(renderer, i => move |chooser| {
+        let path = match chooser.get_filename() {
+          Some(p) => p,
+          None => return,
+        };
+
+        match image_io::load(&path, use_icc) {
+          Ok((img, _)) => i.set(&path, img),
+          Err(e) => {
+            // Go back to whichever image was picked before
+            match i.path() {
+              Some(p) => {
+                chooser.set_filename(&p);
+              },
+              None => chooser.unselect_all(),
+            }
+
+            App::modal_message(
+              chooser
+                .get_toplevel()
+                .and_then(|w| w.downcast::<Window>().ok())
+                .as_ref(),
+              &format!("Couldn't open image: {}", e),
+              MessageType::Error,
+            );
+
+            return;
+          },
+        }
+
+        renderer.borrow_mut().rerender();
+      }));
+
+      clear_btn.connect_clicked(autoclone!(renderer, i, chooser => move |_| {
+        if i.path().is_none() {
+          return;
+        }
+
+        i.clear();
+        chooser.unselect_all();
+
+        renderer.borrow_mut().rerender();
+      }));
+    },
   }
 }
 
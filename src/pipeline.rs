@@ -0,0 +1,80 @@
+use filters::{ArcFilter, ArcProc};
+use std::sync::Arc;
+use worker::{self, WorkerPool};
+
+pub struct Stage {
+  filter: ArcFilter,
+  // Made once, since the renderer tells procs apart by address
+  proc: ArcProc,
+  bypass: bool,
+}
+
+impl Stage {
+  pub fn filter(&self) -> &ArcFilter { &self.filter }
+
+  pub fn bypass(&self) -> bool { self.bypass }
+}
+
+pub struct Pipeline {
+  stages: Vec<Stage>,
+  // Where tiles are rendered, if not in this process
+  workers: Option<Arc<WorkerPool>>,
+}
+
+impl Pipeline {
+  pub fn new() -> Self {
+    Self {
+      stages: Vec::new(),
+      workers: None,
+    }
+  }
+
+  pub fn stages(&self) -> &Vec<Stage> { &self.stages }
+
+  pub fn push(&mut self, filter: ArcFilter) {
+    let proc = match self.workers {
+      Some(ref pool) => worker::isolate(&filter, pool),
+      None => filter.proc(),
+    };
+
+    self.stages.push(Stage {
+      filter,
+      proc,
+      bypass: false,
+    });
+  }
+
+  pub fn remove(&mut self, idx: usize) { self.stages.remove(idx); }
+
+  pub fn move_up(&mut self, idx: usize) {
+    if idx > 0 && idx < self.stages.len() {
+      self.stages.swap(idx - 1, idx);
+    }
+  }
+
+  pub fn move_down(&mut self, idx: usize) {
+    if idx + 1 < self.stages.len() {
+      self.stages.swap(idx, idx + 1);
+    }
+  }
+
+  pub fn set_bypass(&mut self, idx: usize, bypass: bool) {
+    self.stages[idx].bypass = bypass;
+  }
+
+  // Only affects stages added after this is called
+  pub fn set_workers(&mut self, workers: Option<Arc<WorkerPool>>) {
+    self.workers = workers;
+  }
+
+  // Bypassed stages are left out entirely rather than being replaced with a
+  // passthrough, so they don't cost a render pass
+  pub fn procs(&self) -> Vec<ArcProc> {
+    self
+      .stages
+      .iter()
+      .filter(|s| !s.bypass)
+      .map(|s| s.proc.clone())
+      .collect()
+  }
+}
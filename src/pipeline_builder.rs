@@ -0,0 +1,155 @@
+use gtk::{
+  self, prelude::*, Box as GBox, Button, CheckButton, Frame, Label, Orientation,
+};
+use param_builder;
+use pipeline::Pipeline;
+use render::{RenderCallback, Renderer};
+use std::{cell::RefCell, rc::Rc};
+
+pub fn build<C>(
+  tool_box: &GBox,
+  pipeline: &Rc<RefCell<Pipeline>>,
+  renderer: &Rc<RefCell<Renderer<C>>>,
+  use_icc: bool,
+) where
+  C: RenderCallback + Clone + Send + Sync + 'static,
+  C::Tag: Default + Send + Sync,
+{
+  for child in tool_box.get_children() {
+    tool_box.remove(&child);
+  }
+
+  let nstages = pipeline.borrow().stages().len();
+
+  if nstages == 0 {
+    let label = Label::new(None);
+
+    label.set_markup("<i>no filters</i>");
+
+    tool_box.pack_start(&label, false, false, 0);
+  }
+
+  for idx in 0..nstages {
+    build_stage(tool_box, idx, nstages, pipeline, renderer, use_icc);
+  }
+
+  tool_box.show_all();
+}
+
+// Pushes the pipeline's current procs to the renderer and rebuilds the stage
+// list.  The rebuild is deferred, because this is usually called from a signal
+// handler on a widget that's about to be destroyed.
+pub fn update<C>(
+  tool_box: &GBox,
+  pipeline: &Rc<RefCell<Pipeline>>,
+  renderer: &Rc<RefCell<Renderer<C>>>,
+  use_icc: bool,
+) where
+  C: RenderCallback + Clone + Send + Sync + 'static,
+  C::Tag: Default + Send + Sync,
+{
+  let procs = pipeline.borrow().procs();
+
+  renderer.borrow_mut().set_procs(procs);
+
+  gtk::idle_add({
+    let tool_box = tool_box.downgrade();
+    let pipeline = pipeline.clone();
+    let renderer = renderer.clone();
+
+    move || {
+      if let Some(tool_box) = tool_box.upgrade() {
+        build(&tool_box, &pipeline, &renderer, use_icc);
+      }
+
+      Continue(false)
+    }
+  });
+}
+
+fn build_stage<C>(
+  tool_box: &GBox,
+  idx: usize,
+  nstages: usize,
+  pipeline: &Rc<RefCell<Pipeline>>,
+  renderer: &Rc<RefCell<Renderer<C>>>,
+  use_icc: bool,
+) where
+  C: RenderCallback + Clone + Send + Sync + 'static,
+  C::Tag: Default + Send + Sync,
+{
+  let (filter, bypass) = {
+    let pipeline = pipeline.borrow();
+    let stage = &pipeline.stages()[idx];
+
+    (stage.filter().clone(), stage.bypass())
+  };
+
+  let header = GBox::new(Orientation::Horizontal, 2);
+
+  let enable = CheckButton::new_with_label(filter.name());
+
+  enable.set_active(!bypass);
+  enable.set_tooltip_text("Uncheck to bypass this stage");
+
+  header.pack_start(&enable, true, true, 0);
+
+  let up_btn = Button::new_with_label("\u{25b2}");
+  let down_btn = Button::new_with_label("\u{25bc}");
+  let remove_btn = Button::new_with_label("\u{2715}");
+
+  up_btn.set_sensitive(idx > 0);
+  down_btn.set_sensitive(idx + 1 < nstages);
+
+  header.pack_start(&up_btn, false, false, 0);
+  header.pack_start(&down_btn, false, false, 0);
+  header.pack_start(&remove_btn, false, false, 0);
+
+  let frame = Frame::new(None);
+
+  frame.set_label_widget(Some(&header));
+
+  let param_box = GBox::new(Orientation::Vertical, 4);
+
+  param_box.set_margin_left(4);
+  param_box.set_margin_right(4);
+  param_box.set_margin_bottom(4);
+
+  param_builder::build(&param_box, filter.params(), renderer, use_icc);
+
+  frame.add(&param_box);
+
+  tool_box.pack_start(&frame, false, false, 0);
+
+  enable.connect_toggled(autoclone!(pipeline, renderer => move |enable| {
+    pipeline.borrow_mut().set_bypass(idx, !enable.get_active());
+
+    let procs = pipeline.borrow().procs();
+
+    renderer.borrow_mut().set_procs(procs);
+  }));
+
+  let tool_box = tool_box.downgrade();
+
+  up_btn.connect_clicked(autoclone!(tool_box, pipeline, renderer => move |_| {
+    pipeline.borrow_mut().move_up(idx);
+
+    update(&tool_box.upgrade().unwrap(), &pipeline, &renderer, use_icc);
+  }));
+
+  down_btn.connect_clicked(
+    autoclone!(tool_box, pipeline, renderer => move |_| {
+      pipeline.borrow_mut().move_down(idx);
+
+      update(&tool_box.upgrade().unwrap(), &pipeline, &renderer, use_icc);
+    }),
+  );
+
+  remove_btn.connect_clicked(
+    autoclone!(tool_box, pipeline, renderer => move |_| {
+      pipeline.borrow_mut().remove(idx);
+
+      update(&tool_box.upgrade().unwrap(), &pipeline, &renderer, use_icc);
+    }),
+  );
+}
@@ -1,30 +1,353 @@
+use color::ColorSpace;
 use image::{GenericImageView, Rgba, RgbaImage};
 use nalgebra::Vector4;
-use oneshot_pool::OneshotPool;
+use rng::{self, StableRng};
 use std::{
+  any::{Any, TypeId},
   cmp,
+  collections::{hash_map::DefaultHasher, HashMap},
+  hash::{Hash, Hasher},
+  panic::{self, AssertUnwindSafe},
   sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex, MutexGuard,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc::{self, Receiver, Sender},
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, Weak,
   },
+  time::Instant,
 };
-
-// TODO: worry about colorspace conversions
+use storage::{ImageStore, Pager, PixelBlock, PixelFormat, BLOCK_SIZE};
+use thread_pool::{Queue, ThreadPool};
+use tile_cache::TileCache;
+use tile_order::{CenterOut, TileOrder};
+use timing::TileTiming;
 
 pub type Quantum = f32;
 pub type Pixel = Vector4<Quantum>;
 
+// Whatever a proc gathers about its input before rendering it, such as a
+// histogram.  Procs downcast it back to their own type.
+pub type Analysis = Box<Any + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileSize {
+  // Chosen from the image size and worker count whenever either changes
+  Auto,
+  Fixed(u32, u32),
+}
+
+// A whole image, stored row by row
+pub struct PixelBuf {
+  w: u32,
+  h: u32,
+  pixels: Vec<Pixel>,
+}
+
+impl PixelBuf {
+  pub fn new(w: u32, h: u32, pixels: Vec<Pixel>) -> Self {
+    assert_eq!(pixels.len(), (w * h) as usize);
+
+    Self { w, h, pixels }
+  }
+
+  pub fn from_rgba8<I>(img: &I) -> Self
+  where
+    I: GenericImageView<Pixel = Rgba<u8>>,
+  {
+    let (w, h) = img.dimensions();
+    let mut pixels = Vec::with_capacity((w * h) as usize);
+
+    for r in 0..h {
+      for c in 0..w {
+        let px = img.get_pixel(c, r).data;
+
+        pixels.push(Vector4::new(
+          px[0] as Quantum / 255.0,
+          px[1] as Quantum / 255.0,
+          px[2] as Quantum / 255.0,
+          px[3] as Quantum / 255.0,
+        ));
+      }
+    }
+
+    Self::new(w, h, pixels)
+  }
+
+  pub fn to_rgba8(&self) -> RgbaImage {
+    let mut img = RgbaImage::new(self.w, self.h);
+
+    for (px, out) in self.pixels.iter().zip(img.pixels_mut()) {
+      out.data = [
+        quantize(px[0], 255.0) as u8,
+        quantize(px[1], 255.0) as u8,
+        quantize(px[2], 255.0) as u8,
+        quantize(px[3], 255.0) as u8,
+      ];
+    }
+
+    img
+  }
+
+  pub fn w(&self) -> u32 { self.w }
+
+  pub fn h(&self) -> u32 { self.h }
+
+  pub fn pixels(&self) -> &Vec<Pixel> { &self.pixels }
+
+  pub fn map<F>(&self, f: F) -> Self
+  where
+    F: Fn(Pixel) -> Pixel,
+  {
+    Self::new(
+      self.w,
+      self.h,
+      self.pixels.iter().map(|&px| f(px)).collect(),
+    )
+  }
+}
+
+// Scales a channel from [0, 1] to [0, max] and rounds it, clamping anything out
+// of range so it can be safely cast to an integer
+pub fn quantize(q: Quantum, max: Quantum) -> Quantum {
+  (q.max(0.0).min(1.0) * max).round()
+}
+
+// How Tile::sample treats coordinates that fall outside the input image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+  Clamp,
+  Wrap,
+  Mirror,
+  Transparent,
+  Constant(Pixel),
+}
+
+impl Edge {
+  fn resolve(&self, i: i32, n: u32) -> Option<u32> {
+    let n = n as i32;
+
+    if i >= 0 && i < n {
+      return Some(i as u32);
+    }
+
+    match self {
+      Edge::Clamp => Some(cmp::max(0, cmp::min(n - 1, i)) as u32),
+      Edge::Wrap => Some((((i % n) + n) % n) as u32),
+      Edge::Mirror => {
+        let period = n * 2;
+        let i = ((i % period) + period) % period;
+
+        Some(if i < n { i } else { period - 1 - i } as u32)
+      },
+      Edge::Transparent | Edge::Constant(_) => None,
+    }
+  }
+}
+
+// The part of a tile's input it reads from, copied out of the store and
+// encoded while the proc runs
+struct Window {
+  x: u32,
+  y: u32,
+  w: u32,
+  h: u32,
+  pixels: Vec<Pixel>,
+}
+
+// How many blocks each tile keeps on hand for reads outside its window
+const CACHED_BLOCKS: usize = 8;
+
 pub struct Tile {
   x: u32,
   y: u32,
   w: u32,
   h: u32,
-  in_stride: u32,
-  in_buf: Arc<Vec<Pixel>>,
-  out_buf: Mutex<Vec<Pixel>>,
+  scale: u32,
+  pass: u32,
+  halo: Option<u32>,
+  // The color space the proc works in, which reads are encoded to
+  space: ColorSpace,
+  input: Arc<ImageStore>,
+  // The proc's extra inputs, each the same size as the input
+  extra: Arc<Vec<Option<Arc<ImageStore>>>>,
+  // Where the tile's pixels go once it's done.  Analysis tiles have nowhere.
+  output: Option<Arc<ImageStore>>,
+  // Only set on the copy of the tile made for the proc by load()
+  window: Option<Window>,
+  // Blocks recently read outside the window, as (source, block, pixels),
+  // where source 0 is the input and the rest are the extra inputs
+  cache: Mutex<Vec<(usize, usize, Arc<PixelBlock>)>>,
+  out_buf: Arc<Mutex<Vec<Pixel>>>,
 }
 
 impl Tile {
+  fn new(
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    scale: u32,
+    pass: u32,
+    halo: Option<u32>,
+    space: ColorSpace,
+    input: Arc<ImageStore>,
+    extra: Arc<Vec<Option<Arc<ImageStore>>>>,
+    output: Option<Arc<ImageStore>>,
+  ) -> Self {
+    Self {
+      x,
+      y,
+      w,
+      h,
+      scale,
+      pass,
+      halo,
+      space,
+      input,
+      extra,
+      output,
+      window: None,
+      cache: Mutex::new(Vec::new()),
+      // This is only filled in once the tile is about to be processed, so
+      // queued tiles don't take up any room
+      out_buf: Arc::new(Mutex::new(Vec::new())),
+    }
+  }
+
+  // Makes a copy of the tile for the proc to work on, with the part of the
+  // input it said it would read close at hand.  The copy writes to the same
+  // output as the original.
+  fn load(&self) -> Self {
+    let window = self.halo.map(|_| {
+      let (x, y, w, h) = self.footprint();
+      let mut pixels = self.input.read_rect(x, y, w, h);
+
+      if self.space != ColorSpace::Linear {
+        for px in pixels.iter_mut() {
+          *px = self.space.encode(*px);
+        }
+      }
+
+      Window { x, y, w, h, pixels }
+    });
+
+    if self.output.is_some() {
+      *self.out_buf() = (0..self.h * self.w)
+        .map(|_| Pixel::new(0.0, 0.0, 0.0, 0.0))
+        .collect();
+    }
+
+    Self {
+      x: self.x,
+      y: self.y,
+      w: self.w,
+      h: self.h,
+      scale: self.scale,
+      pass: self.pass,
+      halo: self.halo,
+      space: self.space,
+      input: self.input.clone(),
+      extra: self.extra.clone(),
+      output: self.output.clone(),
+      window,
+      cache: Mutex::new(Vec::new()),
+      out_buf: self.out_buf.clone(),
+    }
+  }
+
+  // Sets up a tile to be rendered away from the renderer, such as in a worker
+  // process.  Only the inputs' pixels under the tile's footprint need to be
+  // filled in, already in the proc's color space.
+  pub fn detached(
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    scale: u32,
+    pass: u32,
+    halo: Option<u32>,
+    input: Arc<ImageStore>,
+    extra: Vec<Option<Arc<ImageStore>>>,
+  ) -> Self {
+    let ret = Self::new(
+      x,
+      y,
+      w,
+      h,
+      scale,
+      pass,
+      halo,
+      ColorSpace::Linear,
+      input,
+      Arc::new(extra),
+      None,
+    )
+    .load();
+
+    *ret.out_buf() =
+      (0..h * w).map(|_| Pixel::new(0.0, 0.0, 0.0, 0.0)).collect();
+
+    ret
+  }
+
+  // Reads the tile back out of its output, for tiles an earlier render
+  // already finished
+  fn reload(&self) {
+    if let Some(ref output) = self.output {
+      *self.out_buf() = output.read_rect(self.x, self.y, self.w, self.h);
+    }
+  }
+
+  // Writes the finished tile into the image the next phase reads from
+  fn store(&self) {
+    if let Some(ref output) = self.output {
+      output.write_rect(self.x, self.y, self.w, self.h, &self.out_buf());
+    }
+  }
+
+  // Reads a pixel from the input (source 0) or an extra input, encoded
+  fn read(&self, source: usize, store: &ImageStore, x: u32, y: u32) -> Pixel {
+    if let Some(ref win) = self.window {
+      if source == 0
+        && x >= win.x
+        && y >= win.y
+        && x < win.x + win.w
+        && y < win.y + win.h
+      {
+        return win.pixels[((y - win.y) * win.w + x - win.x) as usize];
+      }
+    }
+
+    let (block, offset) = store.locate(x, y);
+
+    let px = {
+      let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+
+      match cache
+        .iter()
+        .position(|&(s, b, _)| s == source && b == block)
+      {
+        Some(i) => cache[i].2.get(offset),
+        None => {
+          let pixels = store.block(block);
+          let px = pixels.get(offset);
+
+          if cache.len() >= CACHED_BLOCKS {
+            cache.remove(0);
+          }
+
+          cache.push((source, block, pixels));
+
+          px
+        },
+      }
+    };
+
+    match self.space {
+      ColorSpace::Linear => px,
+      _ => self.space.encode(px),
+    }
+  }
+
   pub fn x(&self) -> u32 { self.x }
 
   pub fn y(&self) -> u32 { self.y }
@@ -33,7 +356,81 @@ impl Tile {
 
   pub fn h(&self) -> u32 { self.h }
 
+  // For preview tiles, the width and height of the block of the full-size
+  // image that each pixel stands in for.  This is 1 for a full render.
+  pub fn scale(&self) -> u32 { self.scale }
+
+  // Which of the proc's passes this tile is from, counting from 0
+  pub fn pass(&self) -> u32 { self.pass }
+
+  // The region of the full-size image this tile covers, as (x, y, w, h).  This
+  // is the same as the tile's own coordinates unless it's a preview tile.
+  pub fn bounds(&self) -> (u32, u32, u32, u32) {
+    (
+      self.x * self.scale,
+      self.y * self.scale,
+      self.w * self.scale,
+      self.h * self.scale,
+    )
+  }
+
+  // The size of the image this tile reads from, which may not be the size of
+  // the image it's part of if the proc changes the image size
+  pub fn in_w(&self) -> u32 { self.input.w() }
+
+  pub fn in_h(&self) -> u32 { self.input.h() }
+
   pub fn get_input(&self, x: u32, y: u32) -> Pixel {
+    if x >= self.w || self.x + x >= self.in_w() {
+      panic!("x value {} out-of-bounds", x);
+    }
+
+    if y >= self.h || self.y + y >= self.in_h() {
+      panic!("y value {} out-of-bounds", y);
+    }
+
+    self.read(0, &self.input, self.x + x, self.y + y)
+  }
+
+  pub fn global_input(&self, x: u32, y: u32) -> Pixel {
+    if x >= self.in_w() {
+      panic!("x value {} out-of-bounds", x);
+    }
+
+    if y >= self.in_h() {
+      panic!("y value {} out-of-bounds", y);
+    }
+
+    self.read(0, &self.input, x, y)
+  }
+
+  // Reads a pixel relative to the tile's origin.  Unlike get_input, this can
+  // read anywhere in (or outside of) the input image.
+  pub fn sample(&self, x: i32, y: i32, edge: Edge) -> Pixel {
+    self.global_sample(self.x as i32 + x, self.y as i32 + y, edge)
+  }
+
+  pub fn global_sample(&self, x: i32, y: i32, edge: Edge) -> Pixel {
+    match (edge.resolve(x, self.in_w()), edge.resolve(y, self.in_h())) {
+      (Some(x), Some(y)) => self.read(0, &self.input, x, y),
+      _ => match edge {
+        Edge::Constant(px) => px,
+        _ => Pixel::new(0.0, 0.0, 0.0, 0.0),
+      },
+    }
+  }
+
+  // Whether the proc's ith extra input (see RenderProc::inputs) has an image
+  pub fn has_extra(&self, i: usize) -> bool {
+    match self.extra.get(i) {
+      Some(Some(_)) => true,
+      _ => false,
+    }
+  }
+
+  // Reads the ith extra input, stretched to the size of the input image.
+  // Inputs with no image read as transparent.
+  pub fn get_extra(&self, i: usize, x: u32, y: u32) -> Pixel {
     if x >= self.w {
       panic!("x value {} out-of-bounds", x);
     }
@@ -42,19 +439,67 @@ impl Tile {
       panic!("y value {} out-of-bounds", y);
     }
 
-    self.in_buf[((self.y + y) * self.in_stride + self.x + x) as usize]
+    self.global_extra(i, self.x + x, self.y + y)
   }
 
-  pub fn global_input(&self, x: u32, y: u32) -> Pixel {
-    if x >= self.in_stride {
+  pub fn global_extra(&self, i: usize, x: u32, y: u32) -> Pixel {
+    if x >= self.in_w() {
       panic!("x value {} out-of-bounds", x);
     }
 
-    self.in_buf[(y * self.in_stride + x) as usize]
+    if y >= self.in_h() {
+      panic!("y value {} out-of-bounds", y);
+    }
+
+    match self.extra.get(i) {
+      Some(Some(store)) => self.read(i + 1, store, x, y),
+      _ => Pixel::new(0.0, 0.0, 0.0, 0.0),
+    }
   }
 
+  // The region of the input this tile's proc declared it would read from, as
+  // (x, y, w, h)
+  pub fn footprint(&self) -> (u32, u32, u32, u32) {
+    match self.halo {
+      Some(halo) => {
+        let x = self.x.saturating_sub(halo);
+        let y = self.y.saturating_sub(halo);
+
+        (
+          x,
+          y,
+          cmp::min(self.in_w(), self.x + self.w + halo) - x,
+          cmp::min(self.in_h(), self.y + self.h + halo) - y,
+        )
+      },
+      None => (0, 0, self.in_w(), self.in_h()),
+    }
+  }
+
+  // NB: a proc that panics while holding this poisons it, but the tile is
+  //     cleared afterwards, so the contents are still safe to read
   pub fn out_buf(&self) -> MutexGuard<Vec<Pixel>> {
-    self.out_buf.lock().unwrap()
+    self.out_buf.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  // A random number generator seeded from seed and which tile this is.  What
+  // it gives depends on how the image is tiled, so anything that has to look
+  // the same whatever the tile size should use noise instead.
+  pub fn rng(&self, seed: u64) -> StableRng {
+    StableRng::new(rng::hash(&[
+      seed,
+      self.x as u64,
+      self.y as u64,
+      self.scale as u64,
+      self.pass as u64,
+    ]))
+  }
+
+  // A random value in [0, 1) for a pixel relative to the tile's origin, which
+  // only depends on seed and where the pixel is in the image.  Preview tiles
+  // use the position of the first full-size pixel each one stands in for.
+  pub fn noise(&self, seed: u64, x: u32, y: u32) -> Quantum {
+    rng::noise(seed, (self.x + x) * self.scale, (self.y + y) * self.scale)
   }
 
   pub fn cx(&self) -> u32 { self.x + self.w / 2 }
@@ -62,184 +507,1733 @@ impl Tile {
   pub fn cy(&self) -> u32 { self.y + self.h / 2 }
 }
 
-pub struct TaggedTile<T>
-where
-  T: Send + Sync,
-{
-  tile: Tile,
-  tag: T,
-}
+pub struct TaggedTile<T>
+where
+  T: Send + Sync,
+{
+  tile: Tile,
+  tag: T,
+  generation: usize,
+  writes_output: bool,
+}
+
+impl<T> TaggedTile<T>
+where
+  T: Send + Sync,
+{
+  pub fn tile(&self) -> &Tile { &self.tile }
+
+  pub fn tag(&self) -> &T { &self.tag }
+
+  // Which render this tile is from.  Each call to begin a render gets a new
+  // generation, so anything older than the last RenderCallback::before_begin
+  // is from a render that was aborted.
+  pub fn generation(&self) -> usize { self.generation }
+
+  // Whether this tile is part of the finished image (or of its preview), as
+  // opposed to an earlier stage or pass
+  pub fn writes_output(&self) -> bool { self.writes_output }
+}
+
+// How many tiles across and down it takes to cover a w-by-h image
+fn tile_counts(w: u32, h: u32, tile_w: u32, tile_h: u32) -> (u32, u32) {
+  ((w + tile_w - 1) / tile_w, (h + tile_h - 1) / tile_h)
+}
+
+// The size of a w-by-h image after downsampling by a factor of scale
+fn scaled((w, h): (u32, u32), scale: u32) -> (u32, u32) {
+  ((w + scale - 1) / scale, (h + scale - 1) / scale)
+}
+
+// A rectangle, as (x, y, w, h)
+type Rect = (u32, u32, u32, u32);
+
+// Where a full-size rectangle lands once downsampled by a factor of scale,
+// grown out to whole pixels
+fn scaled_rect((x, y, w, h): Rect, scale: u32) -> Rect {
+  let (x1, y1) = scaled((x + w, y + h), scale);
+
+  (x / scale, y / scale, x1 - x / scale, y1 - y / scale)
+}
+
+// Marks which tiles of a w-by-h image overlap any of rects, in the order
+// gen_tiles makes them
+fn tiles_touching(
+  w: u32,
+  h: u32,
+  (tile_w, tile_h): (u32, u32),
+  rects: &[Rect],
+) -> Vec<bool> {
+  let (tiles_x, tiles_y) = tile_counts(w, h, tile_w, tile_h);
+  let mut touching = vec![false; (tiles_x * tiles_y) as usize];
+
+  for &(x, y, rect_w, rect_h) in rects {
+    let x1 = cmp::min(w, x + rect_w);
+    let y1 = cmp::min(h, y + rect_h);
+
+    if x >= x1 || y >= y1 {
+      continue;
+    }
+
+    for r in y / tile_h..(y1 + tile_h - 1) / tile_h {
+      for c in x / tile_w..(x1 + tile_w - 1) / tile_w {
+        touching[(r * tiles_x + c) as usize] = true;
+      }
+    }
+  }
+
+  touching
+}
+
+// The areas covered by the marked tiles of a w-by-h image, each grown by halo
+// on every side
+fn tile_rects(
+  w: u32,
+  h: u32,
+  (tile_w, tile_h): (u32, u32),
+  tiles: &[bool],
+  halo: u32,
+) -> Vec<Rect> {
+  let (tiles_x, _) = tile_counts(w, h, tile_w, tile_h);
+
+  tiles
+    .iter()
+    .enumerate()
+    .filter(|&(_, &t)| t)
+    .map(|(i, _)| {
+      let x = i as u32 % tiles_x * tile_w;
+      let y = i as u32 / tiles_x * tile_h;
+      let x0 = x.saturating_sub(halo);
+      let y0 = y.saturating_sub(halo);
+
+      (
+        x0,
+        y0,
+        cmp::min(w, (x + tile_w).saturating_add(halo)) - x0,
+        cmp::min(h, (y + tile_h).saturating_add(halo)) - y0,
+      )
+    })
+    .collect()
+}
+
+// Splits a w-by-h output into tiles, all reading from input (and extra), which
+// write into output
+fn gen_tiles<T>(
+  w: u32,
+  h: u32,
+  (tile_w, tile_h): (u32, u32),
+  scale: u32,
+  pass: u32,
+  halo: Option<u32>,
+  space: ColorSpace,
+  input: &Arc<ImageStore>,
+  extra: &Arc<Vec<Option<Arc<ImageStore>>>>,
+  output: Option<&Arc<ImageStore>>,
+  generation: usize,
+  writes_output: bool,
+) -> Vec<Arc<TaggedTile<T>>>
+where
+  T: Default + Send + Sync,
+{
+  let (tiles_x, tiles_y) = tile_counts(w, h, tile_w, tile_h);
+
+  (0..tiles_y)
+    .flat_map(|r| {
+      let y = r * tile_h;
+      let th = cmp::min(tile_h, h - y);
+
+      (0..tiles_x).map(move |c| {
+        let x = c * tile_w;
+        let tw = cmp::min(tile_w, w - x);
+
+        Arc::new(TaggedTile {
+          tile: Tile::new(
+            x,
+            y,
+            tw,
+            th,
+            scale,
+            pass,
+            halo,
+            space,
+            input.clone(),
+            extra.clone(),
+            output.cloned(),
+          ),
+          tag: Default::default(),
+          generation,
+          writes_output,
+        })
+      })
+    })
+    .collect()
+}
+
+fn cmp_tiles(
+  order: &TileOrder,
+  a: &Tile,
+  b: &Tile,
+  w: u32,
+  h: u32,
+) -> cmp::Ordering {
+  let (ax, ay, _, _) = a.bounds();
+  let (bx, by, _, _) = b.bounds();
+
+  order
+    .priority(a, w, h)
+    .partial_cmp(&order.priority(b, w, h))
+    .unwrap_or(cmp::Ordering::Equal)
+    .then_with(|| ay.cmp(&by).then_with(|| ax.cmp(&bx)))
+}
+
+// Picks a square power-of-two tile size that gives each worker a good handful
+// of tiles to balance between, without making tiles so small that per-tile
+// overhead (and halo padding) starts to dominate
+fn auto_tile_size(w: u32, h: u32, njobs: usize) -> (u32, u32) {
+  const MIN_SIZE: u32 = 32;
+  const MAX_SIZE: u32 = 256;
+  const TILES_PER_JOB: u64 = 16;
+
+  let target =
+    (w as u64 * h as u64) / (cmp::max(njobs, 1) as u64 * TILES_PER_JOB);
+
+  let mut size = MIN_SIZE;
+
+  while size < MAX_SIZE && (size as u64 * 2).pow(2) <= target {
+    size *= 2;
+  }
+
+  (size, size)
+}
+
+// Box-filters an image down by a factor of scale
+fn downsample(
+  buf: &Vec<Pixel>,
+  w: u32,
+  h: u32,
+  scale: u32,
+) -> (Vec<Pixel>, u32, u32) {
+  let sw = (w + scale - 1) / scale;
+  let sh = (h + scale - 1) / scale;
+
+  let mut ret = Vec::with_capacity((sw * sh) as usize);
+
+  for sy in 0..sh {
+    let y0 = sy * scale;
+    let y1 = cmp::min(h, y0 + scale);
+
+    for sx in 0..sw {
+      let x0 = sx * scale;
+      let x1 = cmp::min(w, x0 + scale);
+
+      let mut sum = Pixel::new(0.0, 0.0, 0.0, 0.0);
+
+      for r in y0..y1 {
+        for c in x0..x1 {
+          sum += buf[(r * w + c) as usize];
+        }
+      }
+
+      ret.push(sum / ((y1 - y0) * (x1 - x0)) as Quantum);
+    }
+  }
+
+  (ret, sw, sh)
+}
+
+// Downsamples the input for the preview pass, a strip at a time so the whole
+// image never has to be in memory at once
+fn downsample_store(src: &ImageStore, scale: u32) -> ImageStore {
+  let (w, h) = scaled((src.w(), src.h()), scale);
+  let ret = ImageStore::new(src.pager().clone(), w, h);
+
+  let strip_h = cmp::max(1, BLOCK_SIZE / scale) * scale;
+
+  for y in (0..src.h()).step_by(strip_h as usize) {
+    let rows = cmp::min(strip_h, src.h() - y);
+    let strip = src.read_rect(0, y, src.w(), rows);
+    let (buf, _, sh) = downsample(&strip, src.w(), rows, scale);
+
+    ret.write_rect(0, y / scale, w, sh, &buf);
+  }
+
+  ret
+}
+
+// Stretches an image to w by h for use as an extra input.  Big reductions are
+// box-filtered first so they don't alias, then the rest is bilinear.
+fn resample(img: &PixelBuf, w: u32, h: u32, pager: &Arc<Pager>) -> ImageStore {
+  let ret = ImageStore::new(pager.clone(), w, h);
+
+  if img.w == w && img.h == h {
+    ret.write_rect(0, 0, w, h, &img.pixels);
+    return ret;
+  }
+
+  let scale = cmp::max(1, cmp::min(img.w / w, img.h / h));
+
+  let (buf, src_w, src_h) = if scale > 1 {
+    downsample(&img.pixels, img.w, img.h, scale)
+  } else {
+    (img.pixels.clone(), img.w, img.h)
+  };
+
+  // Finds the two source pixels either side of a destination pixel's center,
+  // and how far along from the first one it lies
+  let span = |i: u32, dst: u32, src: u32| {
+    let pos =
+      ((i as Quantum + 0.5) * src as Quantum / dst as Quantum - 0.5).max(0.0);
+    let a = cmp::min(pos as u32, src - 1);
+
+    (a, cmp::min(a + 1, src - 1), pos - a as Quantum)
+  };
+
+  let px = |x: u32, y: u32| buf[(y * src_w + x) as usize];
+
+  // The output can be much bigger than the image, so it's written out in
+  // strips
+  for y in (0..h).step_by(BLOCK_SIZE as usize) {
+    let rows = cmp::min(BLOCK_SIZE, h - y);
+    let mut strip = Vec::with_capacity((w * rows) as usize);
+
+    for y in y..y + rows {
+      let (y0, y1, ty) = span(y, h, src_h);
+
+      for x in 0..w {
+        let (x0, x1, tx) = span(x, w, src_w);
+
+        let top = px(x0, y0) * (1.0 - tx) + px(x1, y0) * tx;
+        let bottom = px(x0, y1) * (1.0 - tx) + px(x1, y1) * tx;
+
+        strip.push(top * (1.0 - ty) + bottom * ty);
+      }
+    }
+
+    ret.write_rect(0, y, w, rows, &strip);
+  }
+
+  ret
+}
+
+pub fn panic_msg(payload: Box<Any + Send>) -> String {
+  match payload.downcast::<String>() {
+    Ok(s) => *s,
+    Err(payload) => match payload.downcast::<&str>() {
+      Ok(s) => s.to_string(),
+      Err(_) => "unknown error".to_string(),
+    },
+  }
+}
+
+pub struct CancelTok {
+  cancelled: Arc<AtomicBool>,
+  progress: Option<Box<Fn(f32) + Send + Sync>>,
+}
+
+impl CancelTok {
+  fn new() -> Self {
+    Self {
+      cancelled: Arc::new(AtomicBool::new(false)),
+      progress: None,
+    }
+  }
+
+  // For tiles rendered away from the renderer (see Tile::detached)
+  pub fn detached(
+    cancelled: Arc<AtomicBool>,
+    progress: Box<Fn(f32) + Send + Sync>,
+  ) -> Self {
+    Self {
+      cancelled,
+      progress: Some(progress),
+    }
+  }
+
+  pub fn cancelled(&self) -> bool { self.cancelled.load(Ordering::SeqCst) }
+
+  // Reports how much of the current tile is done, from 0 to 1. This is only
+  // worth calling from procs where a single tile can take a while, and does
+  // nothing for preview tiles.
+  pub fn set_progress(&self, frac: f32) {
+    if let Some(ref progress) = self.progress {
+      progress(frac.max(0.0).min(1.0));
+    }
+  }
+}
+
+pub trait RenderProc: 'static {
+  // The size of the image this proc produces from a w-by-h input.  This is
+  // always queried at full resolution; preview renders scale the result down.
+  fn output_size(&self, w: u32, h: u32) -> (u32, u32) { (w, h) }
+
+  // How many times process_tile is run over the whole image.  Each pass reads
+  // the complete output of the one before it, and sees its number through
+  // Tile::pass.  Only the last pass produces output_size; the others keep the
+  // size of the input.  This is queried alongside output_size.
+  fn passes(&self) -> u32 { 1 }
+
+  // w and h are the size of the input, which may be a preview.  This is only
+  // called before the first pass (or the analysis, if there is one).
+  fn begin(&self, _w: u32, _h: u32) {}
+
+  // Procs that need statistics about the whole input before they can render
+  // any of it return true here.  analyze_tile is then run over every tile of
+  // the input in parallel, and the results are folded together in tile order
+  // with combine and handed to analyzed before the first pass.  This is
+  // queried alongside output_size.
+  fn needs_analysis(&self) -> bool { false }
+
+  // Gathers statistics about the tile's input.  This should only depend on
+  // the pixels, since the result is reused until the input changes.
+  fn analyze_tile(&self, _tile: &Tile) -> Analysis { Box::new(()) }
+
+  fn combine(&self, a: Analysis, _b: Analysis) -> Analysis { a }
+
+  fn analyzed(&self, _result: &(Any + Send + Sync)) {}
+
+  // How many pixels past the edges of a tile process_tile will read, or None
+  // if it may read from anywhere in the input (as procs that move pixels
+  // around should).  This is queried after begin(), and again for each pass.
+  // When only part of the image is rendered, it's also queried alongside
+  // output_size to work out which tiles the stages before need, so it
+  // shouldn't depend on anything begin() sets up.
+  fn halo(&self) -> Option<u32> { Some(0) }
+
+  // Extra images process_tile reads from, such as a mask or a layer to blend.
+  // Each is stretched to the size of the input and read with Tile::get_extra.
+  // They come with a key that has to change whenever the image does, like
+  // ImageParam::key, since the stretched copies are reused while it stays the
+  // same.  This is queried after begin().
+  fn inputs(&self) -> Vec<Option<(u64, Arc<PixelBuf>)>> { Vec::new() }
+
+  // Which values process_tile reads and writes.  Procs are handed linear light
+  // unless they ask otherwise, and their output is converted back afterwards.
+  fn color_space(&self) -> ColorSpace { ColorSpace::Linear }
+
+  // Sums up everything the proc's output depends on besides its input, such
+  // as its parameters, so finished tiles can be reused when the same settings
+  // come back.  Procs that return None are never cached, and neither are the
+  // stages after them.
+  fn cache_key(&self) -> Option<u64> { None }
+
+  // Which kind of proc this is, so procs from different filters that happen to
+  // have the same cache_key don't share tiles.  Procs that only wrap another
+  // one should pass its kind on.
+  fn kind(&self) -> TypeId { TypeId::of::<Self>() }
+
+  fn process_tile(&self, tile: &Tile, cancel_tok: &CancelTok);
+}
+
+pub trait RenderCallback {
+  type Tag;
+
+  // w and h are the size of the final output
+  fn before_begin(&self, _generation: usize, _ntiles: usize, _w: u32, _h: u32) {
+  }
+
+  // Only called for renders that weren't aborted and didn't fail
+  fn after_end(&self) {}
+
+  // Called instead of after_end if the render couldn't carry on, e.g. because
+  // image data that was paged out couldn't be read back
+  fn handle_error(&self, _generation: usize, _msg: &str) {}
+
+  // Called when a render is aborted.  Tiles the workers were in the middle of
+  // may still be reported afterwards, so check their generation.
+  fn abort(&self) {}
+
+  fn before_tile(&self, tile: Arc<TaggedTile<Self::Tag>>, wid: usize)
+  where
+    Self::Tag: Send + Sync,
+  {
+  }
+
+  fn handle_tile(&self, tile: Arc<TaggedTile<Self::Tag>>, wid: usize)
+  where
+    Self::Tag: Send + Sync;
+
+  // Called instead of handle_tile for tiles from the low-resolution preview
+  // pass, if one is enabled.  Their coordinates are divided by tile.scale().
+  fn handle_preview(&self, _tile: Arc<TaggedTile<Self::Tag>>, _wid: usize)
+  where
+    Self::Tag: Send + Sync,
+  {
+  }
+
+  // Called instead of handle_tile or handle_preview if the proc panicked while
+  // processing a tile.  The tile's output is left blank.
+  fn handle_panic(
+    &self,
+    _tile: Arc<TaggedTile<Self::Tag>>,
+    _wid: usize,
+    _msg: &str,
+  ) where
+    Self::Tag: Send + Sync,
+  {
+  }
+
+  // Called whenever a proc reports progress through CancelTok::set_progress,
+  // between before_tile and handle_tile
+  fn handle_progress(
+    &self,
+    _tile: Arc<TaggedTile<Self::Tag>>,
+    _wid: usize,
+    _frac: f32,
+  ) where
+    Self::Tag: Send + Sync,
+  {
+  }
+
+  // Called after every call to RenderProc::process_tile, before the tile is
+  // handed to any of the above, even if the render was cancelled
+  fn handle_timing(&self, _timing: TileTiming) {}
+}
+
+struct TileTask<T>
+where
+  T: Send + Sync,
+{
+  proc: Arc<RenderProc + Send + Sync>,
+  tile: Arc<TaggedTile<T>>,
+  // Which proc in the pipeline this is, and which of its passes
+  stage: usize,
+  pass: u32,
+  preview: bool,
+  space: ColorSpace,
+  // The full-size dimensions of the stage's output, for ordering tiles
+  w: u32,
+  h: u32,
+  // Set if the proc panicked before it got to this tile
+  error: Option<String>,
+  // For analysis tasks, which slot of the results to fill in
+  analysis: Option<(usize, AnalysisResults)>,
+  cache: Option<StageKey>,
+  // Set for output tiles an earlier render of the same settings finished,
+  // which are read back rather than rendered again
+  kept: bool,
+}
+
+type AnalysisResults = Arc<Mutex<Vec<Option<Result<Analysis, String>>>>>;
+
+// What a proc's analysis found, kept until the stage's input changes
+struct CachedAnalysis {
+  proc: Arc<RenderProc + Send + Sync>,
+  // The content id of the input it was found in
+  input: u64,
+  result: Arc<Analysis>,
+}
+
+// Keyed by stage and scale, since the preview is analyzed separately
+type AnalysisCache = Arc<Mutex<HashMap<(usize, u32), CachedAnalysis>>>;
+
+// Extra inputs already stretched to fit, keyed by their key and the size they
+// were stretched to.  The originals are only held weakly, so their entries go
+// once they're replaced.
+type ResampleCache =
+  Arc<Mutex<HashMap<(u64, u32, u32), (Weak<PixelBuf>, Arc<ImageStore>)>>>;
+
+// Looks up img stretched to w by h, stretching it if it isn't there yet
+fn resample_cached(
+  cache: &ResampleCache,
+  key: u64,
+  img: &Arc<PixelBuf>,
+  (w, h): (u32, u32),
+  pager: &Arc<Pager>,
+) -> Arc<ImageStore> {
+  let mut cache = cache.lock().unwrap();
+
+  cache.retain(|_, e| e.0.upgrade().is_some());
+
+  cache
+    .entry((key, w, h))
+    .or_insert_with(|| {
+      (Arc::downgrade(img), Arc::new(resample(img, w, h, pager)))
+    })
+    .1
+    .clone()
+}
+
+// A stage's analysis, between its analysis phase and its first pass
+enum Analyzed {
+  Cached(Arc<Analysis>),
+  Gathering(u64, AnalysisResults),
+}
+
+// Folds the results of an analysis phase together in tile order
+fn reduce_analysis(
+  proc: &Arc<RenderProc + Send + Sync>,
+  results: &AnalysisResults,
+) -> Result<Analysis, String> {
+  let results = results.lock().unwrap().drain(..).collect::<Vec<_>>();
+
+  panic::catch_unwind(AssertUnwindSafe(|| {
+    let mut acc: Option<Analysis> = None;
+
+    for result in results {
+      let result = result.unwrap_or_else(|| Err("tile not analyzed".into()))?;
+
+      acc = Some(match acc {
+        Some(a) => proc.combine(a, result),
+        None => result,
+      });
+    }
+
+    acc.ok_or_else(|| "nothing to analyze".to_string())
+  }))
+  .map_err(panic_msg)
+  .and_then(|r| r)
+}
+
+// What a stage's tiles are cached under, which covers the input and every proc
+// up to and including the stage's own
+#[derive(Clone)]
+struct StageKey {
+  // Identifies the input image
+  base: u64,
+  procs: Arc<Vec<Arc<RenderProc + Send + Sync>>>,
+  key: u64,
+}
+
+impl StageKey {
+  // Procs are told apart by kind as well as by key, since two different
+  // filters can have the same settings.  Two procs of the same kind with the
+  // same key are interchangeable, so switching back to a filter (which makes
+  // a new proc) still finds its tiles.
+  fn chain(base: u64, procs: &[Arc<RenderProc + Send + Sync>]) -> Option<u64> {
+    procs.iter().fold(Some(base), |h, p| {
+      let mut kind = DefaultHasher::new();
+
+      p.kind().hash(&mut kind);
+
+      Some(rng::hash(&[h?, kind.finish(), p.cache_key()?]))
+    })
+  }
+
+  fn tile(&self, tile: &Tile) -> u64 {
+    rng::hash(&[
+      self.key,
+      tile.scale as u64,
+      tile.pass as u64,
+      tile.x as u64,
+      tile.y as u64,
+      tile.w as u64,
+      tile.h as u64,
+    ])
+  }
+
+  // Whether the procs' settings are still the ones the key was made with.  A
+  // setting can change while a tile is being rendered, which would otherwise
+  // file the tile under the wrong key.
+  fn current(&self) -> bool {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+      Self::chain(self.base, &self.procs) == Some(self.key)
+    }))
+    .unwrap_or(false)
+  }
+}
+
+// One pass of one stage of a pipeline, applied at a single resolution
+struct Phase {
+  proc: Arc<RenderProc + Send + Sync>,
+  stage: usize,
+  // Set for the phase that analyzes the stage's input before its first pass
+  analyze: bool,
+  pass: u32,
+  // The full-size dimensions of the stage's input and output
+  in_size: (u32, u32),
+  out_size: (u32, u32),
+  // If this is None, the phase reads the output of the one before it
+  input: Option<Arc<ImageStore>>,
+  // Greater than 1 for the preview pass
+  scale: u32,
+  // Set if the proc panicked while reporting its output size
+  error: Option<String>,
+  cache: Option<StageKey>,
+  // Set for the last pass of the last stage, which writes the output
+  writes_output: bool,
+  // Which of the phase's tiles to render, in the order gen_tiles makes them,
+  // or None for all of them
+  needed: Option<Vec<bool>>,
+  // Only set for the last full-size phase, which carries on where an earlier
+  // render left off
+  kept: Option<Kept>,
+}
+
+// The output of a render, and which of its tiles were finished, for a render
+// of the same settings to pick up from
+#[derive(Clone)]
+struct Kept {
+  output: Arc<ImageStore>,
+  tiles: Arc<Vec<bool>>,
+}
+
+// Works out which tiles each of phases (which all share one scale) needs for
+// the last of them to cover rects, going backwards from the last.  A stage
+// that reads its whole input, or changes the image size, needs all of it.
+fn limit_phases(
+  phases: &mut [Phase],
+  tile_dims: (u32, u32),
+  halos: &[Option<u32>],
+  rects: Option<Vec<Rect>>,
+) {
+  let mut rects = rects;
+
+  for phase in phases.iter_mut().rev() {
+    let (w, h) = scaled(phase.out_size, phase.scale);
+
+    // Analyzing takes the whole input, unless the stage has nothing to render
+    if phase.analyze {
+      phase.needed = match rects {
+        Some(ref r) if r.is_empty() => Some(tiles_touching(w, h, tile_dims, r)),
+        _ => None,
+      };
+
+      if phase.needed.is_none() {
+        rects = None;
+      }
+
+      continue;
+    }
+
+    phase.needed = rects.map(|r| tiles_touching(w, h, tile_dims, &r));
+
+    rects = match (&phase.needed, halos[phase.stage]) {
+      (Some(needed), Some(halo)) if phase.in_size == phase.out_size => {
+        Some(tile_rects(w, h, tile_dims, needed, halo))
+      },
+      _ => None,
+    };
+  }
+}
+
+// Produces the tasks for each phase in turn, setting up each one's input.  The
+// image the last phase writes to is the output.
+fn run_phases<T>(
+  phases: Vec<Phase>,
+  tile_dims: (u32, u32),
+  generation: usize,
+  cache: AnalysisCache,
+  resamples: ResampleCache,
+  output: Arc<Mutex<Option<Arc<ImageStore>>>>,
+  intact: Arc<AtomicBool>,
+) -> impl FnMut() -> Option<Vec<TileTask<T>>>
+where
+  T: Default + Send + Sync,
+{
+  let mut phases = phases.into_iter();
+  // What the last phase wrote to.  Analysis phases don't write anything, so
+  // the first pass after one reads the same input.
+  let mut last: Option<Arc<ImageStore>> = None;
+  let mut analyzed: Option<Analyzed> = None;
+  // A stage whose proc panicked while setting up, so its later passes fail too
+  let mut failed: Option<(usize, String)> = None;
+  // The key of the last phase, if it rendered all of its tiles, and the
+  // content id that goes with it
+  let mut described: Option<(StageKey, u64)> = None;
+
+  move || {
+    // The last phase is done, so if none of its tiles failed, its output is
+    // what its key says.  Later stages' analyses can then be kept for as long
+    // as the same settings come back.
+    let whole = intact.swap(true, Ordering::SeqCst);
+
+    if let (Some(ref l), Some((key, id))) = (&last, described.take()) {
+      if whole && key.current() {
+        l.set_content_id(id);
+      }
+    }
+
+    let Phase {
+      proc,
+      stage,
+      analyze,
+      pass,
+      in_size,
+      out_size,
+      input,
+      scale,
+      error,
+      cache: stage_key,
+      writes_output,
+      needed,
+      kept,
+    } = phases.next()?;
+
+    let (in_w, in_h) = scaled(in_size, scale);
+    let (out_w, out_h) = scaled(out_size, scale);
+
+    let input = match input {
+      Some(i) => i,
+      None => last.clone().expect("phase has no input"),
+    };
+
+    let mut error = match failed {
+      Some((s, ref e)) if s == stage => Some(e.clone()),
+      _ => error,
+    };
+
+    let analysis = match analyzed.take() {
+      Some(Analyzed::Cached(r)) => Some(r),
+      Some(Analyzed::Gathering(input, results)) => {
+        match reduce_analysis(&proc, &results) {
+          Ok(r) => {
+            let r = Arc::new(r);
+
+            cache.lock().unwrap().insert(
+              (stage, scale),
+              CachedAnalysis {
+                proc: proc.clone(),
+                input,
+                result: r.clone(),
+              },
+            );
+
+            Some(r)
+          },
+          Err(e) => {
+            error = error.or(Some(e));
+            None
+          },
+        }
+      },
+      None => None,
+    };
+
+    // If this fails, the tiles are still queued so each one can be reported
+    let begun = match error {
+      Some(e) => Err(e),
+      None => panic::catch_unwind(AssertUnwindSafe(|| {
+        match analysis {
+          Some(ref r) => proc.analyzed(&***r),
+          None if pass == 0 => proc.begin(in_w, in_h),
+          None => (),
+        }
+
+        (proc.halo(), proc.color_space(), proc.inputs())
+      }))
+      .map_err(panic_msg),
+    };
+
+    let (halo, space, inputs, error) = match begun {
+      Ok((halo, space, inputs)) => (halo, space, inputs, None),
+      Err(e) => {
+        failed = Some((stage, e.clone()));
+        (Some(0), ColorSpace::Linear, Vec::new(), Some(e))
+      },
+    };
+
+    if analyze {
+      // Analysis tasks report nothing to the callback, so there's no point
+      // queueing them if the proc has already failed, or if none of the
+      // stage's tiles are being rendered
+      if error.is_some()
+        || needed.as_ref().map_or(false, |n| !n.contains(&true))
+      {
+        return Some(Vec::new());
+      }
+
+      let cached = cache.lock().unwrap().get(&(stage, scale)).and_then(|c| {
+        if Arc::ptr_eq(&c.proc, &proc) && c.input == input.content_id() {
+          Some(c.result.clone())
+        } else {
+          None
+        }
+      });
+
+      if let Some(r) = cached {
+        analyzed = Some(Analyzed::Cached(r));
+        return Some(Vec::new());
+      }
+    }
+
+    // Tiles encode what they read from extra inputs, as with the input
+    let extra = Arc::new(
+      inputs
+        .iter()
+        .map(|i| {
+          i.as_ref().map(|&(key, ref img)| {
+            resample_cached(&resamples, key, img, (in_w, in_h), input.pager())
+          })
+        })
+        .collect(),
+    );
+
+    let out = if analyze {
+      None
+    } else if let Some(ref k) = kept {
+      Some(k.output.clone())
+    } else {
+      Some(Arc::new(ImageStore::new(
+        input.pager().clone(),
+        out_w,
+        out_h,
+      )))
+    };
+
+    let tiles = gen_tiles(
+      out_w,
+      out_h,
+      tile_dims,
+      scale,
+      pass,
+      halo,
+      space,
+      &input,
+      &extra,
+      out.as_ref(),
+      generation,
+      writes_output,
+    );
+
+    if phases.len() == 0 {
+      *output.lock().unwrap() = out.clone();
+    }
+
+    let results: Option<AnalysisResults> = if analyze {
+      let results = Arc::new(Mutex::new(tiles.iter().map(|_| None).collect()));
+
+      analyzed = Some(Analyzed::Gathering(input.content_id(), results.clone()));
+
+      Some(results)
+    } else {
+      None
+    };
+
+    let tasks = tiles
+      .iter()
+      .enumerate()
+      .filter(|&(i, _)| needed.as_ref().map_or(true, |n| n[i]))
+      .map(|(i, t)| TileTask {
+        proc: proc.clone(),
+        tile: t.clone(),
+        stage,
+        pass,
+        preview: scale > 1,
+        space,
+        w: out_size.0,
+        h: out_size.1,
+        error: error.clone(),
+        analysis: results.as_ref().map(|r| (i, r.clone())),
+        cache: if analyze { None } else { stage_key.clone() },
+        kept: kept.as_ref().map_or(false, |k| k.tiles[i]),
+      })
+      .collect();
+
+    if out.is_some() {
+      last = out;
+
+      if needed.is_none() {
+        described = stage_key.map(|k| {
+          let id = rng::hash(&[k.key, scale as u64, pass as u64]);
+
+          (k, id)
+        });
+      }
+    }
+
+    Some(tasks)
+  }
+}
+
+struct Preview {
+  scale: u32,
+  // The input, downsampled by scale
+  input: Arc<ImageStore>,
+}
+
+// What the pool's threads are handed
+enum JobStep<T>
+where
+  T: Send + Sync,
+{
+  // Sets up the job's first phase.  This is left to the pool rather than done
+  // when the render starts, since procs can hold it up while they finish tiles
+  // from the render before.
+  Begin,
+  Tile(TileTask<T>),
+}
+
+type JobTask<C> = (Arc<RenderJob<C>>, JobStep<<C as RenderCallback>::Tag>);
+
+// The state of a single call to Renderer::begin_render, shared by all of its
+// tasks
+struct RenderJob<C>
+where
+  C: RenderCallback + Clone + Send + Sync + 'static,
+  C::Tag: Default + Send + Sync,
+{
+  callback: C,
+  generation: usize,
+  cancel_tok: CancelTok,
+  order: Arc<RwLock<Arc<TileOrder + Send + Sync>>>,
+  started: Instant,
+  // What the last phase writes to, once it's started
+  output: Arc<Mutex<Option<Arc<ImageStore>>>>,
+  // Cleared when one of the current phase's tiles fails
+  intact: Arc<AtomicBool>,
+  // Which of the output's tiles are done, in the order gen_tiles makes them
+  finished: Mutex<Vec<bool>>,
+  tile_dims: (u32, u32),
+  out_size: (u32, u32),
+  tiles: Arc<TileCache>,
+  // How many tasks in the current phase have yet to finish
+  remaining: AtomicUsize,
+  next: Mutex<Box<FnMut() -> Option<Vec<TileTask<C::Tag>>> + Send>>,
+  queue: Queue<JobTask<C>>,
+  // Why the job stopped partway, if setting up one of its phases failed
+  error: Mutex<Option<String>>,
+  done: Mutex<bool>,
+  done_cond: Condvar,
+  // How far along the render is, and the streams to send that to as it
+  // changes
+  progress: Mutex<(Progress, Vec<Sender<Progress>>)>,
+}
+
+impl<C> RenderJob<C>
+where
+  C: RenderCallback + Clone + Send + Sync + 'static,
+  C::Tag: Default + Send + Sync,
+{
+  fn run(job: &Arc<Self>, id: usize, task: TileTask<C::Tag>) {
+    let TileTask {
+      proc,
+      tile,
+      stage,
+      pass,
+      preview,
+      space,
+      error,
+      analysis,
+      cache,
+      kept,
+      ..
+    } = task;
+
+    if let Some((i, results)) = analysis {
+      if !job.cancel_tok.cancelled() {
+        let result = match error {
+          Some(e) => Err(e),
+          None => panic::catch_unwind(AssertUnwindSafe(|| {
+            proc.analyze_tile(&tile.tile.load())
+          }))
+          .map_err(panic_msg),
+        };
+
+        results.lock().unwrap()[i] = Some(result);
+      }
+    } else if kept && !job.cancel_tok.cancelled() {
+      let result = panic::catch_unwind(AssertUnwindSafe(|| tile.tile.reload()))
+        .map_err(panic_msg);
+
+      Self::report(job, tile, id, preview, result);
+    } else if !job.cancel_tok.cancelled() {
+      let key = match (&error, &cache) {
+        (None, Some(c)) => Some(c.tile(&tile.tile)),
+        _ => None,
+      };
+
+      match key.and_then(|k| job.tiles.get(k)) {
+        Some(pixels) => {
+          // A reused tile never reaches the proc, so there's no progress or
+          // timing to report
+          let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            *tile.tile.out_buf() = (*pixels).clone();
+            tile.tile.store();
+          }))
+          .map_err(panic_msg);
+
+          Self::report(job, tile, id, preview, result);
+        },
+        None => Self::render(
+          job,
+          id,
+          proc,
+          tile,
+          stage,
+          pass,
+          preview,
+          space,
+          error,
+          key.and_then(|k| cache.map(|c| (k, c))),
+        ),
+      }
+    }
+
+    if job.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+      Self::advance(job);
+    }
+  }
+
+  // Runs the proc on a tile that wasn't in the cache
+  fn render(
+    job: &Arc<Self>,
+    id: usize,
+    proc: Arc<RenderProc + Send + Sync>,
+    tile: Arc<TaggedTile<C::Tag>>,
+    stage: usize,
+    pass: u32,
+    preview: bool,
+    space: ColorSpace,
+    error: Option<String>,
+    cache: Option<(u64, StageKey)>,
+  ) {
+    if !preview {
+      job.callback.before_tile(tile.clone(), id);
+    }
+
+    // Each tile gets its own token, so progress can be traced back to it
+    let tok = CancelTok {
+      cancelled: job.cancel_tok.cancelled.clone(),
+      progress: if preview {
+        None
+      } else {
+        let job = job.clone();
+        let tile = tile.clone();
+
+        Some(Box::new(move |frac| {
+          if !job.cancel_tok.cancelled() {
+            job.callback.handle_progress(tile.clone(), id, frac);
+          }
+        }))
+      },
+    };
+
+    let ran = error.is_none();
+    let began = Instant::now();
+
+    // Loading is counted as part of the tile, since paging its input back
+    // in can be a good part of the cost
+    let result = match error {
+      Some(e) => Err(e),
+      None => panic::catch_unwind(AssertUnwindSafe(|| {
+        proc.process_tile(&tile.tile.load(), &tok)
+      }))
+      .map_err(panic_msg),
+    };
+
+    if ran {
+      let (x, y, w, h) = tile.tile.bounds();
+
+      job.callback.handle_timing(TileTiming {
+        x,
+        y,
+        w,
+        h,
+        stage,
+        pass,
+        wid: id,
+        preview,
+        generation: job.generation,
+        cancelled: job.cancel_tok.cancelled(),
+        failed: result.is_err(),
+        start: began - job.started,
+        time: began.elapsed(),
+      });
+    }
+
+    // Storing the tile can fail if the disk is full, which is reported the
+    // same way as a panic in the proc
+    let result = result.and_then(|()| {
+      panic::catch_unwind(AssertUnwindSafe(|| {
+        if space != ColorSpace::Linear {
+          for px in tile.tile.out_buf().iter_mut() {
+            *px = space.decode(*px);
+          }
+        }
+
+        if !job.cancel_tok.cancelled() {
+          tile.tile.store();
+        }
+      }))
+      .map_err(panic_msg)
+    });
+
+    if let (&Ok(()), Some((key, stage_key))) = (&result, cache) {
+      if !job.cancel_tok.cancelled() && stage_key.current() {
+        job.tiles.insert(key, &tile.tile.out_buf());
+      }
+    }
+
+    Self::report(job, tile, id, preview, result);
+  }
+
+  // Passes a finished tile on to the callback
+  fn report(
+    job: &Arc<Self>,
+    tile: Arc<TaggedTile<C::Tag>>,
+    id: usize,
+    preview: bool,
+    result: Result<(), String>,
+  ) {
+    if !job.cancel_tok.cancelled() {
+      if !preview {
+        job.count(result.is_err());
+      }
+
+      match result {
+        Ok(()) if preview => job.callback.handle_preview(tile, id),
+        Ok(()) => {
+          if tile.writes_output() {
+            job.mark_finished(&tile.tile);
+          }
+
+          job.callback.handle_tile(tile, id)
+        },
+        Err(msg) => {
+          job.intact.store(false, Ordering::SeqCst);
+
+          for px in tile.tile.out_buf().iter_mut() {
+            *px = Pixel::new(0.0, 0.0, 0.0, 0.0);
+          }
+
+          job.callback.handle_panic(tile, id, &msg);
+        },
+      }
+    }
+  }
+
+  // Queues the next phase's tasks once the current phase is finished, or wraps
+  // up the job if there are none left
+  fn advance(job: &Arc<Self>) {
+    loop {
+      let tasks = if job.cancel_tok.cancelled() {
+        None
+      } else {
+        let mut next = job.next.lock().unwrap();
+
+        // Setting up a phase means reading and writing image data, which can
+        // fail if it's been paged out to disk
+        panic::catch_unwind(AssertUnwindSafe(|| (&mut *next)())).unwrap_or_else(
+          |e| {
+            *job.error.lock().unwrap() = Some(panic_msg(e));
+            None
+          },
+        )
+      };
+
+      match tasks {
+        Some(ref t) if t.is_empty() => continue,
+        Some(mut t) => {
+          {
+            let order = job.order.read().unwrap();
+
+            t.sort_by(|a, b| {
+              cmp_tiles(&**order, &a.tile.tile, &b.tile.tile, a.w, a.h)
+            });
+          }
+
+          job.remaining.store(t.len(), Ordering::SeqCst);
+          job
+            .queue
+            .extend(t.into_iter().map(|t| (job.clone(), JobStep::Tile(t))));
+
+          return;
+        },
+        None => break,
+      }
+    }
+
+    if !job.cancel_tok.cancelled() {
+      let error = job.error.lock().unwrap().clone();
+
+      match error {
+        Some(msg) => job.callback.handle_error(job.generation, &msg),
+        None => job.callback.after_end(),
+      }
+    }
+
+    *job.done.lock().unwrap() = true;
+    job.done_cond.notify_all();
 
-impl<T> TaggedTile<T>
-where
-  T: Send + Sync,
-{
-  pub fn tile(&self) -> &Tile { &self.tile }
+    // Ends the progress streams
+    job.progress.lock().unwrap().1.clear();
+  }
 
-  pub fn tag(&self) -> &T { &self.tag }
-}
+  // Counts a full-size tile towards the render's progress
+  fn count(&self, failed: bool) {
+    let mut progress = self.progress.lock().unwrap();
 
-pub struct CancelTok {
-  cancelled: AtomicBool,
-}
+    progress.0.done += 1;
 
-impl CancelTok {
-  pub fn cancelled(&self) -> bool { self.cancelled.load(Ordering::SeqCst) }
-}
+    if failed {
+      progress.0.failed += 1;
+    }
 
-pub trait RenderProc {
-  fn begin(&self, _w: u32, _h: u32) {}
+    let now = progress.0;
 
-  fn process_tile(&self, tile: &Tile, cancel_tok: &CancelTok);
-}
+    progress.1.retain(|tx| tx.send(now).is_ok());
+  }
 
-pub trait RenderCallback {
-  type Tag;
+  fn mark_finished(&self, tile: &Tile) {
+    let (tile_w, tile_h) = self.tile_dims;
+    let (tiles_x, _) =
+      tile_counts(self.out_size.0, self.out_size.1, tile_w, tile_h);
 
-  fn before_begin(&self, _ntiles: usize) {}
+    self.finished.lock().unwrap()
+      [(tile.y / tile_h * tiles_x + tile.x / tile_w) as usize] = true;
+  }
 
-  fn after_end(&self) {}
+  fn cancel(job: &Arc<Self>) {
+    job.cancel_tok.cancelled.store(true, Ordering::SeqCst);
 
-  fn abort(&self) {}
+    // Queued tasks are dropped rather than run, so they have to be counted off
+    // here instead
+    let removed = job.queue.remove(|(j, _)| Arc::ptr_eq(j, job));
 
-  fn before_tile(&self, tile: Arc<TaggedTile<Self::Tag>>, wid: usize)
-  where
-    Self::Tag: Send + Sync,
-  {
+    if removed > 0
+      && job.remaining.fetch_sub(removed, Ordering::SeqCst) == removed
+    {
+      Self::advance(job);
+    }
   }
 
-  fn handle_tile(&self, tile: Arc<TaggedTile<Self::Tag>>, wid: usize)
-  where
-    Self::Tag: Send + Sync;
+  fn wait(&self) {
+    let mut done = self.done.lock().unwrap();
+
+    while !*done {
+      done = self.done_cond.wait(done).unwrap();
+    }
+  }
 }
 
 pub struct Renderer<C>
 where
-  C: RenderCallback + Clone + Send + 'static,
+  C: RenderCallback + Clone + Send + Sync + 'static,
   C::Tag: Default + Send + Sync,
 {
-  njobs: usize,
   w: u32,
   h: u32,
+  // The size of the final output, as of the last render
+  out_w: u32,
+  out_h: u32,
+  tile_size: TileSize,
   tile_w: u32,
   tile_h: u32,
-  tiles: Vec<Arc<TaggedTile<C::Tag>>>,
-  worker: Option<OneshotPool<Arc<TaggedTile<C::Tag>>>>,
-  proc: Arc<RenderProc + Send + Sync>,
+  njobs: usize,
+  // Holds the blocks of every image the renderer keeps
+  pager: Arc<Pager>,
+  input: Option<Arc<ImageStore>>,
+  // Counts up with each new input, to tell cached tiles from different
+  // inputs apart
+  input_serial: u64,
+  tiles: Arc<TileCache>,
+  // The part of the output to render, or None for all of it
+  region: Option<Rect>,
+  preview_scale: Option<u32>,
+  preview: Option<Preview>,
+  order: Arc<RwLock<Arc<TileOrder + Send + Sync>>>,
+  pool: ThreadPool<JobTask<C>>,
+  generation: usize,
+  // The last render started, which is kept after it finishes for its output
+  job: Option<Arc<RenderJob<C>>>,
+  analyses: AnalysisCache,
+  resamples: ResampleCache,
+  procs: Vec<Arc<RenderProc + Send + Sync>>,
   callback: C,
-  cancel_tok: Arc<CancelTok>,
 }
 
 impl<C> Renderer<C>
 where
-  C: RenderCallback + Clone + Send + 'static,
+  C: RenderCallback + Clone + Send + Sync + 'static,
   C::Tag: Default + Send + Sync,
 {
-  pub fn new<P>(
-    tile_w: u32,
-    tile_h: u32,
+  pub fn new(
+    tile_size: TileSize,
     njobs: usize,
-    proc: Arc<P>,
+    proc: Arc<RenderProc + Send + Sync>,
     callback: C,
-  ) -> Self
-  where
-    P: RenderProc + Send + Sync + 'static,
-  {
+  ) -> Self {
+    let njobs = cmp::max(njobs, 1);
+
     Self {
-      njobs,
       w: 0,
       h: 0,
-      tile_w,
-      tile_h,
-      tiles: Vec::new(),
-      worker: None,
-      proc,
+      out_w: 0,
+      out_h: 0,
+      tile_size,
+      tile_w: 0,
+      tile_h: 0,
+      njobs,
+      pager: Arc::new(Pager::new(None)),
+      input: None,
+      input_serial: 0,
+      tiles: Arc::new(TileCache::new(0)),
+      region: None,
+      preview_scale: None,
+      preview: None,
+      order: Arc::new(RwLock::new(Arc::new(CenterOut))),
+      pool: Self::gen_pool(njobs),
+      generation: 0,
+      job: None,
+      analyses: Arc::new(Mutex::new(HashMap::new())),
+      resamples: Arc::new(Mutex::new(HashMap::new())),
+      procs: vec![proc],
       callback,
-      cancel_tok: Arc::new(CancelTok {
-        cancelled: AtomicBool::new(false),
-      }),
     }
   }
 
-  fn update_ordering(&mut self) {
-    let cx = (self.w / 2) as f32;
-    let cy = (self.h / 2) as f32;
+  fn gen_pool(njobs: usize) -> ThreadPool<JobTask<C>> {
+    ThreadPool::new((0..njobs).map(|_| ()), |id, _, task| {
+      let (job, step): JobTask<C> = task;
+
+      match step {
+        JobStep::Begin => {
+          if job.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+            RenderJob::advance(&job);
+          }
+        },
+        JobStep::Tile(task) => RenderJob::run(&job, id, task),
+      }
+    })
+  }
 
-    self.tiles.sort_by(|a, b| {
-      let a = a.tile();
-      let b = b.tile();
+  pub fn tile_size(&self) -> TileSize { self.tile_size }
 
-      let da = (((a.cx() as f32 - cx).powi(2) + (a.cy() as f32 - cy).powi(2))
-        as f32)
-        .sqrt();
-      let db = (((b.cx() as f32 - cx).powi(2) + (b.cy() as f32 - cy).powi(2))
-        as f32)
-        .sqrt();
+  // The tile size actually in use, which may differ from tile_size() if it's
+  // set to Auto
+  pub fn tile_dims(&self) -> (u32, u32) { (self.tile_w, self.tile_h) }
 
-      da.partial_cmp(&db)
-        .unwrap()
-        .then_with(|| a.y.cmp(&b.y).then_with(|| a.x.cmp(&b.x)))
-    });
+  pub fn njobs(&self) -> usize { self.njobs }
+
+  pub fn memory_budget(&self) -> Option<usize> { self.pager.budget() }
+
+  // Keeps track of every image the renderer holds, and pages them out when
+  // they go over the memory budget
+  pub fn pager(&self) -> &Arc<Pager> { &self.pager }
+
+  pub fn pixel_format(&self) -> PixelFormat { self.pager.format() }
+
+  pub fn cache_limit(&self) -> usize { self.tiles.limit() }
+
+  pub fn region(&self) -> Option<(u32, u32, u32, u32)> { self.region }
+
+  // How many tiles the final output is split into
+  pub fn ntiles(&self) -> usize {
+    let (x, y) = tile_counts(self.out_w, self.out_h, self.tile_w, self.tile_h);
+
+    (x * y) as usize
   }
 
-  fn begin_render(&mut self) {
-    self.callback.before_begin(self.tiles.len());
-    self.proc.begin(self.w, self.h);
+  // How many of the output's tiles the current render hasn't finished, either
+  // because it's still going or because they're outside its region
+  pub fn missing_tiles(&self) -> usize {
+    match self.job {
+      Some(ref job) => {
+        job.finished.lock().unwrap().iter().filter(|&&f| !f).count()
+      },
+      None => self.ntiles(),
+    }
+  }
 
-    self.worker = Some(OneshotPool::new(
-      self.tiles.iter().map(|t| t.clone()),
-      (0..self.njobs).map(|_| {
-        (
-          self.proc.clone(),
-          self.callback.clone(),
-          self.cancel_tok.clone(),
-        )
+  // The size of the final output, which can differ from the input's if any
+  // proc changes the image size
+  pub fn output_size(&self) -> (u32, u32) { (self.out_w, self.out_h) }
+
+  fn update_preview(&mut self) {
+    self.preview = match (self.preview_scale, &self.input) {
+      (Some(scale), Some(input)) if scale > 1 => Some(Preview {
+        scale,
+        input: Arc::new(downsample_store(input, scale)),
       }),
-      |id, (proc, callback, cancel_tok), tile: Arc<TaggedTile<C::Tag>>| {
-        callback.before_tile(tile.clone(), id);
+      _ => None,
+    };
+  }
+
+  // Picks a tile size according to the current settings, and starts rendering
+  // with it
+  fn retile(&mut self) {
+    let (tile_w, tile_h) = match self.tile_size {
+      TileSize::Auto => auto_tile_size(self.w, self.h, self.njobs),
+      TileSize::Fixed(w, h) => (cmp::max(w, 1), cmp::max(h, 1)),
+    };
+
+    self.tile_w = tile_w;
+    self.tile_h = tile_h;
+
+    self.begin_render();
+  }
+
+  fn begin_render(&mut self) {
+    let region = self.region;
+
+    self.render_tiles(region, None);
+  }
+
+  // Renders the tiles of the output inside region, picking up from kept if it
+  // came from a render of the same settings
+  fn render_tiles(&mut self, region: Option<Rect>, kept: Option<Kept>) {
+    let input = match self.input {
+      Some(ref i) => i.clone(),
+      None => return,
+    };
+
+    // Each stage's size is worked out up front so the preview can be scaled
+    // from the full-size image, and so the callback knows the final size
+    let mut stages = Vec::new();
+    let mut size = (self.w, self.h);
+
+    for proc in &self.procs {
+      let (w, h) = size;
+
+      let (out_size, npasses, analyze, error) =
+        match panic::catch_unwind(AssertUnwindSafe(|| {
+          (proc.output_size(w, h), proc.passes(), proc.needs_analysis())
+        })) {
+          Ok(((w, h), n, a)) => {
+            ((cmp::max(w, 1), cmp::max(h, 1)), cmp::max(n, 1), a, None)
+          },
+          Err(e) => (size, 1, false, Some(panic_msg(e))),
+        };
+
+      stages.push((size, out_size, npasses, analyze, error));
+      size = out_size;
+    }
+
+    self.out_w = size.0;
+    self.out_h = size.1;
+
+    let tile_dims = (self.tile_w, self.tile_h);
+
+    // Only what's inside the output can be rendered
+    let region = region.map(|(x, y, w, h)| {
+      let x = cmp::min(x, self.out_w);
+      let y = cmp::min(y, self.out_h);
 
-        proc.process_tile(&tile.tile, &cancel_tok);
+      (
+        x,
+        y,
+        cmp::min(w, self.out_w - x),
+        cmp::min(h, self.out_h - y),
+      )
+    });
+
+    let kept = kept.filter(|k| {
+      (k.output.w(), k.output.h()) == (self.out_w, self.out_h)
+        && k.tiles.len() == self.ntiles()
+    });
 
-        if !cancel_tok.cancelled() {
-          callback.handle_tile(tile, id);
+    let wanted =
+      region.map(|r| tiles_touching(self.out_w, self.out_h, tile_dims, &[r]));
+
+    // The output's tiles this render has to produce itself, as rects that the
+    // phases before are worked back from
+    let fresh = match kept {
+      Some(ref k) => Some(
+        k.tiles
+          .iter()
+          .enumerate()
+          .map(|(i, &done)| !done && wanted.as_ref().map_or(true, |w| w[i]))
+          .collect::<Vec<_>>(),
+      ),
+      None => wanted.clone(),
+    }
+    .map(|f| tile_rects(self.out_w, self.out_h, tile_dims, &f, 0));
+
+    let halos: Vec<_> = self
+      .procs
+      .iter()
+      .map(|proc| {
+        if fresh.is_none() {
+          return None;
         }
-      },
-      {
-        let callback = self.callback.clone();
 
-        move || {
-          callback.after_end();
+        panic::catch_unwind(AssertUnwindSafe(|| proc.halo())).unwrap_or(None)
+      })
+      .collect();
+
+    // Tiles from a different input, or the same one stored differently, can't
+    // be reused
+    let format = self.pager.format();
+    let base = rng::hash(&[
+      self.input_serial,
+      format.sample as u64,
+      format.layout as u64,
+    ]);
+
+    // These are worked out even if the tile cache is off, since they also
+    // tell analyses whether a stage's input has changed
+    let stage_keys: Vec<_> = (0..self.procs.len())
+      .map(|i| {
+        let procs = &self.procs[..i + 1];
+
+        panic::catch_unwind(AssertUnwindSafe(|| StageKey::chain(base, procs)))
+          .unwrap_or(None)
+          .map(|key| StageKey {
+            base,
+            procs: Arc::new(procs.to_vec()),
+            key,
+          })
+      })
+      .collect();
+
+    let mut passes = Vec::new();
+
+    if let Some(ref preview) = self.preview {
+      passes.push((preview.input.clone(), preview.scale));
+    }
+
+    passes.push((input, 1));
+
+    let mut phases = Vec::new();
+
+    for (buf, scale) in passes {
+      let first = phases.len();
+
+      for (i, proc) in self.procs.iter().enumerate() {
+        let (in_size, out_size, npasses, analyze, ref error) = stages[i];
+
+        if analyze {
+          phases.push(Phase {
+            proc: proc.clone(),
+            stage: i,
+            analyze: true,
+            pass: 0,
+            in_size,
+            out_size: in_size,
+            input: if i == 0 { Some(buf.clone()) } else { None },
+            scale,
+            error: error.clone(),
+            cache: None,
+            writes_output: false,
+            needed: None,
+            kept: None,
+          });
         }
-      },
-    ));
-  }
 
-  fn join_render(&mut self) -> bool {
-    match self.worker.take() {
-      Some(w) => {
-        w.join();
-        true
-      },
-      None => false,
+        for pass in 0..npasses {
+          let last = pass == npasses - 1;
+
+          phases.push(Phase {
+            proc: proc.clone(),
+            stage: i,
+            analyze: false,
+            pass,
+            in_size,
+            // Earlier passes write at the input's size
+            out_size: if last { out_size } else { in_size },
+            input: if i == 0 && pass == 0 {
+              Some(buf.clone())
+            } else {
+              None
+            },
+            scale,
+            error: error.clone(),
+            cache: stage_keys[i].clone(),
+            writes_output: last && i == self.procs.len() - 1,
+            needed: None,
+            kept: None,
+          });
+        }
+      }
+
+      limit_phases(
+        &mut phases[first..],
+        tile_dims,
+        &halos,
+        fresh
+          .as_ref()
+          .map(|f| f.iter().map(|&r| scaled_rect(r, scale)).collect()),
+      );
+
+      // The full-size output queues every tile in the region, reading back the
+      // ones that were kept
+      if scale == 1 {
+        if let Some(phase) = phases.last_mut() {
+          phase.needed = wanted.clone();
+          phase.kept = kept.clone();
+        }
+      }
     }
-  }
 
-  fn abort_render(&mut self) -> bool {
-    self.cancel_tok.cancelled.store(true, Ordering::SeqCst);
+    // Every pass counts, so progress keeps moving through multi-pass procs
+    let ntiles = phases
+      .iter()
+      .filter(|p| p.scale == 1 && !p.analyze)
+      .map(|p| match p.needed {
+        Some(ref n) => n.iter().filter(|&&t| t).count(),
+        None => {
+          let (x, y) =
+            tile_counts(p.out_size.0, p.out_size.1, self.tile_w, self.tile_h);
+
+          (x * y) as usize
+        },
+      })
+      .sum();
 
-    self.callback.abort();
+    self.generation += 1;
+
+    self
+      .callback
+      .before_begin(self.generation, ntiles, self.out_w, self.out_h);
 
-    let ret = match self.worker.take() {
-      Some(w) => {
-        w.abort();
+    // Starting from what was kept means it isn't lost if this render is
+    // stopped before it gets to the output
+    let output = Arc::new(Mutex::new(kept.as_ref().map(|k| k.output.clone())));
+    let intact = Arc::new(AtomicBool::new(true));
+    let finished = match kept {
+      Some(k) => (*k.tiles).clone(),
+      None => vec![false; self.ntiles()],
+    };
+
+    let job = Arc::new(RenderJob {
+      callback: self.callback.clone(),
+      generation: self.generation,
+      cancel_tok: CancelTok::new(),
+      order: self.order.clone(),
+      started: Instant::now(),
+      output: output.clone(),
+      intact: intact.clone(),
+      finished: Mutex::new(finished),
+      tile_dims,
+      out_size: (self.out_w, self.out_h),
+      tiles: self.tiles.clone(),
+      remaining: AtomicUsize::new(0),
+      next: Mutex::new(Box::new(run_phases(
+        phases,
+        tile_dims,
+        self.generation,
+        self.analyses.clone(),
+        self.resamples.clone(),
+        output,
+        intact,
+      ))),
+      queue: self.pool.queue().clone(),
+      error: Mutex::new(None),
+      done: Mutex::new(false),
+      done_cond: Condvar::new(),
+      progress: Mutex::new((
+        Progress {
+          done: 0,
+          failed: 0,
+          total: ntiles,
+        },
+        Vec::new(),
+      )),
+    });
+
+    // The setup counts as a phase of its own, so cancelling the job before it
+    // runs still wraps it up
+    job.remaining.store(1, Ordering::SeqCst);
+    job.queue.push((job.clone(), JobStep::Begin));
+
+    self.job = Some(job);
+  }
+
+  // Cancels the current render without waiting for it.  Tiles the workers are
+  // in the middle of finish in the background (or stop early, if their procs
+  // check the cancellation token), and are reported with the old generation.
+  fn abort_render(&mut self) -> bool {
+    let aborted = match self.job.take() {
+      Some(job) => {
+        RenderJob::cancel(&job);
         true
       },
       None => false,
     };
 
-    self.cancel_tok.cancelled.store(false, Ordering::SeqCst);
+    self.callback.abort();
 
-    ret
+    aborted
   }
 
   pub fn rerender(&mut self) {
@@ -247,125 +2241,347 @@ where
     self.begin_render();
   }
 
-  pub fn read_input<I>(&mut self, in_img: &I)
-  where
-    I: GenericImageView<Pixel = Rgba<u8>>,
-  {
+  // Stops the current render, and hands back what it finished so a render of
+  // the same settings can carry on from there
+  fn take_kept(&mut self) -> Option<Kept> {
+    let job = self.job.clone();
+
+    self.abort_render();
+
+    let job = job?;
+    let output = job.output.lock().unwrap().clone()?;
+    let tiles = job.finished.lock().unwrap().clone();
+
+    Some(Kept {
+      output,
+      tiles: Arc::new(tiles),
+    })
+  }
+
+  // Only renders the tiles of the output that overlap region, given in
+  // full-size output coordinates, along with whatever the stages before need
+  // to fill them in.  Until the settings change, tiles that were already
+  // finished are kept.  This is cleared when a new input is read.
+  pub fn set_region(&mut self, region: Option<(u32, u32, u32, u32)>) {
+    if region == self.region {
+      return;
+    }
+
+    self.region = region;
+
+    let kept = self.take_kept();
+
+    self.render_tiles(region, kept);
+  }
+
+  // Renders whatever the region left out of the output, without clearing it
+  // for later renders
+  pub fn render_full(&mut self) {
+    let kept = self.take_kept();
+
+    self.render_tiles(None, kept);
+  }
+
+  // Takes an image to render from, which has to have been stored through
+  // pager(), e.g. by image_io::load_store
+  pub fn read_input(&mut self, in_img: ImageStore) {
+    assert!(
+      Arc::ptr_eq(in_img.pager(), &self.pager),
+      "input images have to be stored through the renderer's pager"
+    );
+
+    self.abort_render();
+
+    self.w = in_img.w();
+    self.h = in_img.h();
+    self.input_serial += 1;
+    self.region = None;
     self.tiles.clear();
+    self.input = Some(Arc::new(in_img));
+
+    self.analyses.lock().unwrap().clear();
+    // Extra inputs are stretched to the input's size
+    self.resamples.lock().unwrap().clear();
+
+    self.update_preview();
+    self.retile();
+  }
+
+  pub fn set_tile_size(&mut self, tile_size: TileSize) {
+    if tile_size == self.tile_size {
+      return;
+    }
+
+    self.tile_size = tile_size;
 
     self.abort_render();
+    self.retile();
+  }
 
-    self.w = in_img.width();
-    self.h = in_img.height();
+  // Replaces the worker pool, which waits for the old workers to exit
+  pub fn set_njobs(&mut self, njobs: usize) {
+    let njobs = cmp::max(njobs, 1);
+
+    if njobs == self.njobs {
+      return;
+    }
 
-    let tiles_x =
-      self.w / self.tile_w + if self.w % self.tile_w > 0 { 1 } else { 0 };
-    let tiles_y =
-      self.h / self.tile_h + if self.h % self.tile_h > 0 { 1 } else { 0 };
+    self.abort_render();
 
-    let in_buf = Arc::new({
-      let mut in_buf = Vec::new();
+    self.njobs = njobs;
+    self.pool = Self::gen_pool(njobs);
 
-      for r in 0..self.h {
-        for c in 0..self.w {
-          let px = in_img.get_pixel(c, r).data;
+    // The automatic tile size depends on the worker count
+    self.retile();
+  }
 
-          in_buf.push(Vector4::new(
-            px[0] as Quantum / 255.0,
-            px[1] as Quantum / 255.0,
-            px[2] as Quantum / 255.0,
-            px[3] as Quantum / 255.0,
-          ));
-        }
-      }
+  // Changes which tiles are rendered first.  This also applies to tiles that
+  // are already waiting to be rendered, so it's cheap enough to call whenever
+  // e.g. the mouse moves.
+  pub fn set_tile_order(&mut self, order: Arc<TileOrder + Send + Sync>) {
+    *self.order.write().unwrap() = order.clone();
 
-      in_buf
+    self.pool.queue().sort_by(|a, b| match (&a.1, &b.1) {
+      (JobStep::Tile(a), JobStep::Tile(b)) => {
+        cmp_tiles(&*order, &a.tile.tile, &b.tile.tile, a.w, a.h)
+      },
+      (JobStep::Begin, JobStep::Begin) => cmp::Ordering::Equal,
+      (JobStep::Begin, _) => cmp::Ordering::Less,
+      (_, JobStep::Begin) => cmp::Ordering::Greater,
     });
+  }
 
-    self.tiles = (0..tiles_y)
-      .flat_map(|r| {
-        let y = r * self.tile_h;
-        let h = cmp::min(self.tile_h, self.h - y);
-
-        (0..tiles_x).map(move |c| (h, y, c)).map(|(h, y, c)| {
-          let x = c * self.tile_w;
-          let w = cmp::min(self.tile_w, self.w - x);
-
-          let out_buf: Vec<_> =
-            (0..h * w).map(|_| Pixel::new(0.0, 0.0, 0.0, 0.0)).collect();
-
-          Arc::new(TaggedTile {
-            tile: Tile {
-              x,
-              y,
-              w,
-              h,
-              in_stride: self.w,
-              in_buf: in_buf.clone(),
-              out_buf: Mutex::new(out_buf),
-            },
-            tag: Default::default(),
-          })
-        })
-      })
-      .collect();
-
-    self.update_ordering();
-    self.begin_render();
+  // Limits how many bytes of image data are kept in memory, beyond which the
+  // least recently used parts are paged out to a scratch file in the system's
+  // temporary directory.  This covers the input, the output and everything in
+  // between, but not the copies each tile works on.
+  pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+    self.pager.set_budget(budget);
   }
 
-  pub fn set_proc(&mut self, proc: Arc<RenderProc + Send + Sync>) {
-    self.proc = proc;
+  // Changes how images are stored between stages.  Procs always see f32
+  // pixels, but a smaller format fits more of the image in memory at the cost
+  // of precision.  The input keeps its format until the next read_input.
+  pub fn set_pixel_format(&mut self, format: PixelFormat) {
+    if format == self.pager.format() {
+      return;
+    }
+
+    self.pager.set_format(format);
     self.rerender();
   }
 
-  pub fn get_output(&mut self) -> Option<RgbaImage> {
-    if self.tiles.is_empty() {
-      return None;
+  // Keeps up to this many bytes of finished tiles, so going back to settings
+  // that were rendered before doesn't render them again.  This is 0 (off) by
+  // default, and isn't counted towards the memory budget.
+  pub fn set_cache_limit(&mut self, bytes: usize) {
+    self.tiles.set_limit(bytes);
+  }
+
+  // Renders a quick pass at 1/scale resolution before each full render, which
+  // is reported through RenderCallback::handle_preview
+  pub fn set_preview_scale(&mut self, scale: Option<u32>) {
+    self.preview_scale = scale;
+    self.update_preview();
+  }
+
+  // Sets a chain of procs to be applied one after another, each reading the
+  // output of the last
+  pub fn set_procs(&mut self, procs: Vec<Arc<RenderProc + Send + Sync>>) {
+    if procs.is_empty() {
+      self.procs = vec![Arc::new(DummyRenderProc)];
+    } else {
+      self.procs = procs;
     }
 
-    self.join_render();
+    // Analyses of stages that now hold a different proc are no use anymore
+    {
+      let procs = &self.procs;
 
-    let mut img = RgbaImage::new(self.w, self.h);
+      self.analyses.lock().unwrap().retain(|&(stage, _), c| {
+        procs.get(stage).map_or(false, |p| Arc::ptr_eq(p, &c.proc))
+      });
+    }
 
-    for tile in &self.tiles {
-      let tile = &tile.tile;
+    self.rerender();
+  }
 
-      let buf = tile.out_buf.lock().unwrap();
+  // Like get_output, but hands back the render in progress instead of waiting
+  // for it.  If only a region is being rendered, the rest is started first.
+  // This is None until an input has been read.
+  pub fn render_async(&mut self) -> Option<RenderHandle<C>> {
+    if self.region.is_some() && self.missing_tiles() > 0 {
+      self.render_full();
+    }
 
-      for r in 0..tile.h {
-        let r_stride = r * tile.w;
+    self
+      .job
+      .as_ref()
+      .map(|job| RenderHandle { job: job.clone() })
+  }
 
-        for c in 0..tile.w {
-          let px = buf[(r_stride + c) as usize];
+  // Starts rendering in_img through procs, for programs that only want the
+  // finished image.  This replaces whatever was being rendered before.
+  pub fn render(
+    &mut self,
+    in_img: ImageStore,
+    procs: Vec<Arc<RenderProc + Send + Sync>>,
+  ) -> RenderHandle<C> {
+    // The old input is about to be replaced anyway, and without it setting
+    // the procs doesn't start a render of its own
+    self.input = None;
+
+    self.set_procs(procs);
+    self.read_input(in_img);
+
+    // Reading an input always starts a render
+    self.render_async().unwrap()
+  }
 
-          let data = [
-            (px[0] * 255.0).round() as u8,
-            (px[1] * 255.0).round() as u8,
-            (px[2] * 255.0).round() as u8,
-            (px[3] * 255.0).round() as u8,
-          ];
+  // Waits for the current render to finish, and returns what it produced.  If
+  // a region is set, the rest of the image is rendered first.
+  pub fn get_output(&mut self) -> Option<Arc<ImageStore>> {
+    self.job.as_ref()?.wait();
 
-          img.put_pixel(tile.x + c, tile.y + r, Rgba { data });
-        }
-      }
+    if self.region.is_some() && self.missing_tiles() > 0 {
+      self.render_full();
+      self.job.as_ref()?.wait();
     }
 
-    return Some(img);
+    let job = self.job.as_ref()?;
+
+    let output = job.output.lock().unwrap().clone();
+
+    // The last phase won't have started if the render was stopped early
+    Some(output.unwrap_or_else(|| {
+      Arc::new(ImageStore::new(self.pager.clone(), self.out_w, self.out_h))
+    }))
   }
 }
 
 impl<C> Drop for Renderer<C>
 where
-  C: RenderCallback + Clone + Send + 'static,
+  C: RenderCallback + Clone + Send + Sync + 'static,
   C::Tag: Default + Send + Sync,
 {
   fn drop(&mut self) { self.abort_render(); }
 }
 
+// How far along a render is, as sent by RenderHandle::progress.  This counts
+// the same tiles as the ntiles given to RenderCallback::before_begin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+  pub done: usize,
+  // How many of the done tiles failed, which are left blank
+  pub failed: usize,
+  pub total: usize,
+}
+
+// A render that may still be going, from Renderer::render or render_async.  It
+// can be waited on from any thread, and dropping it doesn't stop the render.
+// If the renderer moves on to something else first, the render reports that
+// it was stopped.
+pub struct RenderHandle<C>
+where
+  C: RenderCallback + Clone + Send + Sync + 'static,
+  C::Tag: Default + Send + Sync,
+{
+  job: Arc<RenderJob<C>>,
+}
+
+impl<C> RenderHandle<C>
+where
+  C: RenderCallback + Clone + Send + Sync + 'static,
+  C::Tag: Default + Send + Sync,
+{
+  // Returns None without blocking if the render is still going, or its output
+  // encoded to 8-bit sRGB if not
+  pub fn poll(&self) -> Option<Result<RgbaImage, String>> {
+    if *self.job.done.lock().unwrap() {
+      Some(self.to_rgba8())
+    } else {
+      None
+    }
+  }
+
+  pub fn wait(&self) -> Result<RgbaImage, String> {
+    self.job.wait();
+
+    self.to_rgba8()
+  }
+
+  // Like wait, but gives the output in full precision and linear light.  It
+  // stays in the renderer's store, so it can be saved without being copied
+  // into memory in full.
+  pub fn wait_store(&self) -> Result<Arc<ImageStore>, String> {
+    self.job.wait();
+
+    self.output()
+  }
+
+  // A stream of the render's progress, starting with where it is now and
+  // ending once it finishes or is stopped
+  pub fn progress(&self) -> Receiver<Progress> {
+    let (tx, rx) = mpsc::channel();
+    let mut progress = self.job.progress.lock().unwrap();
+
+    tx.send(progress.0).unwrap();
+
+    // Finished renders have already ended their streams
+    if !*self.job.done.lock().unwrap() {
+      progress.1.push(tx);
+    }
+
+    rx
+  }
+
+  fn output(&self) -> Result<Arc<ImageStore>, String> {
+    let error = self.job.error.lock().unwrap().clone();
+
+    if let Some(msg) = error {
+      return Err(format!("the render stopped: {}", msg));
+    }
+
+    let progress = self.job.progress.lock().unwrap().0;
+
+    if progress.done < progress.total {
+      return Err("the render was stopped before it finished".to_string());
+    }
+
+    if progress.failed > 0 {
+      return Err(format!("{} tile(s) failed to render", progress.failed));
+    }
+
+    let output = self.job.output.lock().unwrap().clone();
+
+    output.ok_or_else(|| "nothing was rendered".to_string())
+  }
+
+  fn to_rgba8(&self) -> Result<RgbaImage, String> {
+    self.output().map(|o| {
+      o.to_pixel_buf()
+        .map(|px| ColorSpace::Perceptual.encode(px))
+        .to_rgba8()
+    })
+  }
+}
+
+// Ignores everything, for renderers whose output is only read through
+// get_output or a RenderHandle
+#[derive(Clone, Copy)]
+pub struct NullCallback;
+
+impl RenderCallback for NullCallback {
+  type Tag = ();
+
+  fn handle_tile(&self, _: Arc<TaggedTile<()>>, _: usize) {}
+}
+
 pub struct DummyRenderProc;
 
 impl RenderProc for DummyRenderProc {
+  fn cache_key(&self) -> Option<u64> { Some(0) }
+
   fn process_tile(&self, tile: &Tile, _: &CancelTok) {
     let mut out_buf = tile.out_buf();
 
@@ -378,3 +2594,96 @@ impl RenderProc for DummyRenderProc {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::RngCore;
+
+  fn tile(x: u32, y: u32, size: u32, scale: u32, pass: u32) -> Tile {
+    let input = Arc::new(ImageStore::new(Arc::new(Pager::new(None)), 1, 1));
+
+    Tile::new(
+      x,
+      y,
+      size,
+      size,
+      scale,
+      pass,
+      None,
+      ColorSpace::Linear,
+      input,
+      Arc::new(Vec::new()),
+      None,
+    )
+  }
+
+  fn stream(tile: &Tile, seed: u64) -> Vec<u64> {
+    let mut rng = tile.rng(seed);
+
+    (0..4).map(|_| rng.next_u64()).collect()
+  }
+
+  #[test]
+  fn noise_ignores_tile_size() {
+    let whole = tile(0, 0, 64, 1, 0);
+
+    for &size in &[7, 16, 64] {
+      for y in 0..64 {
+        for x in 0..64 {
+          let t = tile(x / size * size, y / size * size, size, 1, 0);
+
+          assert_eq!(
+            t.noise(5, x % size, y % size),
+            whole.noise(5, x, y),
+            "({}, {}) in a {}px tile",
+            x,
+            y,
+            size
+          );
+        }
+      }
+    }
+
+    // A preview pixel takes the noise of the first full-size pixel under it
+    let preview = tile(8, 4, 16, 4, 0);
+
+    assert_eq!(preview.noise(5, 1, 2), whole.noise(5, 36, 24));
+  }
+
+  #[test]
+  fn rng_depends_on_seed_position_and_pass() {
+    let base = stream(&tile(64, 32, 64, 1, 0), 1);
+
+    // The tile's size and contents don't matter
+    assert_eq!(stream(&tile(64, 32, 16, 1, 0), 1), base);
+    assert_eq!(stream(&tile(64, 32, 64, 1, 0), 1), base);
+
+    assert_ne!(stream(&tile(64, 32, 64, 1, 0), 2), base);
+    assert_ne!(stream(&tile(0, 32, 64, 1, 0), 1), base);
+    assert_ne!(stream(&tile(64, 0, 64, 1, 0), 1), base);
+    assert_ne!(stream(&tile(32, 64, 64, 1, 0), 1), base);
+    assert_ne!(stream(&tile(64, 32, 64, 1, 1), 1), base);
+  }
+
+  #[test]
+  fn stage_keys_follow_the_filter_not_the_proc() {
+    use filters::{BlankFilter, Filter, InvertFilter};
+
+    let chain = |procs: Vec<Arc<RenderProc + Send + Sync>>| {
+      StageKey::chain(7, &procs).unwrap()
+    };
+
+    // Switching away from a filter and back makes a new proc for it
+    assert_eq!(
+      chain(vec![InvertFilter::new().proc()]),
+      chain(vec![InvertFilter::new().proc()])
+    );
+
+    // Both of these have a cache_key of 0
+    assert_ne!(
+      chain(vec![BlankFilter::new().proc()]),
+      chain(vec![Arc::new(DummyRenderProc)])
+    );
+  }
+}
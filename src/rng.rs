@@ -0,0 +1,66 @@
+use rand::{Error, RngCore};
+
+// The fractional part of the golden ratio, which spreads consecutive values
+// evenly over every bit
+const GOLDEN: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// SplitMix64's finalizer, which makes every bit of the input affect every bit
+// of the output
+fn mix(z: u64) -> u64 {
+  let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+  z ^ (z >> 31)
+}
+
+// Combines several values into one well-mixed seed.  The order matters, so
+// (1, 2) and (2, 1) hash differently.
+pub fn hash(vals: &[u64]) -> u64 {
+  vals
+    .iter()
+    .fold(GOLDEN, |h, &v| mix(h ^ mix(v.wrapping_add(GOLDEN))))
+}
+
+// A value in [0, 1) that depends only on seed and the position
+pub fn noise(seed: u64, x: u32, y: u32) -> f32 {
+  // 24 bits is all an f32 can hold below 1 without rounding up to it
+  (hash(&[seed, x as u64, y as u64]) >> 40) as f32 / (1u64 << 24) as f32
+}
+
+// A small, fast random number generator (SplitMix64) that gives the same
+// numbers for the same seed on every machine.  rand's SmallRng doesn't promise
+// that, and may change between versions.
+#[derive(Clone, Debug)]
+pub struct StableRng {
+  state: u64,
+}
+
+impl StableRng {
+  pub fn new(seed: u64) -> Self { Self { state: seed } }
+}
+
+impl RngCore for StableRng {
+  fn next_u32(&mut self) -> u32 { (self.next_u64() >> 32) as u32 }
+
+  fn next_u64(&mut self) -> u64 {
+    self.state = self.state.wrapping_add(GOLDEN);
+
+    mix(self.state)
+  }
+
+  fn fill_bytes(&mut self, dest: &mut [u8]) {
+    for chunk in dest.chunks_mut(8) {
+      let val = self.next_u64();
+
+      for (i, byte) in chunk.iter_mut().enumerate() {
+        *byte = (val >> (i * 8)) as u8;
+      }
+    }
+  }
+
+  fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+    self.fill_bytes(dest);
+
+    Ok(())
+  }
+}
@@ -0,0 +1,1066 @@
+use render::{quantize, Pixel, PixelBuf, Quantum};
+use std::{
+  cmp,
+  collections::{BTreeMap, HashMap},
+  env,
+  fs::{self, File, OpenOptions},
+  io::{self, Read, Seek, SeekFrom, Write},
+  path::PathBuf,
+  process,
+  sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex, MutexGuard, PoisonError,
+  },
+};
+
+// Images are stored in square blocks this many pixels on a side, which are
+// the unit that gets paged in and out
+pub const BLOCK_SIZE: u32 = 128;
+
+// Every slot in the scratch file is big enough for a block in any format, so
+// freed slots can be reused by any image
+const BLOCK_BYTES: usize = (BLOCK_SIZE * BLOCK_SIZE * 16) as usize;
+
+// Tells apart the scratch files of several pagers in the same process
+static SCRATCH_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// How each channel of a stored pixel is kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+  F32,
+  // Half-precision floats, which keep values outside [0, 1] but only about
+  // three significant digits
+  F16,
+  // Integers spanning [0, 1].  Anything outside that range is clamped.
+  U16,
+}
+
+impl SampleFormat {
+  pub fn all() -> &'static [SampleFormat] {
+    &[SampleFormat::F32, SampleFormat::F16, SampleFormat::U16]
+  }
+
+  // The name used for this format on the command line
+  pub fn id(&self) -> &'static str {
+    match self {
+      SampleFormat::F32 => "f32",
+      SampleFormat::F16 => "f16",
+      SampleFormat::U16 => "u16",
+    }
+  }
+
+  pub fn from_id(id: &str) -> Option<SampleFormat> {
+    Self::all().iter().cloned().find(|f| f.id() == id)
+  }
+}
+
+// Whether a block keeps each pixel's channels together (RGBARGBA...) or keeps
+// a plane per channel (RR...GG...BB...AA...)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+  Interleaved,
+  Planar,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+  pub sample: SampleFormat,
+  pub layout: Layout,
+}
+
+impl PixelFormat {
+  pub fn bytes_per_pixel(&self) -> usize {
+    match self.sample {
+      SampleFormat::F32 => 16,
+      SampleFormat::F16 | SampleFormat::U16 => 8,
+    }
+  }
+}
+
+impl Default for PixelFormat {
+  fn default() -> Self {
+    Self {
+      sample: SampleFormat::F32,
+      layout: Layout::Interleaved,
+    }
+  }
+}
+
+// Rounds to the nearest half-precision float, with ties going to even
+fn f32_to_f16(q: f32) -> u16 {
+  let bits = q.to_bits();
+  let sign = ((bits >> 16) & 0x8000) as u16;
+  let exp = ((bits >> 23) & 0xff) as i32;
+  let man = bits & 0x7f_ffff;
+
+  // Infinity and NaN
+  if exp == 0xff {
+    return sign | 0x7c00 | if man != 0 { 0x200 } else { 0 };
+  }
+
+  let exp = exp - 127 + 15;
+
+  if exp >= 0x1f {
+    return sign | 0x7c00;
+  }
+
+  // Too small for a normal half, so the implicit leading 1 becomes explicit
+  let (val, rest, shift) = if exp <= 0 {
+    if exp < -10 {
+      return sign;
+    }
+
+    let man = man | 0x80_0000;
+    let shift = (14 - exp) as u32;
+
+    (man >> shift, man & ((1 << shift) - 1), shift)
+  } else {
+    (((exp as u32) << 10) | man >> 13, man & 0x1fff, 13)
+  };
+
+  let half = 1 << (shift - 1);
+
+  // A carry out of the mantissa bumps the exponent, which is still correct
+  if rest > half || (rest == half && val & 1 == 1) {
+    sign | (val + 1) as u16
+  } else {
+    sign | val as u16
+  }
+}
+
+fn f16_to_f32(h: u16) -> f32 {
+  let sign = ((h & 0x8000) as u32) << 16;
+  let exp = ((h >> 10) & 0x1f) as u32;
+  let man = (h & 0x3ff) as u32;
+
+  match exp {
+    0 => f32::from_bits(sign | (man as f32 / 16_777_216.0).to_bits()),
+    0x1f => f32::from_bits(sign | 0x7f80_0000 | man << 13),
+    _ => f32::from_bits(sign | (exp + 112) << 23 | man << 13),
+  }
+}
+
+// Where a channel of the ith of len pixels is in a block's samples
+fn sample_index(layout: Layout, len: usize, i: usize, channel: usize) -> usize {
+  match layout {
+    Layout::Interleaved => i * 4 + channel,
+    Layout::Planar => channel * len + i,
+  }
+}
+
+#[derive(Clone, PartialEq)]
+enum Samples {
+  F32(Vec<f32>),
+  // Either F16 or U16, depending on the format
+  Short(Vec<u16>),
+}
+
+// The pixels of one block, in the format of the image it's part of
+#[derive(Clone, PartialEq)]
+pub struct PixelBlock {
+  format: PixelFormat,
+  len: usize,
+  samples: Samples,
+}
+
+impl PixelBlock {
+  // The block starts out transparent
+  fn new(format: PixelFormat, len: usize) -> Self {
+    Self {
+      format,
+      len,
+      samples: match format.sample {
+        SampleFormat::F32 => Samples::F32(vec![0.0; len * 4]),
+        SampleFormat::F16 | SampleFormat::U16 => {
+          Samples::Short(vec![0; len * 4])
+        },
+      },
+    }
+  }
+
+  fn bytes(&self) -> usize { self.len * self.format.bytes_per_pixel() }
+
+  pub fn len(&self) -> usize { self.len }
+
+  pub fn get(&self, i: usize) -> Pixel {
+    let idx = |c| sample_index(self.format.layout, self.len, i, c);
+
+    match self.samples {
+      Samples::F32(ref s) => {
+        let q = |c| s[idx(c)];
+
+        Pixel::new(q(0), q(1), q(2), q(3))
+      },
+      Samples::Short(ref s) => {
+        let q = |c| {
+          let v = s[idx(c)];
+
+          match self.format.sample {
+            SampleFormat::U16 => v as Quantum / 65535.0,
+            _ => f16_to_f32(v),
+          }
+        };
+
+        Pixel::new(q(0), q(1), q(2), q(3))
+      },
+    }
+  }
+
+  pub fn set(&mut self, i: usize, px: Pixel) {
+    let PixelFormat { sample, layout } = self.format;
+    let len = self.len;
+    let idx = |c| sample_index(layout, len, i, c);
+
+    match self.samples {
+      Samples::F32(ref mut s) => {
+        for c in 0..4 {
+          s[idx(c)] = px[c];
+        }
+      },
+      Samples::Short(ref mut s) => {
+        for c in 0..4 {
+          s[idx(c)] = match sample {
+            SampleFormat::U16 => quantize(px[c], 65535.0) as u16,
+            _ => f32_to_f16(px[c]),
+          };
+        }
+      },
+    }
+  }
+
+  // Little-endian, whatever the platform
+  fn to_bytes(&self) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(self.bytes());
+
+    match self.samples {
+      Samples::F32(ref s) => {
+        for q in s {
+          let bits = q.to_bits();
+
+          ret.extend_from_slice(&[
+            bits as u8,
+            (bits >> 8) as u8,
+            (bits >> 16) as u8,
+            (bits >> 24) as u8,
+          ]);
+        }
+      },
+      Samples::Short(ref s) => {
+        for v in s {
+          ret.extend_from_slice(&[*v as u8, (v >> 8) as u8]);
+        }
+      },
+    }
+
+    ret
+  }
+
+  fn from_bytes(format: PixelFormat, len: usize, bytes: &[u8]) -> Self {
+    Self {
+      format,
+      len,
+      samples: match format.sample {
+        SampleFormat::F32 => Samples::F32(
+          bytes
+            .chunks(4)
+            .map(|b| {
+              f32::from_bits(
+                b[0] as u32
+                  | (b[1] as u32) << 8
+                  | (b[2] as u32) << 16
+                  | (b[3] as u32) << 24,
+              )
+            })
+            .collect(),
+        ),
+        SampleFormat::F16 | SampleFormat::U16 => Samples::Short(
+          bytes
+            .chunks(2)
+            .map(|b| b[0] as u16 | (b[1] as u16) << 8)
+            .collect(),
+        ),
+      },
+    }
+  }
+}
+
+type BlockId = (usize, usize);
+
+struct Block {
+  // None while the block only exists on disk, or while it's taken
+  data: Option<Arc<PixelBlock>>,
+  // Where the block lives in the scratch file, once it's been written there
+  slot: Option<u64>,
+  // Set if data has changed since it was last written out
+  dirty: bool,
+  // Set while the block is being read back from disk or written to, during
+  // which anyone else after it waits
+  busy: bool,
+  // Set while the block is being written out to disk
+  leaving: bool,
+  stamp: u64,
+}
+
+struct Scratch {
+  file: File,
+  path: PathBuf,
+}
+
+impl Scratch {
+  fn create() -> io::Result<Self> {
+    let path = env::temp_dir().join(format!(
+      "ingot-{}-{}.tmp",
+      process::id(),
+      SCRATCH_SEQ.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create_new(true)
+      .open(&path)?;
+
+    // Where the platform allows it, this keeps the file from outliving us if
+    // we crash.  Otherwise it's removed when the pager is dropped.
+    fs::remove_file(&path).ok();
+
+    Ok(Self { file, path })
+  }
+
+  fn write(&mut self, slot: u64, data: &PixelBlock) -> io::Result<()> {
+    self.file.seek(SeekFrom::Start(slot))?;
+    self.file.write_all(&data.to_bytes())
+  }
+
+  fn read(
+    &mut self,
+    slot: u64,
+    format: PixelFormat,
+    len: usize,
+  ) -> io::Result<PixelBlock> {
+    let mut bytes = vec![0u8; len * format.bytes_per_pixel()];
+
+    self.file.seek(SeekFrom::Start(slot))?;
+    self.file.read_exact(&mut bytes)?;
+
+    Ok(PixelBlock::from_bytes(format, len, &bytes))
+  }
+}
+
+impl Drop for Scratch {
+  fn drop(&mut self) { fs::remove_file(&self.path).ok(); }
+}
+
+// A block picked to be written out, and the slot it's going to
+type Leaving = (BlockId, Arc<PixelBlock>, u64);
+
+struct PagerState {
+  // How many bytes of blocks may be kept in memory, if there's a limit
+  budget: Option<usize>,
+  // The format images are stored in when they're made
+  format: PixelFormat,
+  resident: usize,
+  // How much of resident is on its way out to disk
+  leaving: usize,
+  next_store: usize,
+  clock: u64,
+  blocks: HashMap<BlockId, Block>,
+  // The blocks in memory by when they were last used, oldest first.  Taken
+  // blocks aren't in here until they're put back.
+  lru: BTreeMap<u64, BlockId>,
+  // How far the scratch file has been filled
+  scratch_len: u64,
+  // Slots left behind by blocks that have since been freed
+  free: Vec<u64>,
+}
+
+impl PagerState {
+  fn touch(&mut self, id: BlockId) {
+    self.clock += 1;
+
+    let stamp = self.clock;
+    let block = self.blocks.get_mut(&id).unwrap();
+
+    self.lru.remove(&block.stamp);
+    self.lru.insert(stamp, id);
+    block.stamp = stamp;
+  }
+
+  // Picks the least recently used blocks to go out to disk until what's left
+  // in memory fits the budget, sparing keep.  Blocks that are already on disk
+  // are dropped straight away, and the rest are handed back to be written.
+  fn evict(&mut self, keep: Option<BlockId>) -> Vec<Leaving> {
+    let budget = match self.budget {
+      Some(b) => b,
+      None => return Vec::new(),
+    };
+
+    let mut spared = Vec::new();
+    let mut ret = Vec::new();
+
+    while self.resident - self.leaving > budget {
+      let (stamp, id) = match self.lru.iter().next() {
+        Some((&s, &id)) => (s, id),
+        None => break,
+      };
+
+      self.lru.remove(&stamp);
+
+      let block = self.blocks.get_mut(&id).unwrap();
+
+      if Some(id) == keep || block.leaving {
+        spared.push((stamp, id));
+        continue;
+      }
+
+      if !block.dirty && block.slot.is_some() {
+        self.resident -= block.data.take().unwrap().bytes();
+        continue;
+      }
+
+      let slot = match block.slot {
+        Some(s) => s,
+        None => match self.free.pop() {
+          Some(s) => s,
+          None => {
+            self.scratch_len += BLOCK_BYTES as u64;
+            self.scratch_len - BLOCK_BYTES as u64
+          },
+        },
+      };
+
+      let data = block.data.clone().unwrap();
+
+      // Writes from here on dirty it again
+      block.dirty = false;
+      block.leaving = true;
+      self.leaving += data.bytes();
+      ret.push((id, data, slot));
+    }
+
+    for (stamp, id) in spared {
+      self.lru.insert(stamp, id);
+    }
+
+    ret
+  }
+
+  fn free(&mut self, id: BlockId) {
+    if let Some(block) = self.blocks.remove(&id) {
+      if let Some(data) = block.data {
+        self.resident -= data.bytes();
+        self.lru.remove(&block.stamp);
+      }
+
+      // A block on its way out gives up its slot once it gets there
+      if let (Some(slot), false) = (block.slot, block.leaving) {
+        self.free.push(slot);
+      }
+    }
+  }
+}
+
+// Keeps the blocks of every ImageStore made with it, writing the least
+// recently used ones out to a scratch file when they go over the budget.
+// Reading and writing the scratch file, and converting pixels to and from the
+// storage format, happen without the state locked, so threads working on
+// blocks in memory aren't held up by ones waiting on the disk.
+pub struct Pager {
+  state: Mutex<PagerState>,
+  // Signalled whenever a busy block is put back
+  ready: Condvar,
+  scratch: Mutex<Option<Scratch>>,
+}
+
+impl Pager {
+  pub fn new(budget: Option<usize>) -> Self {
+    Self {
+      state: Mutex::new(PagerState {
+        budget,
+        format: PixelFormat::default(),
+        resident: 0,
+        leaving: 0,
+        next_store: 0,
+        clock: 0,
+        blocks: HashMap::new(),
+        lru: BTreeMap::new(),
+        scratch_len: 0,
+        free: Vec::new(),
+      }),
+      ready: Condvar::new(),
+      scratch: Mutex::new(None),
+    }
+  }
+
+  // Nothing is left half-done while the state is locked, so a panic while
+  // it's held doesn't leave anything broken behind
+  fn state(&self) -> MutexGuard<PagerState> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  fn scratch(&self) -> MutexGuard<Option<Scratch>> {
+    self.scratch.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  // Waits until nobody else has the block taken
+  fn wait_ready<'a>(
+    &'a self,
+    mut state: MutexGuard<'a, PagerState>,
+    id: BlockId,
+  ) -> MutexGuard<'a, PagerState> {
+    while state.blocks.get(&id).map_or(false, |b| b.busy) {
+      state = self
+        .ready
+        .wait(state)
+        .unwrap_or_else(PoisonError::into_inner);
+    }
+
+    state
+  }
+
+  // Gets a block's pixels, reading them back from disk if need be.  Blocks
+  // that have never been written read as transparent.
+  fn fetch(
+    &self,
+    id: BlockId,
+    format: PixelFormat,
+    len: usize,
+  ) -> io::Result<Arc<PixelBlock>> {
+    {
+      let mut state = self.wait_ready(self.state(), id);
+      let data = state.blocks.get(&id).and_then(|b| b.data.clone());
+
+      if let Some(data) = data {
+        state.touch(id);
+        return Ok(data);
+      }
+    }
+
+    let data = self.take(id, format, len)?;
+
+    self.put_back(id, data.clone(), false)?;
+
+    Ok(data)
+  }
+
+  // Takes a block's pixels out of the pager, reading them back from disk if
+  // need be, so they can be changed without the state locked.  Anyone else
+  // after the block waits until it's put back.
+  fn take(
+    &self,
+    id: BlockId,
+    format: PixelFormat,
+    len: usize,
+  ) -> io::Result<Arc<PixelBlock>> {
+    let mut state = self.wait_ready(self.state(), id);
+
+    let (data, slot, stamp) = {
+      let block = state.blocks.entry(id).or_insert(Block {
+        data: None,
+        slot: None,
+        dirty: true,
+        busy: false,
+        leaving: false,
+        stamp: 0,
+      });
+
+      block.busy = true;
+
+      (block.data.take(), block.slot, block.stamp)
+    };
+
+    // Taken blocks still count as resident, but can't be evicted
+    if let Some(data) = data {
+      state.lru.remove(&stamp);
+      return Ok(data);
+    }
+
+    drop(state);
+
+    let read = match slot {
+      Some(s) => match *self.scratch() {
+        Some(ref mut scratch) => scratch.read(s, format, len),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "no scratch file")),
+      },
+      None => Ok(PixelBlock::new(format, len)),
+    };
+
+    let mut state = self.state();
+
+    match read {
+      Ok(data) => {
+        state.resident += data.bytes();
+        Ok(Arc::new(data))
+      },
+      Err(e) => {
+        state.blocks.get_mut(&id).unwrap().busy = false;
+        self.ready.notify_all();
+        Err(e)
+      },
+    }
+  }
+
+  // Returns a block from take, then writes out whatever no longer fits the
+  // budget.  If that fails, the blocks stay in memory until the next try.
+  fn put_back(
+    &self,
+    id: BlockId,
+    data: Arc<PixelBlock>,
+    changed: bool,
+  ) -> io::Result<()> {
+    let leaving = {
+      let mut state = self.state();
+
+      {
+        let block = state.blocks.get_mut(&id).unwrap();
+
+        block.data = Some(data);
+        block.busy = false;
+        block.dirty |= changed;
+      }
+
+      state.touch(id);
+      self.ready.notify_all();
+
+      state.evict(Some(id))
+    };
+
+    self.write_out(leaving)
+  }
+
+  // Writes out the blocks evict picked, then drops the ones that haven't
+  // changed in the meantime
+  fn write_out(&self, leaving: Vec<Leaving>) -> io::Result<()> {
+    if leaving.is_empty() {
+      return Ok(());
+    }
+
+    let written: Vec<_> = {
+      let mut scratch = self.scratch();
+
+      if scratch.is_none() {
+        match Scratch::create() {
+          Ok(s) => *scratch = Some(s),
+          Err(e) => return self.finish_leaving(leaving, Err(e)),
+        }
+      }
+
+      let scratch = scratch.as_mut().unwrap();
+
+      leaving
+        .iter()
+        .map(|&(_, ref data, slot)| scratch.write(slot, data))
+        .collect()
+    };
+
+    let mut ret = Ok(());
+
+    for (l, w) in leaving.into_iter().zip(written) {
+      if let Err(e) = self.finish_leaving(vec![l], w) {
+        ret = Err(e);
+      }
+    }
+
+    ret
+  }
+
+  // Settles the blocks write_out was given, once they've been written (or
+  // haven't, if written is an error)
+  fn finish_leaving(
+    &self,
+    leaving: Vec<Leaving>,
+    written: io::Result<()>,
+  ) -> io::Result<()> {
+    let mut state = self.state();
+    let state = &mut *state;
+
+    for (id, data, slot) in leaving {
+      state.leaving -= data.bytes();
+
+      let block = match state.blocks.get_mut(&id) {
+        Some(b) => b,
+        // The block was freed while it was being written
+        None => {
+          state.free.push(slot);
+          continue;
+        },
+      };
+
+      block.leaving = false;
+
+      if written.is_ok() {
+        block.slot = Some(slot);
+      } else {
+        block.dirty = true;
+      }
+
+      // Blocks that are taken go back in the LRU list when they're put back
+      if block.busy {
+        continue;
+      }
+
+      // Ones that were written to since are left in memory
+      if block.dirty {
+        state.clock += 1;
+        state.lru.remove(&block.stamp);
+        state.lru.insert(state.clock, id);
+        block.stamp = state.clock;
+      } else {
+        block.data = None;
+        state.resident -= data.bytes();
+        state.lru.remove(&block.stamp);
+      }
+    }
+
+    written
+  }
+
+  pub fn budget(&self) -> Option<usize> { self.state().budget }
+
+  // If the new budget is smaller, blocks are written out straight away.  If
+  // that fails, they stay in memory until the next try.
+  pub fn set_budget(&self, budget: Option<usize>) {
+    let leaving = {
+      let mut state = self.state();
+
+      state.budget = budget;
+      state.evict(None)
+    };
+
+    if let Err(e) = self.write_out(leaving) {
+      eprintln!("couldn't write image data to disk: {}", e);
+    }
+  }
+
+  pub fn format(&self) -> PixelFormat { self.state().format }
+
+  // Images that already exist keep the format they were made with
+  pub fn set_format(&self, format: PixelFormat) {
+    self.state().format = format;
+  }
+}
+
+// Tells apart the contents of every image, until they're given an id that
+// describes them
+static NEXT_CONTENT: AtomicU64 = AtomicU64::new(1);
+
+// A w-by-h image split into blocks, which may live on disk
+pub struct ImageStore {
+  pager: Arc<Pager>,
+  id: usize,
+  content: AtomicU64,
+  format: PixelFormat,
+  w: u32,
+  h: u32,
+}
+
+impl ImageStore {
+  // The image starts out transparent, in the pager's current format
+  pub fn new(pager: Arc<Pager>, w: u32, h: u32) -> Self {
+    let (id, format) = {
+      let mut state = pager.state();
+
+      state.next_store += 1;
+      (state.next_store, state.format)
+    };
+
+    Self {
+      pager,
+      id,
+      content: AtomicU64::new(NEXT_CONTENT.fetch_add(1, Ordering::SeqCst)),
+      format,
+      w,
+      h,
+    }
+  }
+
+  pub fn w(&self) -> u32 { self.w }
+
+  pub fn h(&self) -> u32 { self.h }
+
+  pub fn pager(&self) -> &Arc<Pager> { &self.pager }
+
+  pub fn format(&self) -> PixelFormat { self.format }
+
+  // Changes whenever the image is written to, so anything worked out from its
+  // pixels can be kept until then.  Two images with the same id hold the same
+  // pixels.
+  pub fn content_id(&self) -> u64 { self.content.load(Ordering::SeqCst) }
+
+  // For images whose pixels are known to be the same as any other image given
+  // the same id, such as a render of the same settings.  It lasts until the
+  // image is next written to.
+  pub fn set_content_id(&self, id: u64) {
+    self.content.store(id, Ordering::SeqCst);
+  }
+
+  fn blocks_x(&self) -> u32 { (self.w + BLOCK_SIZE - 1) / BLOCK_SIZE }
+
+  // The region of the image the block covers, as (x, y, w, h)
+  fn block_rect(&self, i: usize) -> (u32, u32, u32, u32) {
+    let x = (i as u32 % self.blocks_x()) * BLOCK_SIZE;
+    let y = (i as u32 / self.blocks_x()) * BLOCK_SIZE;
+
+    (
+      x,
+      y,
+      cmp::min(BLOCK_SIZE, self.w - x),
+      cmp::min(BLOCK_SIZE, self.h - y),
+    )
+  }
+
+  // Which block a pixel is in, and where in the block it is
+  pub fn locate(&self, x: u32, y: u32) -> (usize, usize) {
+    let i = (y / BLOCK_SIZE * self.blocks_x() + x / BLOCK_SIZE) as usize;
+    let (_, _, bw, _) = self.block_rect(i);
+
+    (i, ((y % BLOCK_SIZE) * bw + x % BLOCK_SIZE) as usize)
+  }
+
+  // Nothing should be written through the block this returns, since other
+  // readers may be sharing it
+  pub fn block(&self, i: usize) -> Arc<PixelBlock> {
+    let (_, _, bw, bh) = self.block_rect(i);
+
+    self
+      .pager
+      .fetch((self.id, i), self.format, (bw * bh) as usize)
+      .unwrap_or_else(|e| panic!("couldn't read image data from disk: {}", e))
+  }
+
+  // Calls f with each block overlapping the given region, along with the
+  // block's own region
+  fn each_block<F>(&self, x: u32, y: u32, w: u32, h: u32, mut f: F)
+  where
+    F: FnMut(usize, (u32, u32, u32, u32)),
+  {
+    if w == 0 || h == 0 {
+      return;
+    }
+
+    let bx0 = x / BLOCK_SIZE;
+    let by0 = y / BLOCK_SIZE;
+    let bx1 = (x + w - 1) / BLOCK_SIZE;
+    let by1 = (y + h - 1) / BLOCK_SIZE;
+
+    for by in by0..by1 + 1 {
+      for bx in bx0..bx1 + 1 {
+        let i = (by * self.blocks_x() + bx) as usize;
+
+        f(i, self.block_rect(i));
+      }
+    }
+  }
+
+  pub fn read_rect(&self, x: u32, y: u32, w: u32, h: u32) -> Vec<Pixel> {
+    let mut ret = vec![Pixel::new(0.0, 0.0, 0.0, 0.0); (w * h) as usize];
+
+    self.each_block(x, y, w, h, |i, (bx, by, bw, bh)| {
+      let block = self.block(i);
+
+      for r in cmp::max(y, by)..cmp::min(y + h, by + bh) {
+        for c in cmp::max(x, bx)..cmp::min(x + w, bx + bw) {
+          ret[((r - y) * w + c - x) as usize] =
+            block.get(((r - by) * bw + c - bx) as usize);
+        }
+      }
+    });
+
+    ret
+  }
+
+  pub fn write_rect(&self, x: u32, y: u32, w: u32, h: u32, px: &[Pixel]) {
+    // Checked up front, since a panic while a block is taken would leave it
+    // taken for good
+    assert!(px.len() >= (w * h) as usize, "not enough pixels to write");
+
+    self.content.store(
+      NEXT_CONTENT.fetch_add(1, Ordering::SeqCst),
+      Ordering::SeqCst,
+    );
+
+    self.each_block(x, y, w, h, |i, (bx, by, bw, bh)| {
+      let id = (self.id, i);
+
+      let mut block = self
+        .pager
+        .take(id, self.format, (bw * bh) as usize)
+        .unwrap_or_else(|e| {
+          panic!("couldn't read image data from disk: {}", e)
+        });
+
+      {
+        // Readers holding on to the old pixels keep them
+        let data = Arc::make_mut(&mut block);
+
+        for r in cmp::max(y, by)..cmp::min(y + h, by + bh) {
+          for c in cmp::max(x, bx)..cmp::min(x + w, bx + bw) {
+            data.set(
+              ((r - by) * bw + c - bx) as usize,
+              px[((r - y) * w + c - x) as usize],
+            );
+          }
+        }
+      }
+
+      self
+        .pager
+        .put_back(id, block, true)
+        .unwrap_or_else(|e| panic!("couldn't write image data to disk: {}", e));
+    });
+  }
+
+  pub fn to_pixel_buf(&self) -> PixelBuf {
+    PixelBuf::new(self.w, self.h, self.read_rect(0, 0, self.w, self.h))
+  }
+}
+
+impl Drop for ImageStore {
+  fn drop(&mut self) {
+    let mut state = self.pager.state();
+
+    let ids: Vec<_> = state
+      .blocks
+      .keys()
+      .filter(|&&(s, _)| s == self.id)
+      .cloned()
+      .collect();
+
+    for id in ids {
+      state.free(id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const POS_INF: u16 = 0x7c00;
+
+  #[test]
+  fn every_f16_survives_a_round_trip() {
+    for h in 0..=0xffffu16 {
+      let q = f16_to_f32(h);
+
+      if q.is_nan() {
+        assert!(f32_to_f16(q) & 0x7fff > POS_INF, "{:#06x}", h);
+      } else {
+        assert_eq!(f32_to_f16(q), h, "{:#06x} -> {}", h, q);
+      }
+    }
+  }
+
+  #[test]
+  fn subnormals() {
+    let tiny = 2.0f32.powi(-24);
+
+    assert_eq!(f32_to_f16(tiny), 0x0001);
+    assert_eq!(f16_to_f32(0x0001), tiny);
+    assert_eq!(f32_to_f16(tiny * 1023.0), 0x03ff);
+    assert_eq!(f32_to_f16(-tiny * 3.0), 0x8003);
+
+    // Halfway to the next subnormal rounds to even
+    assert_eq!(f32_to_f16(tiny * 2.5), 0x0002);
+    assert_eq!(f32_to_f16(tiny * 3.5), 0x0004);
+
+    // The largest subnormal rounds up into the normals
+    assert_eq!(f32_to_f16(tiny * 1023.5), 0x0400);
+  }
+
+  #[test]
+  fn halfway_to_the_smallest_subnormal() {
+    let tie = 2.0f32.powi(-25);
+
+    // The tie goes to zero, which is even, but anything past it rounds up
+    assert_eq!(f32_to_f16(tie), 0x0000);
+    assert_eq!(f32_to_f16(-tie), 0x8000);
+    assert_eq!(f32_to_f16(f32::from_bits(tie.to_bits() + 1)), 0x0001);
+    assert_eq!(f32_to_f16(tie * 1.5), 0x0001);
+    assert_eq!(f32_to_f16(2.0f32.powi(-26)), 0x0000);
+  }
+
+  #[test]
+  fn overflow_to_infinity() {
+    assert_eq!(f32_to_f16(65504.0), 0x7bff);
+    assert_eq!(f16_to_f32(0x7bff), 65504.0);
+    assert_eq!(f32_to_f16(65519.0), 0x7bff);
+
+    // Halfway between the largest half and the next power of two
+    assert_eq!(f32_to_f16(65520.0), POS_INF);
+    assert_eq!(f32_to_f16(-65520.0), 0x8000 | POS_INF);
+    assert_eq!(f32_to_f16(1e10), POS_INF);
+    assert_eq!(f32_to_f16(f32::INFINITY), POS_INF);
+    assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0x8000 | POS_INF);
+    assert_eq!(f16_to_f32(POS_INF), f32::INFINITY);
+  }
+
+  #[test]
+  fn nan() {
+    let h = f32_to_f16(f32::NAN);
+
+    assert_eq!(h & POS_INF, POS_INF);
+    assert_ne!(h & 0x3ff, 0);
+    assert!(f16_to_f32(h).is_nan());
+  }
+
+  #[test]
+  fn signed_zeros() {
+    assert_eq!(f32_to_f16(0.0), 0x0000);
+    assert_eq!(f32_to_f16(-0.0), 0x8000);
+    assert_eq!(f16_to_f32(0x0000).to_bits(), 0.0f32.to_bits());
+    assert_eq!(f16_to_f32(0x8000).to_bits(), (-0.0f32).to_bits());
+  }
+
+  fn block(sample: SampleFormat, layout: Layout, px: &[Pixel]) -> PixelBlock {
+    let mut ret = PixelBlock::new(PixelFormat { sample, layout }, px.len());
+
+    for (i, &p) in px.iter().enumerate() {
+      ret.set(i, p);
+    }
+
+    ret
+  }
+
+  #[test]
+  fn u16_clamps_to_the_unit_range() {
+    let px = [
+      Pixel::new(-0.5, 0.0, 1.0, 1.5),
+      Pixel::new(0.5, -1e10, 1e10, f32::INFINITY),
+    ];
+
+    let b = block(SampleFormat::U16, Layout::Interleaved, &px);
+
+    assert_eq!(b.get(0), Pixel::new(0.0, 0.0, 1.0, 1.0));
+    assert_eq!(b.get(1), Pixel::new(32768.0 / 65535.0, 0.0, 1.0, 1.0));
+  }
+
+  #[test]
+  fn layouts_hold_the_same_values() {
+    let px: Vec<_> = (0..37)
+      .map(|i| {
+        let i = i as Quantum;
+
+        Pixel::new(i / 36.0, 1.0 - i / 36.0, i * 0.37 - 3.0, (i * 0.1).sin())
+      })
+      .collect();
+
+    for &sample in SampleFormat::all() {
+      let a = block(sample, Layout::Interleaved, &px);
+      let b = block(sample, Layout::Planar, &px);
+
+      for i in 0..px.len() {
+        assert_eq!(a.get(i), b.get(i), "{:?} pixel {}", sample, i);
+      }
+
+      // They should also come back the same from disk
+      let a2 = PixelBlock::from_bytes(a.format, a.len, &a.to_bytes());
+      let b2 = PixelBlock::from_bytes(b.format, b.len, &b.to_bytes());
+
+      for i in 0..px.len() {
+        assert_eq!(a2.get(i), b2.get(i), "{:?} pixel {}", sample, i);
+        assert_eq!(a.get(i), a2.get(i), "{:?} pixel {}", sample, i);
+      }
+    }
+  }
+}
@@ -1,34 +1,93 @@
 use std::{
-  collections::HashSet,
-  sync::{
-    mpsc::{channel, Sender},
-    Arc, Mutex,
-  },
+  cmp::Ordering,
+  collections::VecDeque,
+  sync::{Arc, Condvar, Mutex},
   thread::{self, JoinHandle},
 };
 
-pub struct ThreadPool<T>
+struct State<T> {
+  tasks: VecDeque<T>,
+  stopping: bool,
+}
+
+struct Shared<T> {
+  state: Mutex<State<T>>,
+  cond: Condvar,
+}
+
+// A handle for adding and removing tasks, which can be cloned and passed around
+// freely (including into the tasks themselves)
+pub struct Queue<T>
 where
   T: Send + 'static,
 {
-  task_tx: Sender<T>,
-  sched_tx: Sender<SchedMessage>,
-  sched_handle: JoinHandle<()>,
+  shared: Arc<Shared<T>>,
 }
 
-enum SchedMessage {
-  Sleep(usize),
-  WakeAll,
-  ForceWakeAll,
-  Stop,
+impl<T> Clone for Queue<T>
+where
+  T: Send + 'static,
+{
+  fn clone(&self) -> Self {
+    Self {
+      shared: self.shared.clone(),
+    }
+  }
 }
 
-enum WorkerMessage {
-  Continue,
-  Stop,
+impl<T> Queue<T>
+where
+  T: Send + 'static,
+{
+  pub fn push(&self, task: T) { self.extend(Some(task)); }
+
+  pub fn extend<I>(&self, tasks: I)
+  where
+    I: IntoIterator<Item = T>,
+  {
+    self.shared.state.lock().unwrap().tasks.extend(tasks);
+
+    self.shared.cond.notify_all();
+  }
+
+  // Drops every queued task matching pred, and returns how many were dropped.
+  // Tasks that have already been picked up by a worker are unaffected.
+  pub fn remove<P>(&self, mut pred: P) -> usize
+  where
+    P: FnMut(&T) -> bool,
+  {
+    let mut state = self.shared.state.lock().unwrap();
+
+    let len = state.tasks.len();
+
+    state.tasks.retain(|t| !pred(t));
+
+    len - state.tasks.len()
+  }
+
+  // Reorders the queued tasks.  The sort is stable, so tasks that compare equal
+  // keep their relative order.
+  pub fn sort_by<F>(&self, compare: F)
+  where
+    F: FnMut(&T, &T) -> Ordering,
+  {
+    let mut state = self.shared.state.lock().unwrap();
+
+    let mut tasks: Vec<_> = state.tasks.drain(..).collect();
+
+    tasks.sort_by(compare);
+
+    state.tasks.extend(tasks);
+  }
 }
 
-use self::{SchedMessage as SM, WorkerMessage as WM};
+pub struct ThreadPool<T>
+where
+  T: Send + 'static,
+{
+  queue: Queue<T>,
+  workers: Vec<JoinHandle<()>>,
+}
 
 impl<T> ThreadPool<T>
 where
@@ -40,118 +99,72 @@ where
     C: Send + 'static,
     F: Fn(usize, &C, T) -> () + Clone + Send + 'static,
   {
-    let (task_tx, task_rx) = channel();
-
-    let task_rx = Arc::new(Mutex::new(task_rx));
-
-    let (sched_tx, sched_rx) = channel();
-
-    let workers: Vec<_> = closures
+    let queue = Queue {
+      shared: Arc::new(Shared {
+        state: Mutex::new(State {
+          tasks: VecDeque::new(),
+          stopping: false,
+        }),
+        cond: Condvar::new(),
+      }),
+    };
+
+    let workers = closures
       .into_iter()
       .enumerate()
       .map(|(id, closure)| {
-        let (tx, rx) = channel::<WM>();
-
-        let task_rx = task_rx.clone();
-        let sched_tx = sched_tx.clone();
+        let shared = queue.shared.clone();
         let f = f.clone();
 
-        let handle = thread::spawn(move || loop {
-          match rx.recv().expect("failed to receive WM") {
-            WM::Continue => loop {
-              match {
-                let task_rx = task_rx.lock().unwrap();
-                task_rx.try_recv()
-              } {
-                Ok(t) => f(id, &closure, t),
-                Err(_) => break,
+        thread::spawn(move || loop {
+          let task = {
+            let mut state = shared.state.lock().unwrap();
+
+            loop {
+              if let Some(t) = state.tasks.pop_front() {
+                break Some(t);
+              }
+
+              if state.stopping {
+                break None;
               }
-            },
-            WM::Stop => break,
-          }
 
-          sched_tx
-            .send(SM::Sleep(id))
-            .expect("failed to send SM::Sleep");
-        });
+              state = shared.cond.wait(state).unwrap();
+            }
+          };
 
-        (handle, tx)
+          match task {
+            Some(t) => f(id, &closure, t),
+            None => break,
+          }
+        })
       })
       .collect();
 
-    let sched_handle = thread::spawn(move || {
-      let mut awake: HashSet<usize> = HashSet::new();
-      let mut stopping = false;
-
-      loop {
-        match sched_rx.recv().expect("failed to receive SM") {
-          SM::Sleep(i) => {
-            awake.remove(&i);
-
-            if awake.len() == 0 && stopping {
-              for (_, tx) in &workers {
-                tx.send(WM::Stop).expect("failed to send WM::Stop");
-              }
+    Self { queue, workers }
+  }
 
-              for (handle, _) in workers {
-                handle.join().expect("a worker died");
-              }
+  pub fn queue(&self) -> &Queue<T> { &self.queue }
+}
 
-              break;
-            }
-          },
-          SM::WakeAll => {
-            for i in 0..workers.len() {
-              if awake.insert(i) {
-                workers[i]
-                  .1
-                  .send(WM::Continue)
-                  .expect("failed to send WM::Continue");
-              }
-            }
-          },
-          SM::ForceWakeAll => {
-            for i in 0..workers.len() {
-              workers[i]
-                .1
-                .send(WM::Continue)
-                .expect("failed to force-send WM::Continue");
-            }
-          },
-          SM::Stop => stopping = true,
-        }
-      }
-    });
+impl<T> Drop for ThreadPool<T>
+where
+  T: Send + 'static,
+{
+  fn drop(&mut self) {
+    {
+      let mut state = self.queue.shared.state.lock().unwrap();
 
-    Self {
-      task_tx,
-      sched_tx,
-      sched_handle,
+      state.tasks.clear();
+      state.stopping = true;
     }
-  }
 
-  // TODO: make this return a Result maybe?
-  pub fn queue(&self, task: T) {
-    self.task_tx.send(task).expect("failed to send task");
-    self
-      .sched_tx
-      .send(SM::WakeAll)
-      .expect("failed to send SM::WakeAll");
-  }
+    self.queue.shared.cond.notify_all();
 
-  pub fn join(self) {
-    self
-      .sched_tx
-      .send(SM::Stop)
-      .expect("failed to send SM::Stop");
-
-    // NB: I'm not just using SM::WakeAll because if a worker panics, it will
-    //     never send its WM::Sleep message and will never be awoken, causing
-    //     ThreadPool::join to hang
-    self
-      .sched_tx
-      .send(SM::ForceWakeAll)
-      .expect("failed to send SM::ForceWakeAll");
-    self.sched_handle.join().expect("scheduler died");
+    for worker in self.workers.drain(..) {
+      // NB: a worker that panicked has nothing left to clean up, so there's no
+      //     reason to propagate the panic here
+      worker.join().ok();
+    }
   }
 }
@@ -0,0 +1,126 @@
+use render::Pixel;
+use std::{
+  collections::{BTreeMap, HashMap},
+  mem,
+  sync::{Arc, Mutex},
+};
+
+struct Entry {
+  pixels: Arc<Vec<Pixel>>,
+  stamp: u64,
+}
+
+struct CacheState {
+  // How many bytes of pixels may be kept
+  limit: usize,
+  used: usize,
+  clock: u64,
+  entries: HashMap<u64, Entry>,
+  // The entries by when they were last used, oldest first
+  lru: BTreeMap<u64, u64>,
+}
+
+impl CacheState {
+  fn remove(&mut self, key: u64) {
+    if let Some(entry) = self.entries.remove(&key) {
+      self.used -= entry.pixels.len() * mem::size_of::<Pixel>();
+      self.lru.remove(&entry.stamp);
+    }
+  }
+
+  fn evict(&mut self) {
+    while self.used > self.limit {
+      let key = match self.lru.iter().next() {
+        Some((_, &k)) => k,
+        None => break,
+      };
+
+      self.remove(key);
+    }
+  }
+}
+
+// Finished tiles, so going back to settings that were rendered before doesn't
+// mean rendering them again.  The least recently used tiles are dropped when
+// the cache goes over its limit.
+pub struct TileCache {
+  state: Mutex<CacheState>,
+}
+
+impl TileCache {
+  pub fn new(limit: usize) -> Self {
+    Self {
+      state: Mutex::new(CacheState {
+        limit,
+        used: 0,
+        clock: 0,
+        entries: HashMap::new(),
+        lru: BTreeMap::new(),
+      }),
+    }
+  }
+
+  pub fn limit(&self) -> usize { self.state.lock().unwrap().limit }
+
+  pub fn set_limit(&self, limit: usize) {
+    let mut state = self.state.lock().unwrap();
+
+    state.limit = limit;
+    state.evict();
+  }
+
+  pub fn clear(&self) {
+    let mut state = self.state.lock().unwrap();
+
+    state.entries.clear();
+    state.lru.clear();
+    state.used = 0;
+  }
+
+  pub fn get(&self, key: u64) -> Option<Arc<Vec<Pixel>>> {
+    let mut state = self.state.lock().unwrap();
+
+    state.clock += 1;
+
+    let stamp = state.clock;
+
+    let (pixels, old) = match state.entries.get_mut(&key) {
+      Some(entry) => {
+        let old = entry.stamp;
+
+        entry.stamp = stamp;
+        (entry.pixels.clone(), old)
+      },
+      None => return None,
+    };
+
+    state.lru.remove(&old);
+    state.lru.insert(stamp, key);
+
+    Some(pixels)
+  }
+
+  // Tiles bigger than the whole cache aren't kept
+  pub fn insert(&self, key: u64, pixels: &[Pixel]) {
+    let bytes = pixels.len() * mem::size_of::<Pixel>();
+
+    if bytes > self.limit() {
+      return;
+    }
+
+    // Copied before locking, so other workers aren't kept waiting
+    let pixels = Arc::new(pixels.to_vec());
+    let mut state = self.state.lock().unwrap();
+
+    state.remove(key);
+    state.clock += 1;
+
+    let stamp = state.clock;
+
+    state.entries.insert(key, Entry { pixels, stamp });
+
+    state.lru.insert(stamp, key);
+    state.used += bytes;
+    state.evict();
+  }
+}
@@ -0,0 +1,133 @@
+use render::Tile;
+
+// Decides which tiles get rendered first.  Tiles with a lower priority are
+// rendered sooner, and ties are broken in scanline order.
+pub trait TileOrder {
+  // w and h are the size of the full image.  tile.bounds() is in the same
+  // coordinates, even for preview tiles.
+  fn priority(&self, tile: &Tile, w: u32, h: u32) -> f64;
+}
+
+fn center(tile: &Tile) -> (f64, f64) {
+  let (x, y, w, h) = tile.bounds();
+
+  ((x + w / 2) as f64, (y + h / 2) as f64)
+}
+
+fn dist((ax, ay): (f64, f64), (bx, by): (f64, f64)) -> f64 {
+  ((ax - bx).powi(2) + (ay - by).powi(2)).sqrt()
+}
+
+pub struct CenterOut;
+
+impl TileOrder for CenterOut {
+  fn priority(&self, tile: &Tile, w: u32, h: u32) -> f64 {
+    dist(center(tile), ((w / 2) as f64, (h / 2) as f64))
+  }
+}
+
+pub struct Scanline;
+
+impl TileOrder for Scanline {
+  fn priority(&self, tile: &Tile, w: u32, _: u32) -> f64 {
+    let (x, y, _, _) = tile.bounds();
+
+    y as f64 * w as f64 + x as f64
+  }
+}
+
+// Walks the image along a Hilbert curve, so each tile is usually next to the
+// one rendered before it
+pub struct Hilbert;
+
+// Maps (x, y) to its distance along a Hilbert curve filling an n-by-n square,
+// where n is a power of two
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+  let mut d = 0;
+  let mut s = n / 2;
+
+  while s > 0 {
+    let rx = x & s != 0;
+    let ry = y & s != 0;
+
+    d += s as u64 * s as u64 * ((3 * rx as u64) ^ ry as u64);
+
+    if !ry {
+      if rx {
+        x = n - 1 - x;
+        y = n - 1 - y;
+      }
+
+      let t = x;
+      x = y;
+      y = t;
+    }
+
+    s /= 2;
+  }
+
+  d
+}
+
+impl TileOrder for Hilbert {
+  fn priority(&self, tile: &Tile, w: u32, h: u32) -> f64 {
+    let (x, y, tw, th) = tile.bounds();
+    let n = w.max(h).next_power_of_two();
+
+    hilbert_index(n, x + tw / 2, y + th / 2) as f64
+  }
+}
+
+// Renders outward from a point, such as the mouse cursor
+pub struct AroundPoint {
+  x: u32,
+  y: u32,
+}
+
+impl AroundPoint {
+  pub fn new(x: u32, y: u32) -> Self { Self { x, y } }
+}
+
+impl TileOrder for AroundPoint {
+  fn priority(&self, tile: &Tile, _: u32, _: u32) -> f64 {
+    dist(center(tile), (self.x as f64, self.y as f64))
+  }
+}
+
+// Renders every tile that overlaps a rectangle (such as the part of the image
+// that's scrolled into view) before any tile that doesn't, working outward from
+// the middle of the rectangle
+pub struct ViewportFirst {
+  x: u32,
+  y: u32,
+  w: u32,
+  h: u32,
+}
+
+impl ViewportFirst {
+  pub fn new(x: u32, y: u32, w: u32, h: u32) -> Self { Self { x, y, w, h } }
+}
+
+impl TileOrder for ViewportFirst {
+  fn priority(&self, tile: &Tile, w: u32, h: u32) -> f64 {
+    let (x, y, tw, th) = tile.bounds();
+
+    let d = dist(
+      center(tile),
+      ((self.x + self.w / 2) as f64, (self.y + self.h / 2) as f64),
+    );
+
+    let visible = x < self.x + self.w
+      && self.x < x + tw
+      && y < self.y + self.h
+      && self.y < y + th;
+
+    // No two points in the image are further apart than w + h, so this puts
+    // every hidden tile after every visible one
+    if visible {
+      d
+    } else {
+      d + (w + h) as f64
+    }
+  }
+}
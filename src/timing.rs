@@ -0,0 +1,225 @@
+use std::{collections::HashMap, fmt, fs, path::Path, time::Duration};
+
+// How long a single call to RenderProc::process_tile took
+#[derive(Clone, Debug)]
+pub struct TileTiming {
+  // The region of the full-size image the tile covers
+  pub x: u32,
+  pub y: u32,
+  pub w: u32,
+  pub h: u32,
+  // Which proc in the pipeline processed the tile, and on which pass
+  pub stage: usize,
+  pub pass: u32,
+  pub wid: usize,
+  pub preview: bool,
+  // Which render the tile was from, as in TaggedTile::generation
+  pub generation: usize,
+  // Set if the render was cancelled before the proc returned, in which case it
+  // may have stopped early
+  pub cancelled: bool,
+  pub failed: bool,
+  // When the proc was called, relative to the start of the render
+  pub start: Duration,
+  pub time: Duration,
+}
+
+pub fn secs(d: Duration) -> f64 {
+  d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+pub struct Summary {
+  // From the start of the render until the last tile finished
+  pub total: Duration,
+  // Full-size tiles that finished without being cancelled
+  pub ntiles: usize,
+  pub slowest: Option<TileTiming>,
+}
+
+impl Summary {
+  pub fn tiles_per_sec(&self) -> f64 {
+    match secs(self.total) {
+      t if t > 0.0 => self.ntiles as f64 / t,
+      _ => 0.0,
+    }
+  }
+}
+
+impl fmt::Display for Summary {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      fmt,
+      "{:.2} s, {:.0} tiles/s",
+      secs(self.total),
+      self.tiles_per_sec()
+    )?;
+
+    if let Some(ref t) = self.slowest {
+      write!(
+        fmt,
+        ", slowest {:.1} ms at ({}, {})",
+        secs(t.time) * 1e3,
+        t.x,
+        t.y
+      )?;
+    }
+
+    Ok(())
+  }
+}
+
+// What Timings::save writes, going by the file extension
+enum Format {
+  Csv,
+  Json,
+}
+
+impl Format {
+  fn from_path(path: &Path) -> Result<Self, String> {
+    let ext = path
+      .extension()
+      .and_then(|e| e.to_str())
+      .unwrap_or("")
+      .to_lowercase();
+
+    match ext.as_str() {
+      "csv" => Ok(Format::Csv),
+      "json" => Ok(Format::Json),
+      _ => Err(format!(
+        "timings can't be saved as .{} (try .csv, .json)",
+        ext
+      )),
+    }
+  }
+}
+
+// Every tile timing from one render
+pub struct Timings {
+  tiles: Vec<TileTiming>,
+}
+
+impl Timings {
+  pub fn new() -> Self { Self { tiles: Vec::new() } }
+
+  pub fn tiles(&self) -> &Vec<TileTiming> { &self.tiles }
+
+  pub fn clear(&mut self) { self.tiles.clear(); }
+
+  pub fn push(&mut self, timing: TileTiming) { self.tiles.push(timing); }
+
+  pub fn summary(&self) -> Summary {
+    let full = || {
+      self
+        .tiles
+        .iter()
+        .filter(|t| !t.preview && !t.cancelled && !t.failed)
+    };
+
+    Summary {
+      total: self
+        .tiles
+        .iter()
+        .map(|t| t.start + t.time)
+        .max()
+        .unwrap_or_default(),
+      ntiles: full().count(),
+      slowest: full().max_by_key(|t| t.time).cloned(),
+    }
+  }
+
+  // The total time spent on each region of the full-size image across every
+  // stage of the pipeline, as (x, y, w, h, time)
+  pub fn heat(&self) -> Vec<(u32, u32, u32, u32, Duration)> {
+    let mut regions = HashMap::new();
+
+    for t in self.tiles.iter().filter(|t| !t.preview) {
+      *regions
+        .entry((t.x, t.y, t.w, t.h))
+        .or_insert_with(Duration::default) += t.time;
+    }
+
+    regions
+      .into_iter()
+      .map(|((x, y, w, h), time)| (x, y, w, h, time))
+      .collect()
+  }
+
+  pub fn to_csv(&self) -> String {
+    let mut ret = "x,y,w,h,stage,pass,worker,preview,cancelled,failed,\
+                   start_ms,time_ms\n"
+      .to_string();
+
+    for t in &self.tiles {
+      ret.push_str(&format!(
+        "{},{},{},{},{},{},{},{},{},{},{:.3},{:.3}\n",
+        t.x,
+        t.y,
+        t.w,
+        t.h,
+        t.stage,
+        t.pass,
+        t.wid,
+        t.preview,
+        t.cancelled,
+        t.failed,
+        secs(t.start) * 1e3,
+        secs(t.time) * 1e3,
+      ));
+    }
+
+    ret
+  }
+
+  pub fn to_json(&self) -> String {
+    let summary = self.summary();
+
+    let tiles: Vec<_> = self
+      .tiles
+      .iter()
+      .map(|t| {
+        format!(
+          "    {{\"x\": {}, \"y\": {}, \"w\": {}, \"h\": {}, \"stage\": {}, \
+           \"pass\": {}, \"worker\": {}, \"preview\": {}, \
+           \"cancelled\": {}, \"failed\": {}, \"start_ms\": {:.3}, \
+           \"time_ms\": {:.3}}}",
+          t.x,
+          t.y,
+          t.w,
+          t.h,
+          t.stage,
+          t.pass,
+          t.wid,
+          t.preview,
+          t.cancelled,
+          t.failed,
+          secs(t.start) * 1e3,
+          secs(t.time) * 1e3,
+        )
+      })
+      .collect();
+
+    format!(
+      "{{\n  \"total_ms\": {:.3},\n  \"tiles_per_sec\": {:.3},\n  \
+       \"tiles\": [\n{}\n  ]\n}}\n",
+      secs(summary.total) * 1e3,
+      summary.tiles_per_sec(),
+      tiles.join(",\n")
+    )
+  }
+
+  // Makes sure save will know what to write to this path, so a typo can be
+  // caught before rendering anything
+  pub fn check_path(path: &Path) -> Result<(), String> {
+    Format::from_path(path).map(|_| ())
+  }
+
+  // Writes CSV or JSON, depending on the file extension
+  pub fn save(&self, path: &Path) -> Result<(), String> {
+    let data = match Format::from_path(path)? {
+      Format::Csv => self.to_csv(),
+      Format::Json => self.to_json(),
+    };
+
+    fs::write(path, data).map_err(|e| e.to_string())
+  }
+}
@@ -0,0 +1,782 @@
+use cli;
+use color::ColorSpace;
+use filters::{self, ctor, params::*, ArcFilter, ArcProc, FilterCtor};
+use render::{self, Analysis, CancelTok, Pixel, PixelBuf, RenderProc, Tile};
+use std::{
+  any::{Any, TypeId},
+  io::{self, BufReader, BufWriter, Read, Write},
+  panic::{self, AssertUnwindSafe},
+  path::PathBuf,
+  process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex,
+  },
+  thread,
+};
+use storage::{ImageStore, Pager};
+
+// Sent to a worker
+const MSG_TILE: u8 = 0;
+const MSG_CANCEL: u8 = 1;
+
+// Sent back by a worker
+const MSG_PROGRESS: u8 = 0;
+const MSG_DONE: u8 = 1;
+const MSG_FAILED: u8 = 2;
+
+fn bad_data(msg: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+  let mut buf = [0; 1];
+
+  r.read_exact(&mut buf)?;
+
+  Ok(buf[0])
+}
+
+fn write_u32<W: Write>(w: &mut W, n: u32) -> io::Result<()> {
+  w.write_all(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+  let mut buf = [0; 4];
+
+  r.read_exact(&mut buf)?;
+
+  Ok(
+    buf[0] as u32
+      | (buf[1] as u32) << 8
+      | (buf[2] as u32) << 16
+      | (buf[3] as u32) << 24,
+  )
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+  write_u32(w, s.len() as u32)?;
+  w.write_all(s.as_bytes())
+}
+
+fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+  let mut buf = vec![0; read_u32(r)? as usize];
+
+  r.read_exact(&mut buf)?;
+
+  String::from_utf8(buf).map_err(|_| bad_data("invalid string"))
+}
+
+fn write_pixel<W: Write>(w: &mut W, px: Pixel) -> io::Result<()> {
+  for i in 0..4 {
+    write_u32(w, px[i].to_bits())?;
+  }
+
+  Ok(())
+}
+
+fn read_pixels<R: Read>(r: &mut R, n: usize) -> io::Result<Vec<Pixel>> {
+  let mut ret = Vec::with_capacity(n);
+
+  for _ in 0..n {
+    let mut px = Pixel::new(0.0, 0.0, 0.0, 0.0);
+
+    for i in 0..4 {
+      px[i] = f32::from_bits(read_u32(r)?);
+    }
+
+    ret.push(px);
+  }
+
+  Ok(ret)
+}
+
+// Whether an optional value follows
+fn read_flag<R: Read>(r: &mut R) -> io::Result<bool> {
+  match read_u8(r)? {
+    0 => Ok(false),
+    1 => Ok(true),
+    _ => Err(bad_data("invalid flag")),
+  }
+}
+
+// A parameter's value as --param would take it.  Image parameters are left
+// out, since their pixels are sent with each tile as its extra inputs.
+fn param_value(val: &ParamVal) -> Option<String> {
+  match val {
+    ParamVal::Switch(b) => Some(b.get().to_string()),
+    ParamVal::SpinInt(i) => Some(i.get().to_string()),
+    ParamVal::RangedInt(r) => Some(r.get().to_string()),
+    ParamVal::RangedFloat(r) => Some(r.get().to_string()),
+    ParamVal::Image(_) => None,
+  }
+}
+
+// A process started with its pool's command, which renders the tiles sent down
+// its stdin
+struct Worker {
+  child: Child,
+  stdin: BufWriter<ChildStdin>,
+  stdout: BufReader<ChildStdout>,
+}
+
+impl Worker {
+  fn spawn(program: &PathBuf, args: &[String]) -> io::Result<Self> {
+    let mut child = Command::new(program)
+      .args(args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .spawn()?;
+
+    let stdin = BufWriter::new(child.stdin.take().unwrap());
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+
+    Ok(Self {
+      child,
+      stdin,
+      stdout,
+    })
+  }
+
+  // Sends a tile and waits for its pixels, passing on the worker's progress
+  // reports.  The tile is written straight down the pipe rather than put
+  // together in memory first.  The outer error means the worker is gone, and
+  // the inner one that the proc panicked.
+  fn render(
+    &mut self,
+    proc: &IsolatedProc,
+    tile: &Tile,
+    cancel_tok: &CancelTok,
+  ) -> io::Result<Result<Vec<Pixel>, String>> {
+    self.stdin.write_all(&[MSG_TILE])?;
+    proc.write_tile(&mut self.stdin, tile)?;
+    self.stdin.flush()?;
+
+    let mut cancelled = false;
+
+    loop {
+      match read_u8(&mut self.stdout)? {
+        MSG_PROGRESS => {
+          cancel_tok.set_progress(f32::from_bits(read_u32(&mut self.stdout)?));
+
+          // The worker can only be told to stop in between progress reports,
+          // since that's the only time this side isn't blocked on it
+          if cancel_tok.cancelled() && !cancelled {
+            cancelled = true;
+
+            self.stdin.write_all(&[MSG_CANCEL])?;
+            self.stdin.flush()?;
+          }
+        },
+        MSG_DONE => {
+          let n = read_u32(&mut self.stdout)? as usize;
+
+          return Ok(Ok(read_pixels(&mut self.stdout, n)?));
+        },
+        MSG_FAILED => return Ok(Err(read_str(&mut self.stdout)?)),
+        _ => return Err(bad_data("unexpected message from worker")),
+      }
+    }
+  }
+
+  // Makes sure the worker is gone, and describes how it went
+  fn exit_status(&mut self) -> String {
+    self.child.kill().ok();
+
+    match self.child.wait() {
+      Ok(status) => status.to_string(),
+      Err(e) => e.to_string(),
+    }
+  }
+}
+
+impl Drop for Worker {
+  fn drop(&mut self) { self.exit_status(); }
+}
+
+// Worker processes that aren't rendering anything at the moment.  There are
+// never more of them than the renderer has threads, since each thread only
+// waits on one at a time.
+pub struct WorkerPool {
+  program: PathBuf,
+  args: Vec<String>,
+  idle: Mutex<Vec<Worker>>,
+}
+
+impl WorkerPool {
+  // Workers are started by running program with args, which should end up in
+  // worker_main with the same filters the renderer has.  Ingot's own binary
+  // does that for `ingot worker`.
+  pub fn new(program: PathBuf, args: Vec<String>) -> Self {
+    Self {
+      program,
+      args,
+      idle: Mutex::new(Vec::new()),
+    }
+  }
+
+  fn take(&self) -> io::Result<Worker> {
+    let worker = self.idle.lock().unwrap().pop();
+
+    match worker {
+      Some(w) => Ok(w),
+      None => Worker::spawn(&self.program, &self.args),
+    }
+  }
+
+  fn give(&self, worker: Worker) { self.idle.lock().unwrap().push(worker); }
+}
+
+// Wraps a filter's proc so its tiles are rendered by worker processes, which
+// rebuild the filter from its name and parameters.  Everything besides
+// process_tile still runs here, and so do procs that analyze their input,
+// since what they gathered can't be sent along, and procs without a halo,
+// since every one of their tiles would need the whole image sent with it.
+struct IsolatedProc {
+  filter: ArcFilter,
+  proc: ArcProc,
+  pool: Arc<WorkerPool>,
+}
+
+pub fn isolate(filter: &ArcFilter, pool: &Arc<WorkerPool>) -> ArcProc {
+  Arc::new(IsolatedProc {
+    filter: filter.clone(),
+    proc: filter.proc(),
+    pool: pool.clone(),
+  })
+}
+
+impl IsolatedProc {
+  fn write_tile<W: Write>(&self, w: &mut W, tile: &Tile) -> io::Result<()> {
+    let params: Vec<_> = self
+      .filter
+      .params()
+      .iter()
+      .filter_map(|Param(name, val)| param_value(val).map(|v| (name, v)))
+      .collect();
+
+    write_str(w, self.filter.name())?;
+    write_u32(w, params.len() as u32)?;
+
+    for (name, val) in params {
+      write_str(w, name)?;
+      write_str(w, &val)?;
+    }
+
+    for &n in &[
+      tile.in_w(),
+      tile.in_h(),
+      tile.x(),
+      tile.y(),
+      tile.w(),
+      tile.h(),
+      tile.scale(),
+      tile.pass(),
+    ] {
+      write_u32(w, n)?;
+    }
+
+    // Procs without one aren't sent to workers
+    write_u32(w, self.proc.halo().unwrap())?;
+
+    let (fx, fy, fw, fh) = tile.footprint();
+
+    for &n in &[fx, fy, fw, fh] {
+      write_u32(w, n)?;
+    }
+
+    // Reads through the tile come out already in the proc's color space
+    for r in 0..fh {
+      for c in 0..fw {
+        write_pixel(w, tile.global_input(fx + c, fy + r))?;
+      }
+    }
+
+    let nextra = self.proc.inputs().len();
+
+    write_u32(w, nextra as u32)?;
+
+    for i in 0..nextra {
+      if !tile.has_extra(i) {
+        w.write_all(&[0])?;
+        continue;
+      }
+
+      w.write_all(&[1])?;
+
+      for r in 0..fh {
+        for c in 0..fw {
+          write_pixel(w, tile.global_extra(i, fx + c, fy + r))?;
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+impl RenderProc for IsolatedProc {
+  fn output_size(&self, w: u32, h: u32) -> (u32, u32) {
+    self.proc.output_size(w, h)
+  }
+
+  fn passes(&self) -> u32 { self.proc.passes() }
+
+  fn begin(&self, w: u32, h: u32) { self.proc.begin(w, h) }
+
+  fn needs_analysis(&self) -> bool { self.proc.needs_analysis() }
+
+  fn analyze_tile(&self, tile: &Tile) -> Analysis {
+    self.proc.analyze_tile(tile)
+  }
+
+  fn combine(&self, a: Analysis, b: Analysis) -> Analysis {
+    self.proc.combine(a, b)
+  }
+
+  fn analyzed(&self, result: &(Any + Send + Sync)) {
+    self.proc.analyzed(result)
+  }
+
+  fn halo(&self) -> Option<u32> { self.proc.halo() }
+
+  fn inputs(&self) -> Vec<Option<(u64, Arc<PixelBuf>)>> { self.proc.inputs() }
+
+  fn color_space(&self) -> ColorSpace { self.proc.color_space() }
+
+  fn cache_key(&self) -> Option<u64> { self.proc.cache_key() }
+
+  fn kind(&self) -> TypeId { self.proc.kind() }
+
+  fn process_tile(&self, tile: &Tile, cancel_tok: &CancelTok) {
+    if self.proc.needs_analysis() || self.proc.halo().is_none() {
+      return self.proc.process_tile(tile, cancel_tok);
+    }
+
+    let mut status = String::new();
+
+    // A worker that dies is replaced and the tile tried once more, in case
+    // something besides the tile brought it down
+    for _ in 0..2 {
+      if cancel_tok.cancelled() {
+        return;
+      }
+
+      let mut worker = self
+        .pool
+        .take()
+        .unwrap_or_else(|e| panic!("couldn't start a worker process: {}", e));
+
+      match worker.render(self, tile, cancel_tok) {
+        Ok(Ok(pixels)) => {
+          *tile.out_buf() = pixels;
+          self.pool.give(worker);
+          return;
+        },
+        Ok(Err(e)) => {
+          self.pool.give(worker);
+          panic!("{}", e);
+        },
+        Err(_) => status = worker.exit_status(),
+      }
+    }
+
+    panic!("worker process died twice on this tile ({})", status);
+  }
+}
+
+// Everything a worker is sent about a tile
+struct TileMsg {
+  filter: String,
+  params: Vec<(String, String)>,
+  in_w: u32,
+  in_h: u32,
+  x: u32,
+  y: u32,
+  w: u32,
+  h: u32,
+  scale: u32,
+  pass: u32,
+  halo: u32,
+  footprint: (u32, u32, u32, u32),
+  input: Vec<Pixel>,
+  extra: Vec<Option<Vec<Pixel>>>,
+}
+
+fn read_tile<R: Read>(r: &mut R) -> io::Result<TileMsg> {
+  let filter = read_str(r)?;
+  let mut params = Vec::new();
+
+  for _ in 0..read_u32(r)? {
+    params.push((read_str(r)?, read_str(r)?));
+  }
+
+  let mut dims = [0; 8];
+
+  for n in dims.iter_mut() {
+    *n = read_u32(r)?;
+  }
+
+  let halo = read_u32(r)?;
+
+  let footprint = (read_u32(r)?, read_u32(r)?, read_u32(r)?, read_u32(r)?);
+  let npixels = (footprint.2 * footprint.3) as usize;
+  let input = read_pixels(r, npixels)?;
+  let mut extra = Vec::new();
+
+  for _ in 0..read_u32(r)? {
+    extra.push(if read_flag(r)? {
+      Some(read_pixels(r, npixels)?)
+    } else {
+      None
+    });
+  }
+
+  Ok(TileMsg {
+    filter,
+    params,
+    in_w: dims[0],
+    in_h: dims[1],
+    x: dims[2],
+    y: dims[3],
+    w: dims[4],
+    h: dims[5],
+    scale: dims[6],
+    pass: dims[7],
+    halo,
+    footprint,
+    input,
+    extra,
+  })
+}
+
+fn build_proc(ctors: &[FilterCtor], msg: &TileMsg) -> Result<ArcProc, String> {
+  let filter = ctors
+    .iter()
+    .map(|c| c())
+    .find(|f| f.name() == msg.filter)
+    .ok_or_else(|| format!("unknown filter '{}'", msg.filter))?;
+
+  for (name, val) in &msg.params {
+    let param = filter
+      .params()
+      .iter()
+      .find(|Param(n, _)| n == name)
+      .ok_or_else(|| {
+        format!("filter {} has no parameter '{}'", filter.name(), name)
+      })?;
+
+    cli::set_param(param, val, true)?;
+  }
+
+  Ok(filter.proc())
+}
+
+fn render_tile(
+  proc: &ArcProc,
+  msg: &TileMsg,
+  pager: &Arc<Pager>,
+  cancel_tok: &CancelTok,
+) -> Vec<Pixel> {
+  let (fx, fy, fw, fh) = msg.footprint;
+
+  // Only the blocks under the footprint ever get filled in
+  let store = |pixels: &Vec<Pixel>| {
+    let ret = ImageStore::new(pager.clone(), msg.in_w, msg.in_h);
+
+    ret.write_rect(fx, fy, fw, fh, pixels);
+
+    Arc::new(ret)
+  };
+
+  let tile = Tile::detached(
+    msg.x,
+    msg.y,
+    msg.w,
+    msg.h,
+    msg.scale,
+    msg.pass,
+    Some(msg.halo),
+    store(&msg.input),
+    msg.extra.iter().map(|e| e.as_ref().map(&store)).collect(),
+  );
+
+  proc.process_tile(&tile, cancel_tok);
+
+  let out_buf = tile.out_buf();
+
+  out_buf.clone()
+}
+
+fn reply<W: Write>(
+  w: &mut W,
+  result: Result<Vec<Pixel>, String>,
+) -> io::Result<()> {
+  match result {
+    Ok(pixels) => {
+      w.write_all(&[MSG_DONE])?;
+      write_u32(w, pixels.len() as u32)?;
+
+      for px in pixels {
+        write_pixel(w, px)?;
+      }
+    },
+    Err(e) => {
+      w.write_all(&[MSG_FAILED])?;
+      write_str(w, &e)?;
+    },
+  }
+
+  w.flush()
+}
+
+// The other end of a WorkerPool, run as `ingot worker` or whatever command the
+// pool was made with.  Tiles come in on stdin and their pixels go back out on
+// stdout, so procs mustn't print there.
+pub fn worker_main(filter_list: Vec<FilterCtor>) -> Result<(), String> {
+  let ctors: Vec<_> = vec![ctor(filters::DummyFilter::new)]
+    .into_iter()
+    .chain(filter_list)
+    .collect();
+
+  // The renderer reports panics itself
+  panic::set_hook(Box::new(|_| {}));
+
+  serve(&ctors, BufReader::new(io::stdin()), io::stdout())
+}
+
+// Renders tiles read from input until it ends, replying to each on output
+fn serve<R, W>(ctors: &[FilterCtor], input: R, output: W) -> Result<(), String>
+where
+  R: Read + Send + 'static,
+  W: Write + Send + 'static,
+{
+  let current = Arc::new(Mutex::new(Arc::new(AtomicBool::new(false))));
+  let (tx, rx) = mpsc::channel();
+
+  // Cancellations arrive while a tile is rendering, so the renderer is
+  // listened to on its own thread.  Anything unreadable hangs up, which ends
+  // the worker.
+  thread::spawn({
+    let current = current.clone();
+
+    move || {
+      let mut input = input;
+
+      loop {
+        match read_u8(&mut input) {
+          Ok(MSG_TILE) => {
+            let msg = match read_tile(&mut input) {
+              Ok(m) => m,
+              Err(_) => break,
+            };
+
+            let cancelled = Arc::new(AtomicBool::new(false));
+
+            *current.lock().unwrap() = cancelled.clone();
+
+            if tx.send((msg, cancelled)).is_err() {
+              break;
+            }
+          },
+          Ok(MSG_CANCEL) => {
+            current.lock().unwrap().store(true, Ordering::SeqCst)
+          },
+          _ => break,
+        }
+      }
+    }
+  });
+
+  let output = Arc::new(Mutex::new(BufWriter::new(output)));
+  let pager = Arc::new(Pager::new(None));
+
+  // The proc from the last tile, kept for as long as tiles keep coming for the
+  // same filter and settings so it doesn't have to begin() again
+  let mut last: Option<((String, Vec<(String, String)>, u32, u32), ArcProc)> =
+    None;
+
+  for (msg, cancelled) in rx {
+    let key = (msg.filter.clone(), msg.params.clone(), msg.in_w, msg.in_h);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(
+      || -> Result<Vec<Pixel>, String> {
+        let reuse = match last {
+          Some((ref k, _)) => *k == key,
+          None => false,
+        };
+
+        if !reuse {
+          last = None;
+
+          let proc = build_proc(ctors, &msg)?;
+
+          proc.begin(msg.in_w, msg.in_h);
+
+          last = Some((key, proc));
+        }
+
+        let proc = last.as_ref().unwrap().1.clone();
+
+        let tok = CancelTok::detached(cancelled, {
+          let output = output.clone();
+
+          Box::new(move |frac: f32| {
+            let mut output = output.lock().unwrap();
+
+            // A renderer that's gone away is noticed once the tile's done
+            output.write_all(&[MSG_PROGRESS]).ok();
+            write_u32(&mut *output, frac.to_bits()).ok();
+            output.flush().ok();
+          })
+        });
+
+        Ok(render_tile(&proc, &msg, &pager, &tok))
+      },
+    ))
+    .map_err(render::panic_msg)
+    .and_then(|r| r);
+
+    reply(&mut *output.lock().unwrap(), result)
+      .map_err(|e| format!("couldn't reply to the renderer: {}", e))?;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use filters::{flt, BoxBlurFilter, InvertFilter, PanicFilter};
+  use std::io::Cursor;
+
+  // A w-by-h input whose pixels all differ
+  fn input(w: u32, h: u32) -> (Arc<ImageStore>, Vec<Pixel>) {
+    let pixels: Vec<_> = (0..w * h)
+      .map(|i| {
+        let i = i as f32;
+
+        Pixel::new(i / 100.0, 1.0 - i / 100.0, i, 0.5)
+      })
+      .collect();
+
+    let store = ImageStore::new(Arc::new(Pager::new(None)), w, h);
+
+    store.write_rect(0, 0, w, h, &pixels);
+
+    (Arc::new(store), pixels)
+  }
+
+  // What the renderer sends a worker for a tile
+  fn send(buf: &mut Vec<u8>, filter: &ArcFilter, tile: &Tile) {
+    let proc = IsolatedProc {
+      filter: filter.clone(),
+      proc: filter.proc(),
+      pool: Arc::new(WorkerPool::new(PathBuf::new(), Vec::new())),
+    };
+
+    buf.push(MSG_TILE);
+    proc.write_tile(buf, tile).unwrap();
+  }
+
+  #[test]
+  fn tiles_survive_the_trip() {
+    let filter = flt(BoxBlurFilter::new());
+
+    cli::set_param(&filter.params()[0], "2", true).unwrap();
+
+    let (store, pixels) = input(12, 10);
+    let tile = Tile::detached(4, 4, 4, 3, 1, 1, Some(2), store, Vec::new());
+
+    let mut buf = Vec::new();
+
+    send(&mut buf, &filter, &tile);
+
+    let mut r = Cursor::new(buf);
+
+    assert_eq!(read_u8(&mut r).unwrap(), MSG_TILE);
+
+    let msg = read_tile(&mut r).unwrap();
+
+    assert_eq!(r.position() as usize, r.get_ref().len());
+    assert_eq!(msg.filter, "Box Blur");
+    assert_eq!(msg.params, vec![("Radius".to_string(), "2".to_string())]);
+    assert_eq!(
+      (msg.in_w, msg.in_h, msg.x, msg.y, msg.w, msg.h),
+      (12, 10, 4, 4, 4, 3)
+    );
+    assert_eq!((msg.scale, msg.pass, msg.halo), (1, 1, 2));
+    assert_eq!(msg.footprint, (2, 2, 8, 7));
+
+    let expected: Vec<_> = (2..9)
+      .flat_map(|y| (2..10).map(move |x| (x, y)))
+      .map(|(x, y)| pixels[y * 12 + x])
+      .collect();
+
+    assert_eq!(msg.input, expected);
+    assert!(msg.extra.is_empty());
+  }
+
+  // Stands in for the renderer's end of the pipe, so the test can read back
+  // what was written after the worker's done with it
+  #[derive(Clone)]
+  struct Pipe(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+  }
+
+  #[test]
+  fn panics_are_reported_and_the_worker_carries_on() {
+    let ctors = vec![ctor(PanicFilter::new), ctor(InvertFilter::new)];
+    let (store, pixels) = input(4, 4);
+    let tile =
+      || Tile::detached(0, 0, 4, 4, 1, 0, Some(0), store.clone(), Vec::new());
+
+    let mut buf = Vec::new();
+
+    send(&mut buf, &flt(PanicFilter::new()), &tile());
+    send(&mut buf, &flt(InvertFilter::new()), &tile());
+
+    let out = Pipe(Arc::new(Mutex::new(Vec::new())));
+
+    serve(&ctors, Cursor::new(buf), out.clone()).unwrap();
+
+    let out = out.0.lock().unwrap().clone();
+    let mut r = Cursor::new(out);
+    let mut replies = Vec::new();
+
+    while (r.position() as usize) < r.get_ref().len() {
+      match read_u8(&mut r).unwrap() {
+        MSG_PROGRESS => {
+          read_u32(&mut r).unwrap();
+        },
+        MSG_DONE => {
+          let n = read_u32(&mut r).unwrap() as usize;
+
+          replies.push(Ok(read_pixels(&mut r, n).unwrap()));
+        },
+        MSG_FAILED => replies.push(Err(read_str(&mut r).unwrap())),
+        m => panic!("unexpected message {}", m),
+      }
+    }
+
+    assert_eq!(replies.len(), 2);
+    assert_eq!(replies[0], Err("debug panic".to_string()));
+
+    let inverted = flt(InvertFilter::new()).proc();
+    let expected = tile();
+
+    inverted.process_tile(
+      &expected,
+      &CancelTok::detached(Arc::new(AtomicBool::new(false)), Box::new(|_| ())),
+    );
+
+    assert_eq!(replies[1], Ok(expected.out_buf().clone()));
+    assert_ne!(replies[1], Ok(pixels));
+  }
+}